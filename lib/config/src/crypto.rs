@@ -0,0 +1,222 @@
+use serde::{Serialize, Deserialize};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, aead::{Aead, Payload}};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{StaticSecret, PublicKey as X25519Public};
+use argon2::{Argon2, Algorithm, Version, Params};
+use rand::seq::SliceRandom;
+
+use crate::db::{Node, Us, Error};
+
+/*
+    Encryption to a node's identity.
+
+    The ed25519 keys nodes sign with double as X25519 keys (what libsodium calls
+    crypto_sign_ed25519_pk_to_curve25519). Every sealed box gets a fresh
+    ephemeral key, so only the recipient can open it, but it says nothing
+    about who sealed it: sign whatever you seal if that matters.
+*/
+
+const SEAL_INFO:&[u8] = b"cricket seal v1";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Sealed {
+    pub ephemeral: [u8; 32], // X25519 public key
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>
+}
+
+fn x25519_public(node: &Node) -> Result<X25519Public, Error> {
+    let key = ed25519_dalek::VerifyingKey::from_bytes(&node.public_key).map_err(|_| Error::InvalidKey)?;
+    Ok(X25519Public::from(key.to_montgomery().to_bytes()))
+}
+
+fn x25519_secret(us: &Us) -> StaticSecret {
+    StaticSecret::from(ed25519_dalek::SigningKey::from_bytes(&us.private_key).to_scalar_bytes())
+}
+
+// Both sides end up with the same key, bound to the ephemeral key and the recipient
+fn derive_key(shared: &[u8; 32], ephemeral: &X25519Public, recipient: &X25519Public) -> [u8; 32] {
+    let salt = [*ephemeral.as_bytes(), *recipient.as_bytes()].concat();
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared).expand(SEAL_INFO, &mut key).expect("32 bytes is a valid hkdf length");
+    key
+}
+
+// `context` is authenticated but not encrypted, it has to match when opening
+pub fn seal(recipient: &Node, plaintext: &[u8], context: &[u8]) -> Result<Sealed, Error> {
+    let recipient = x25519_public(recipient)?;
+
+    let mut secret = [0u8; 32];
+    rand::fill(&mut secret[..]);
+    let secret = StaticSecret::from(secret);
+    let ephemeral = X25519Public::from(&secret);
+
+    let key = derive_key(secret.diffie_hellman(&recipient).as_bytes(), &ephemeral, &recipient);
+    let mut nonce = [0u8; 12];
+    rand::fill(&mut nonce[..]);
+
+    let cipher = ChaCha20Poly1305::new(&key.into());
+    let ciphertext = cipher.encrypt(&nonce.into(), Payload { msg: plaintext, aad: context })
+        .map_err(|_| Error::UndecryptableMessage)?;
+
+    Ok(Sealed { ephemeral: *ephemeral.as_bytes(), nonce, ciphertext })
+}
+
+pub fn open(us: &Us, sealed: &Sealed, context: &[u8]) -> Result<Vec<u8>, Error> {
+    let secret = x25519_secret(us);
+    let ephemeral = X25519Public::from(sealed.ephemeral);
+
+    let key = derive_key(secret.diffie_hellman(&ephemeral).as_bytes(), &ephemeral, &X25519Public::from(&secret));
+
+    let cipher = ChaCha20Poly1305::new(&key.into());
+    cipher.decrypt(&sealed.nonce.into(), Payload { msg: &sealed.ciphertext, aad: context })
+        .map_err(|_| Error::UndecryptableMessage)
+}
+
+// Content for a group: encrypted once, and the key sealed to every recipient.
+// The slots don't say who they are for, a recipient tries them until one opens.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Envelope {
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>,
+    pub keys: Vec<Sealed> // In no particular order, so how many is all it gives away
+}
+
+pub fn seal_envelope(recipients: &[Node], plaintext: &[u8], context: &[u8]) -> Result<Envelope, Error> {
+    let mut key = [0u8; 32];
+    rand::fill(&mut key[..]);
+    let mut nonce = [0u8; 12];
+    rand::fill(&mut nonce[..]);
+
+    let cipher = ChaCha20Poly1305::new(&key.into());
+    let ciphertext = cipher.encrypt(&nonce.into(), Payload { msg: plaintext, aad: context })
+        .map_err(|_| Error::UndecryptableMessage)?;
+
+    let mut keys = vec![];
+    for recipient in recipients {
+        keys.push(seal(recipient, &key, context)?);
+    }
+    keys.shuffle(&mut rand::rng());
+
+    Ok(Envelope { nonce, ciphertext, keys })
+}
+
+// None if it was not meant for us
+pub fn open_envelope(us: &Us, envelope: &Envelope, context: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let Some(key) = envelope.keys.iter().find_map(|sealed| open(us, sealed, context).ok()) else {
+        return Ok(None);
+    };
+
+    let key: [u8; 32] = key.try_into().map_err(|_| Error::UndecryptableMessage)?;
+    let cipher = ChaCha20Poly1305::new(&key.into());
+    let plaintext = cipher.decrypt(&envelope.nonce.into(), Payload { msg: &envelope.ciphertext, aad: context })
+        .map_err(|_| Error::UndecryptableMessage)?;
+    Ok(Some(plaintext))
+}
+
+// Something only a passphrase opens, the argon2 costs are kept so they can go up later
+// (up to the limits below, a backup can come from anyone and argon2 would take what it asks for)
+const MAX_M_COST: u32 = 256 * 1024; // KiB
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 8;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Locked {
+    pub salt: [u8; 16],
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>
+}
+
+fn passphrase_key(passphrase: &str, salt: &[u8], m_cost: u32, t_cost: u32, p_cost: u32) -> Result<[u8; 32], Error> {
+    let params = Params::new(m_cost, t_cost, p_cost, Some(32)).map_err(|_| Error::WrongPassphrase)?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|_| Error::WrongPassphrase)?;
+    Ok(key)
+}
+
+pub fn lock(passphrase: &str, plaintext: &[u8]) -> Result<Locked, Error> {
+    let mut salt = [0u8; 16];
+    rand::fill(&mut salt[..]);
+    let mut nonce = [0u8; 12];
+    rand::fill(&mut nonce[..]);
+    let (m_cost, t_cost, p_cost) = (Params::DEFAULT_M_COST, Params::DEFAULT_T_COST, Params::DEFAULT_P_COST);
+
+    let key = passphrase_key(passphrase, &salt, m_cost, t_cost, p_cost)?;
+    let cipher = ChaCha20Poly1305::new(&key.into());
+    let ciphertext = cipher.encrypt(&nonce.into(), plaintext).map_err(|_| Error::WrongPassphrase)?;
+
+    Ok(Locked { salt, m_cost, t_cost, p_cost, nonce, ciphertext })
+}
+
+pub fn unlock(passphrase: &str, locked: &Locked) -> Result<Vec<u8>, Error> {
+    if locked.m_cost > MAX_M_COST || locked.t_cost > MAX_T_COST || locked.p_cost > MAX_P_COST {
+        return Err(Error::InvalidBackup);
+    }
+    let key = passphrase_key(passphrase, &locked.salt, locked.m_cost, locked.t_cost, locked.p_cost)?;
+    let cipher = ChaCha20Poly1305::new(&key.into());
+    cipher.decrypt(&locked.nonce.into(), &locked.ciphertext[..]).map_err(|_| Error::WrongPassphrase)
+}
+
+#[test]
+fn test_seal() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::NodeDB;
+    use crate::db::identity::Identity;
+
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let us = db.get_identity()?;
+    let stranger = db.generate_identity()?;
+
+    let sealed = seal(&us.node, b"hello", b"context")?;
+    assert_eq!(open(&us, &sealed, b"context")?, b"hello");
+
+    // Wrong key, or someone swapped the context
+    assert!(open(&stranger, &sealed, b"context").is_err());
+    assert!(open(&us, &sealed, b"other").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_envelope() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::NodeDB;
+    use crate::db::identity::Identity;
+
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let us = db.get_identity()?;
+    let friend = db.generate_identity()?;
+    let stranger = db.generate_identity()?;
+
+    let envelope = seal_envelope(&[us.node.clone(), friend.node.clone()], b"hello", b"context")?;
+    assert_eq!(open_envelope(&us, &envelope, b"context")?, Some(b"hello".to_vec()));
+    assert_eq!(open_envelope(&friend, &envelope, b"context")?, Some(b"hello".to_vec()));
+    assert_eq!(open_envelope(&stranger, &envelope, b"context")?, None);
+
+    // Nothing in it points at either of them
+    let serialized = bincode::serialize(&envelope)?;
+    for node in [&us.node, &friend.node] {
+        assert!(!serialized.windows(32).any(|bytes| bytes == node.public_key));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_lock() -> Result<(), Box<dyn std::error::Error>> {
+    let locked = lock("correct horse", b"secret")?;
+    assert_eq!(unlock("correct horse", &locked)?, b"secret");
+    assert!(matches!(unlock("battery staple", &locked), Err(Error::WrongPassphrase)));
+
+    // Costs nobody would pick are refused before argon2 gets to allocate them
+    let greedy = Locked { m_cost: u32::MAX, ..locked.clone() };
+    assert!(matches!(unlock("correct horse", &greedy), Err(Error::InvalidBackup)));
+    let slow = Locked { t_cost: MAX_T_COST + 1, ..locked };
+    assert!(matches!(unlock("correct horse", &slow), Err(Error::InvalidBackup)));
+
+    Ok(())
+}
@@ -2,6 +2,7 @@ use std::str::FromStr;
 use sled::Db;
 use serde::{Serialize, Deserialize};
 use crate::misc::{get_epoch, sha256};
+use crate::crypto::Envelope;
 use rand::Rng;
 
 pub trait Hashable: Serialize {
@@ -24,10 +25,10 @@ impl Node {
         }
     }
 
-    fn verify(&self, message: &[u8; 32], signature:&str) -> Result<(), Box<dyn std::error::Error>> {
-        let public_key = iroh::PublicKey::from_bytes(&self.public_key)?;
-        public_key.verify(message, &ed25519::Signature::from_str(signature)?)?;
-        Ok(())
+    fn verify(&self, message: &[u8; 32], signature:&str) -> Result<(), Error> {
+        let public_key = iroh::PublicKey::from_bytes(&self.public_key).map_err(|_| Error::InvalidSignature)?;
+        let signature = ed25519::Signature::from_str(signature).map_err(|_| Error::InvalidSignature)?;
+        public_key.verify(message, &signature).map_err(|_| Error::InvalidSignature)
     }
 }
 
@@ -54,11 +55,33 @@ impl Us {
     }
 }
 
+/*
+    Post versions, every version adds fields to the end of the hash so older posts still verify
+    0: author, content, message_id
+    1: + created_at, signed by the author
+    2: + seq, prev, the author's feed (see feed.rs)
+    3: + audience, content only our trusted peers can read (see audience.rs)
+*/
+pub const POST_VERSION:u8 = 3;
+
+// How far in the future a post can claim to be written, clocks are never quite in sync
+pub const MAX_CLOCK_SKEW:u64 = 60 * 5;
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct RawPost {
     pub author: Node,
     pub content: String,
     pub message_id: u128,
+    #[serde(default)] // Missing from version 0 posts
+    pub version: u8,
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)] // Missing before version 2
+    pub seq: u64, // 1 for the first post in the author's feed, 0 before version 2 when there was none
+    #[serde(default)]
+    pub prev: Option<PostId>, // Hash of the author's previous post, only the first one has none
+    #[serde(default)] // Missing before version 3
+    pub audience: Option<Envelope> // The real content when it is only for some nodes, content is empty then
 }
 
 impl RawPost {
@@ -68,7 +91,12 @@ impl RawPost {
         Self {
             author,
             content,
-            message_id
+            message_id,
+            version: POST_VERSION,
+            created_at: get_epoch(),
+            seq: 1, // Feed::new_post finds the right place
+            prev: None,
+            audience: None
         }
 
     }
@@ -76,6 +104,24 @@ impl RawPost {
     pub fn get_id(&self) -> PostId {
         PostId { raw: self.hash() }
     }
+
+    // Fields newer than the post's version are not covered by the signature
+    fn unsigned_fields_empty(&self) -> bool {
+        (self.version >= 1 || self.created_at == 0)
+            && (self.version >= 3 || self.audience.is_none())
+    }
+
+    // Only the first post of a feed links back to nothing, and every post since version 2 is in one
+    fn valid_link(&self) -> bool {
+        if self.version < 2 {
+            return self.seq == 0 && self.prev.is_none(); // Not signed, so it can't be trusted
+        }
+        match self.seq {
+            0 => false,
+            1 => self.prev.is_none(),
+            _ => self.prev.is_some()
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -101,10 +147,24 @@ impl IncomingPost {
 
     // Need to have a method that allows us to create a post to send to the network
 
-    pub fn new(post:&RawPost, history: &Vec<Path>, signature:&String, us: &Us) -> Result<Self, Box<dyn std::error::Error>> {
+    // max_skew is how far ahead of our clock the author's may be, settings.clock_skew
+    pub fn new(post:&RawPost, history: &Vec<Path>, signature:&String, us: &Us, max_skew: u64) -> Result<Self, Error> {
         IncomingPost::verify_history(&history, &post, &us)?;
         IncomingPost::verify_signature(&post, signature)?;
 
+        if post.version > POST_VERSION {
+            return Err(Error::UnsupportedPost(post.version));
+        }
+        if !post.unsigned_fields_empty() {
+            return Err(Error::MalformedPost);
+        }
+        if post.version >= 1 && post.created_at > get_epoch() + max_skew {
+            return Err(Error::FuturePost);
+        }
+        if !post.valid_link() {
+            return Err(Error::BrokenFeed);
+        }
+
         Ok(IncomingPost {
             post: post.clone(),
             history: history.clone(),
@@ -113,12 +173,12 @@ impl IncomingPost {
         })
 
     }
-    fn verify_signature(post: &RawPost, signature:&String) -> Result<(), Box<dyn std::error::Error>> {
+    fn verify_signature(post: &RawPost, signature:&str) -> Result<(), Error> {
         post.author.verify(&post.hash(), signature)?;
         Ok(())
     }
 
-    fn verify_history(history: &Vec<Path>, post: &RawPost, us: &Us) -> Result<(), Box<dyn std::error::Error>> {
+    fn verify_history(history: &[Path], post: &RawPost, us: &Us) -> Result<(), Error> {
         let post_id = PostId { raw: post.hash() };
         for (idx, path) in history.iter().enumerate() {
             let message = construct_path_msg(&post_id, &path.from, &path.to);
@@ -127,14 +187,14 @@ impl IncomingPost {
             if idx < history.len() - 1 {
                 let next_node = history.get(idx + 1).unwrap();
                 if path.to.public_key != next_node.from.public_key {
-                    Err("History contained broken chain")?;
+                    return Err(Error::BrokenHistory);
                 }
             }
         }
 
         if let Some(last) = history.last() { // Might receive an empty history
             if last.to.public_key != us.node.public_key {
-                Err("We got a post that was not intended for us")?;
+                return Err(Error::MisdirectedPost);
             }
         }
 
@@ -144,6 +204,14 @@ impl IncomingPost {
     fn get_id(&self) -> PostId {
         self.post.get_id()
     }
+
+    // When the author wrote it, or when we got it for posts that did not say
+    pub fn created(&self) -> u64 {
+        match self.post.version {
+            0 => self.received,
+            _ => self.post.created_at
+        }
+    }
 }
 
 
@@ -188,34 +256,166 @@ pub struct PostId {
     raw: [u8; 32]
 }
 
+pub const SHORT_ID_LEN:usize = 4; // bytes, so 8 hex characters
+
+impl PostId {
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.raw)
+    }
+
+    // What the user sees and types in the feed
+    pub fn short(&self) -> String {
+        hex::encode(&self.raw[..SHORT_ID_LEN])
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
-struct TrustRequest {
-    recipient: Node,
-    intermediate: Node,
-    post: PostId,       // Proof that we share the intermediate node
-    signature: String   // proof that the intermediate node gave us the post 
-                        // sign(post_id + us public key, intermediate private key)
+pub struct TrustRequest {
+    pub recipient: Node,
+    pub intermediate: Node,
+    pub post: PostId,       // Proof that we share the intermediate node
+    pub signature: String   // proof that the intermediate node gave us the post 
+                            // sign(post_id + us public key, intermediate private key)
+}
+
+
+impl Hashable for RawPost {
+    fn hash(&self) -> [u8; 32] {
+        // Same bytes as the version 0 struct, so those ids never change
+        let mut serialized = bincode::serialize(&(&self.author, &self.content, &self.message_id)).unwrap();
+        if self.version >= 1 {
+            serialized.extend(bincode::serialize(&(self.version, self.created_at)).unwrap());
+        }
+        if self.version >= 2 {
+            serialized.extend(bincode::serialize(&(self.seq, &self.prev)).unwrap());
+        }
+        if self.version >= 3 {
+            serialized.extend(bincode::serialize(&self.audience).unwrap());
+        }
+        sha256(serialized)
+    }
 }
 
+// Knobs that are not worth a table, everything in seconds
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub outbox_ttl: u64,            // Give up delivering a post after this long
+    pub outbox_backoff: u64,        // First retry, doubles after every failed attempt
+    pub outbox_max_backoff: u64,
+    pub unreachable_penalty: usize, // Score lost every time a peer can't be reached
+    pub clock_skew: u64,
+    pub retention_age: Option<u64>, // Posts we keep (all of them unless set), pinned posts are kept no matter what
+    pub retention_count: Option<usize>,
+    pub retention_bytes: Option<u64>
+}
 
-impl Hashable for RawPost {}
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            outbox_ttl: 60 * 60 * 24,
+            outbox_backoff: 30,
+            outbox_max_backoff: 60 * 60,
+            unreachable_penalty: 8,
+            clock_skew: MAX_CLOCK_SKEW,
+            retention_age: None,
+            retention_count: None,
+            retention_bytes: None
+        }
+    }
+}
 
 pub struct NodeDB {
     pub db: Db,
-    pub bootstrap_nodes: Option<Vec<Node>>
+    pub bootstrap_nodes: Option<Vec<Node>>,
+    pub settings: Settings,
+    identity: std::sync::RwLock<Option<Us>> // Once unlocked, so the passphrase is only needed once
+}
+
+// sled's background threads keep the lock file for a moment after the last handle is gone,
+// so reopening a database in the same process can briefly fail
+fn open_sled(path: &std::path::Path) -> Result<sled::Db, Error> {
+    let mut attempts = 0;
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(e)) if e.kind() == std::io::ErrorKind::Other && attempts < 20 => {
+                attempts += 1;
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            },
+            result => return Ok(result?)
+        }
+    }
+}
+
+// Where a database is copied to while it is rewritten, and where the old one waits to be deleted
+fn rewrite_paths(path: &std::path::Path) -> (std::path::PathBuf, std::path::PathBuf) {
+    (path.with_extension("rewrite"), path.with_extension("old"))
 }
 
+// Copies every tree into a fresh database and swaps it in. Whatever sled kept of
+// values we overwrote or removed (like a key that used to be in the clear) goes with the old files.
+fn rewrite(path: &std::path::Path, db: sled::Db) -> Result<sled::Db, Error> {
+    let (fresh_path, old_path) = rewrite_paths(path);
+    let _ = std::fs::remove_dir_all(&fresh_path); // Left over from a rewrite that never finished
+
+    let fresh = sled::open(&fresh_path)?;
+    fresh.import(db.export());
+    fresh.open_tree(migrate::META_TABLE)?.remove(NEEDS_REWRITE)?;
+    fresh.flush()?;
+    drop(fresh);
+    drop(db);
+
+    std::fs::rename(path, &old_path).map_err(sled::Error::from)?;
+    std::fs::rename(&fresh_path, path).map_err(sled::Error::from)?;
+    std::fs::remove_dir_all(&old_path).map_err(sled::Error::from)?;
+    open_sled(path)
+}
+
+// In META_TABLE, set when something we want gone for good is still in the old files
+pub(crate) const NEEDS_REWRITE:&[u8] = b"needs_rewrite";
+
 impl NodeDB {
-    pub fn new<P: AsRef<std::path::Path>>(path: P, bootstrap_nodes:Option<Vec<Node>>) -> Result<Self, Box<dyn std::error::Error>> {
-        let db = sled::open(path)?;
+    pub fn new<P: AsRef<std::path::Path>>(path: P, bootstrap_nodes:Option<Vec<Node>>) -> Result<Self, Error> {
+        let path = path.as_ref();
+
+        // A rewrite stopped halfway, either the old files are still all we have or they are not needed anymore
+        let (_fresh_path, old_path) = rewrite_paths(path);
+        if old_path.exists() {
+            if path.exists() {
+                std::fs::remove_dir_all(&old_path).map_err(sled::Error::from)?;
+            } else {
+                std::fs::rename(&old_path, path).map_err(sled::Error::from)?;
+            }
+        }
+
+        let mut db = open_sled(path)?;
+        if db.open_tree(migrate::META_TABLE)?.contains_key(NEEDS_REWRITE)? {
+            db = rewrite(path, db)?;
+        }
         
-        Ok(Self {
+        let node_db = Self {
             db: db,
-            bootstrap_nodes: bootstrap_nodes
-        })
+            bootstrap_nodes: bootstrap_nodes,
+            settings: Settings::default(),
+            identity: std::sync::RwLock::new(None)
+        };
+        node_db.migrate()?;
+        Ok(node_db)
+    }
+
+    // Until then copies of overwritten secrets may still be on disk, the next NodeDB::new takes care of it
+    pub fn needs_rewrite(&self) -> Result<bool, Error> {
+        Ok(self.db.open_tree(migrate::META_TABLE)?.contains_key(NEEDS_REWRITE)?)
+    }
+
+    pub fn with_settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
     }
 }
 
+mod error;
+pub use error::Error;
+
 pub mod identity;
 pub mod trust_request;
 
@@ -223,4 +423,14 @@ pub mod handle_post;
 
 pub mod trust;
 pub mod score;
-pub mod search;
\ No newline at end of file
+pub mod search;
+pub mod rank;
+pub mod text;
+pub mod post_sync;
+pub mod outbox;
+pub mod migrate;
+pub mod retention;
+pub mod feed;
+pub mod direct_message;
+pub mod audience;
+pub mod rotation;
\ No newline at end of file
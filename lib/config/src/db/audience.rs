@@ -0,0 +1,88 @@
+use crate::crypto;
+use crate::db::{NodeDB, RawPost, Error};
+use crate::db::identity::Identity;
+use crate::db::trust::Trust;
+use crate::db::feed::Feed;
+
+/*
+    Posts only our trusted peers can read.
+
+    The content is encrypted once and its key sealed to every node we trust
+    right now (and to us). Everything else about the post stays public, so it
+    is signed, stored and relayed like any other post; the nodes it reaches
+    past our peers just can't open it, or tell who can.
+*/
+
+pub trait Audience {
+    fn new_private_post(&self, content: String) -> Result<RawPost, Error>;
+    fn read_post(&self, post: &RawPost) -> Result<Option<String>, Error>;
+}
+
+// Ties the envelope to the post it came in
+fn envelope_context(post: &RawPost) -> Vec<u8> {
+    [&post.author.public_key[..], &post.message_id.to_be_bytes()[..]].concat()
+}
+
+impl Audience for NodeDB {
+    fn new_private_post(&self, content: String) -> Result<RawPost, Error> {
+        let us = self.get_identity()?;
+        let mut recipients:Vec<_> = self.get_trusted()?.into_iter().map(|(node, _score)| node).collect();
+        recipients.push(us.node.clone());
+
+        let mut post = self.new_post(String::new())?;
+        post.audience = Some(crypto::seal_envelope(&recipients, content.as_bytes(), &envelope_context(&post))?);
+        Ok(post)
+    }
+
+    // What we get to see of a post, None if it's not for us
+    fn read_post(&self, post: &RawPost) -> Result<Option<String>, Error> {
+        let Some(envelope) = &post.audience else {
+            return Ok(Some(post.content.clone()));
+        };
+
+        let us = self.get_identity()?;
+        match crypto::open_envelope(&us, envelope, &envelope_context(post))? {
+            Some(content) => Ok(Some(String::from_utf8(content).map_err(|_| Error::UndecryptableMessage)?)),
+            None => Ok(None)
+        }
+    }
+}
+
+#[test]
+fn test_private_posts() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{IncomingPost, OutgoingPost, MAX_CLOCK_SKEW};
+    use crate::db::handle_post::HandlePost;
+
+    let db1 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node1 = db1.get_identity()?;
+    let db2 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node2 = db2.get_identity()?;
+    let db3 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node3 = db3.get_identity()?;
+
+    // 1 trusts 2, 2 trusts 3
+    db1.trust(&node2.node)?;
+    db2.trust(&node3.node)?;
+
+    let raw_post = db1.new_private_post("just for friends".to_string())?;
+    assert_eq!(raw_post.content, "");
+    let signature = node1.sign(&raw_post.get_id().raw);
+    let out = db1.receive(&IncomingPost::new(&raw_post, &vec![], &signature, &node1, MAX_CLOCK_SKEW)?)?;
+    assert_eq!(db1.read_post(&raw_post)?, Some("just for friends".to_string()));
+
+    // Our peer can read it, and passes it on like any other post
+    let post = IncomingPost::new(&out[0].post, &out[0].history, &out[0].signature, &node2, MAX_CLOCK_SKEW)?;
+    assert_eq!(db2.read_post(&post.post)?, Some("just for friends".to_string()));
+    let out: Vec<OutgoingPost> = db2.receive(&post)?;
+
+    // One hop further it still verifies, but stays closed
+    let post = IncomingPost::new(&out[0].post, &out[0].history, &out[0].signature, &node3, MAX_CLOCK_SKEW)?;
+    assert_eq!(db3.read_post(&post.post)?, None);
+
+    // The audience is signed, nobody can add themselves to it
+    let mut tampered = raw_post.clone();
+    tampered.audience.as_mut().unwrap().keys.pop();
+    assert!(IncomingPost::new(&tampered, &vec![], &signature, &node1, MAX_CLOCK_SKEW).is_err());
+
+    Ok(())
+}
@@ -0,0 +1,322 @@
+use serde::{Serialize, Deserialize};
+
+use std::cmp::Reverse;
+
+use crate::crypto::{self, Sealed};
+use crate::db::{NodeDB, Node, Us, Error};
+use crate::db::identity::Identity;
+use crate::db::trust::Trust;
+use crate::db::outbox::Retry;
+use crate::misc::{get_epoch, sha256};
+
+/*
+    Direct messages between nodes that trust each other.
+
+    They go straight to the recipient, sealed to their identity key and signed
+    by the sender, and never touch the posts tables so nothing relays them.
+    Our copy of both sides of a conversation is sealed to our own key, so it
+    is as safe as the identity is (see /passphrase). Who we talked to and when is not.
+    What we write waits in PENDING_MESSAGES_TABLE until the recipient took it,
+    with the same backoff and ttl as the outbox. When a key on either end
+    rotates, it is sealed and signed again between the new keys.
+*/
+
+pub trait DirectMessages {
+    fn write_message(&self, recipient: &Node, content: &str) -> Result<DirectMessage, Error>;
+    fn receive_message(&self, message: &DirectMessage) -> Result<StoredMessage, Error>;
+    fn inbox(&self, peer: Option<&Node>, limit: usize) -> Result<Vec<StoredMessage>, Error>;
+    fn due_messages(&self) -> Result<Vec<DirectMessage>, Error>;
+    fn message_delivered(&self, message: &DirectMessage) -> Result<(), Error>;
+    fn message_failed(&self, message: &DirectMessage) -> Result<Retry, Error>;
+}
+
+// peer public key + sent_at (big endian) + message id -> StoredMessage, sealed to us
+const MESSAGES_TABLE:&str = "MESSAGES_TABLE";
+// message id -> PendingMessage
+const PENDING_MESSAGES_TABLE:&str = "PENDING_MESSAGES_TABLE";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct DirectMessage {
+    pub sender: Node,
+    pub recipient: Node,
+    pub sent_at: u64,
+    pub sealed: Sealed,
+    pub signature: String // sign(id, sender private key)
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct StoredMessage {
+    pub peer: Node, // Whoever we are talking to
+    pub outgoing: bool,
+    pub sent_at: u64,
+    pub content: String,
+    pub delivered: bool // Always true for the ones we got
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+struct PendingMessage {
+    message: DirectMessage,
+    attempts: u32,
+    next_attempt: u64
+}
+
+impl DirectMessage {
+    pub fn id(&self) -> [u8; 32] {
+        sha256(bincode::serialize(&(&self.sender, &self.recipient, self.sent_at, &self.sealed)).unwrap())
+    }
+
+    // Sealed together with the content, so the box can't be replayed under another header
+    fn context(sender: &Node, recipient: &Node, sent_at: u64) -> Vec<u8> {
+        [&sender.public_key[..], &recipient.public_key[..], &sent_at.to_be_bytes()[..]].concat()
+    }
+}
+
+fn message_key(peer: &Node, sent_at: u64, id: &[u8; 32]) -> Vec<u8> {
+    [&peer.public_key[..], &sent_at.to_be_bytes()[..], &id[..]].concat()
+}
+
+impl NodeDB {
+    // The key is the context, so a sealed message can't be moved to another conversation
+    fn store_message(&self, us: &Us, id: &[u8; 32], message: &StoredMessage) -> Result<(), Error> {
+        let messages = self.db.open_tree(MESSAGES_TABLE)?;
+        let key = message_key(&message.peer, message.sent_at, id);
+        let sealed = crypto::seal(&us.node, &bincode::serialize(message)?, &key)?;
+        messages.insert(key, bincode::serialize(&sealed)?)?;
+        Ok(())
+    }
+
+    fn open_message(&self, us: &Us, key: &[u8], sealed: &[u8]) -> Result<StoredMessage, Error> {
+        let sealed: Sealed = bincode::deserialize(sealed)?;
+        Ok(bincode::deserialize(&crypto::open(us, &sealed, key)?)?)
+    }
+
+    // A message still waiting goes out again between whoever we and they are now, under a new id
+    fn readdress(&self, us: &Us, pending: PendingMessage, recipient: &Node) -> Result<(), Error> {
+        let messages = self.db.open_tree(MESSAGES_TABLE)?;
+        let pending_messages = self.db.open_tree(PENDING_MESSAGES_TABLE)?;
+        let old = &pending.message;
+        pending_messages.remove(old.id())?;
+
+        // Nothing to seal again without our copy
+        let key = message_key(&old.recipient, old.sent_at, &old.id());
+        let Some(sealed) = messages.remove(&key)? else { return Ok(()) };
+        let stored = StoredMessage { peer: recipient.clone(), ..self.open_message(us, &key, &sealed)? };
+
+        let message = seal_message(us, recipient, stored.sent_at, &stored.content)?;
+        self.store_message(us, &message.id(), &stored)?;
+        let pending = PendingMessage { message, ..pending };
+        pending_messages.insert(pending.message.id(), bincode::serialize(&pending)?)?;
+        Ok(())
+    }
+
+    // Our copies are sealed to our key, they have to move along when it rotates
+    pub(crate) fn reseal_messages(&self, old: &Us, new: &Us) -> Result<(), Error> {
+        let messages = self.db.open_tree(MESSAGES_TABLE)?;
+        for entry in messages.iter() {
+            let (key, sealed) = entry?;
+            let message = self.open_message(old, &key, &sealed)?;
+            let sealed = crypto::seal(&new.node, &bincode::serialize(&message)?, &key)?;
+            messages.insert(key, bincode::serialize(&sealed)?)?;
+        }
+        Ok(())
+    }
+
+    // Someone rotated, maybe us: the conversation with them continues under the new key
+    pub(crate) fn move_messages(&self, us: &Us, old: &Node, new: &Node) -> Result<(), Error> {
+        let pending_messages = self.db.open_tree(PENDING_MESSAGES_TABLE)?;
+        for entry in pending_messages.iter() {
+            let (_key, entry) = entry?;
+            let entry: PendingMessage = bincode::deserialize(&entry)?;
+            if &entry.message.sender == old || &entry.message.recipient == old {
+                let recipient = if &entry.message.recipient == old { new.clone() } else { entry.message.recipient.clone() };
+                self.readdress(us, entry, &recipient)?;
+            }
+        }
+
+        let messages = self.db.open_tree(MESSAGES_TABLE)?;
+        for entry in messages.scan_prefix(old.public_key) {
+            let (key, sealed) = entry?;
+            let stored = StoredMessage { peer: new.clone(), ..self.open_message(us, &key, &sealed)? };
+            self.store_message(us, &key[key.len() - 32..].try_into().map_err(|_| Error::InvalidKey)?, &stored)?;
+            messages.remove(key)?;
+        }
+        Ok(())
+    }
+}
+
+fn seal_message(us: &Us, recipient: &Node, sent_at: u64, content: &str) -> Result<DirectMessage, Error> {
+    let context = DirectMessage::context(&us.node, recipient, sent_at);
+    let sealed = crypto::seal(recipient, content.as_bytes(), &context)?;
+
+    let mut message = DirectMessage { sender: us.node.clone(), recipient: recipient.clone(), sent_at, sealed, signature: String::new() };
+    message.signature = us.sign(&message.id());
+    Ok(message)
+}
+
+impl DirectMessages for NodeDB {
+    fn write_message(&self, recipient: &Node, content: &str) -> Result<DirectMessage, Error> {
+        if !self.is_trusted(recipient)? {
+            return Err(Error::UntrustedPeer);
+        }
+
+        let us = self.get_identity()?;
+        let sent_at = get_epoch();
+        let message = seal_message(&us, recipient, sent_at, content)?;
+
+        let stored = StoredMessage { peer: recipient.clone(), outgoing: true, sent_at, content: content.to_string(), delivered: false };
+        self.store_message(&us, &message.id(), &stored)?;
+
+        // The first attempt is on its way already
+        let pending = PendingMessage { message: message.clone(), attempts: 0, next_attempt: sent_at + self.backoff(0) };
+        self.db.open_tree(PENDING_MESSAGES_TABLE)?.insert(message.id(), bincode::serialize(&pending)?)?;
+        Ok(message)
+    }
+
+    fn receive_message(&self, message: &DirectMessage) -> Result<StoredMessage, Error> {
+        let us = self.get_identity()?;
+        if message.recipient != us.node {
+            return Err(Error::MisdirectedMessage);
+        }
+        if !self.is_trusted(&message.sender)? {
+            return Err(Error::UntrustedPeer);
+        }
+        message.sender.verify(&message.id(), &message.signature)?;
+
+        let context = DirectMessage::context(&message.sender, &message.recipient, message.sent_at);
+        let content = crypto::open(&us, &message.sealed, &context)?;
+        let content = String::from_utf8(content).map_err(|_| Error::UndecryptableMessage)?;
+
+        let stored = StoredMessage { peer: message.sender.clone(), outgoing: false, sent_at: message.sent_at, content, delivered: true };
+        self.store_message(&us, &message.id(), &stored)?;
+        Ok(stored)
+    }
+
+    // Newest first, with one peer or with everyone
+    fn inbox(&self, peer: Option<&Node>, limit: usize) -> Result<Vec<StoredMessage>, Error> {
+        let messages = self.db.open_tree(MESSAGES_TABLE)?;
+        let us = self.get_identity()?;
+        let prefix = peer.map_or(vec![], |peer| peer.public_key.to_vec());
+
+        let mut result = vec![];
+        for message in messages.scan_prefix(prefix) {
+            let (key, message) = message?;
+            result.push(self.open_message(&us, &key, &message)?);
+        }
+        result.sort_by_key(|message| Reverse(message.sent_at));
+        result.truncate(limit);
+        Ok(result)
+    }
+
+    fn due_messages(&self) -> Result<Vec<DirectMessage>, Error> {
+        let pending = self.db.open_tree(PENDING_MESSAGES_TABLE)?;
+        let now = get_epoch();
+
+        let mut results = vec![];
+        for entry in pending.iter() {
+            let (_key, entry) = entry?;
+            let entry: PendingMessage = bincode::deserialize(&entry)?;
+            if entry.next_attempt <= now {
+                results.push(entry.message);
+            }
+        }
+        Ok(results)
+    }
+
+    // The recipient has it, only now does it show up as sent
+    fn message_delivered(&self, message: &DirectMessage) -> Result<(), Error> {
+        self.db.open_tree(PENDING_MESSAGES_TABLE)?.remove(message.id())?;
+
+        let messages = self.db.open_tree(MESSAGES_TABLE)?;
+        let us = self.get_identity()?;
+        let key = message_key(&message.recipient, message.sent_at, &message.id());
+        if let Some(sealed) = messages.get(&key)? {
+            let mut stored = self.open_message(&us, &key, &sealed)?;
+            stored.delivered = true;
+            self.store_message(&us, &message.id(), &stored)?;
+        }
+        Ok(())
+    }
+
+    // Our copy stays, it just never shows up as sent
+    fn message_failed(&self, message: &DirectMessage) -> Result<Retry, Error> {
+        let pending = self.db.open_tree(PENDING_MESSAGES_TABLE)?;
+        let mut entry: PendingMessage = match pending.get(message.id())? {
+            Some(entry) => bincode::deserialize(&entry)?,
+            None => return Ok(Retry::GaveUp)
+        };
+
+        entry.attempts += 1;
+        entry.next_attempt = get_epoch() + self.backoff(entry.attempts - 1);
+        if entry.next_attempt > message.sent_at + self.settings.outbox_ttl {
+            pending.remove(message.id())?;
+            return Ok(Retry::GaveUp);
+        }
+
+        pending.insert(message.id(), bincode::serialize(&entry)?)?;
+        Ok(Retry::At(entry.next_attempt))
+    }
+}
+
+#[test]
+fn test_direct_messages() -> Result<(), Box<dyn std::error::Error>> {
+    let db1 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node1 = db1.get_identity()?;
+
+    let db2 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node2 = db2.get_identity()?;
+
+    // Only between peers that trust each other
+    assert!(matches!(db1.write_message(&node2.node, "meet me at noon"), Err(Error::UntrustedPeer)));
+    db1.trust(&node2.node)?;
+    let message = db1.write_message(&node2.node, "meet me at noon")?;
+    assert!(matches!(db2.receive_message(&message), Err(Error::UntrustedPeer)));
+    db2.trust(&node1.node)?;
+
+    assert_eq!(db2.receive_message(&message)?.content, "meet me at noon");
+    assert!(db1.inbox(Some(&node2.node), 10)?[0].outgoing);
+    assert_eq!(db2.inbox(None, 10)?[0].peer, node1.node);
+
+    // Nothing about it can change on the way
+    let mut tampered = message.clone();
+    tampered.sent_at += 1;
+    assert!(db2.receive_message(&tampered).is_err());
+    let mut tampered = message.clone();
+    tampered.recipient = node1.node.clone();
+    assert!(matches!(db2.receive_message(&tampered), Err(Error::MisdirectedMessage)));
+
+    // And it never shows up with the posts
+    assert_eq!(db2.db.open_tree(crate::db::handle_post::POSTS_TABLE)?.len(), 0);
+
+    // Nobody reading the database finds it either
+    for db in [&db1, &db2] {
+        for message in db.db.open_tree(MESSAGES_TABLE)?.iter() {
+            let (_key, message) = message?;
+            assert!(!message.windows(15).any(|bytes| bytes == b"meet me at noon"));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_pending_messages() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::Settings;
+
+    let settings = Settings { outbox_backoff: 0, ..Settings::default() };
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?.with_settings(settings);
+    let peer = db.generate_identity()?;
+    db.trust(&peer.node)?;
+
+    // Not sent until they have it
+    let message = db.write_message(&peer.node, "hi")?;
+    assert!(!db.inbox(None, 10)?[0].delivered);
+    assert_eq!(db.due_messages()?, vec![message.clone()]);
+    assert!(matches!(db.message_failed(&message)?, Retry::At(_)));
+
+    db.message_delivered(&message)?;
+    assert!(db.inbox(None, 10)?[0].delivered);
+    assert_eq!(db.due_messages()?, vec![]);
+    assert_eq!(db.message_failed(&message)?, Retry::GaveUp);
+
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use std::fmt;
+
+/*
+    Everything the db can refuse or fail at.
+
+    Callers can match on these (e.g. a duplicate post is normal while
+    syncing, a storage error is not), and Display keeps the old messages
+    for logging and for sending back to peers.
+*/
+
+#[derive(Debug)]
+pub enum Error {
+    // Storage
+    Storage(sled::Error),
+    Encoding(bincode::Error),
+
+    // Posts
+    DuplicatePost,
+    ExpiredPost, // We had it once, but it was pruned
+    PostNotFound,
+    AmbiguousPost,
+    BrokenHistory,
+    MisdirectedPost, // Last hop of the history isn't us
+    InvalidSignature,
+    OwnPost,
+    FuturePost, // Claims to be written further in the future than clocks drift
+    UnsupportedPost(u8), // Post version we don't know how to verify
+    MalformedPost, // Has fields its version doesn't sign
+    RotatedKey, // Author moved to a new key before writing this
+    BrokenFeed, // seq and prev don't go together
+    ForkedFeed, // Author wrote two different posts at the same point of their feed
+
+    // Direct messages
+    InvalidKey, // Not a public key we can encrypt to
+    MisdirectedMessage,
+    UndecryptableMessage,
+
+    // Identity
+    Locked, // Identity key is encrypted and nobody gave us the passphrase yet
+    WrongPassphrase,
+    IdentityExists, // Importing would replace the identity we already have
+    Unprotected, // Importing would store the key in the clear where the old one was locked
+    InvalidBackup,
+    RecoveringFeed, // Imported identity, our earlier posts have to come back before we write new ones
+    InvalidRotation, // Rotates to itself, or the key already rotated somewhere else
+
+    // Trust
+    AlreadyTrusted,
+    UntrustedPeer,
+    UntrustedIntermediate,
+    InsufficientHistory,
+    SelfTrust,
+    WrongRecipient,
+    IntermediateIsUs,
+    UnknownPost,
+    UnsharedPost, // We never gave the post to the intermediate node
+    PeerLimitReached,
+    MinimumPeers,
+    UnsolicitedResponse,
+    NotBootstrap
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Storage(e) => write!(f, "Storage error: {}", e),
+            Error::Encoding(e) => write!(f, "Could not (de)serialize: {}", e),
+
+            Error::DuplicatePost => write!(f, "We have already seen this post"),
+            Error::ExpiredPost => write!(f, "This post expired and was deleted"),
+            Error::PostNotFound => write!(f, "Could not find post"),
+            Error::AmbiguousPost => write!(f, "More than one post matches that id"),
+            Error::BrokenHistory => write!(f, "History contained broken chain"),
+            Error::MisdirectedPost => write!(f, "We got a post that was not intended for us"),
+            Error::InvalidSignature => write!(f, "Signature is invalid"),
+            Error::OwnPost => write!(f, "Cannot promote our own post"),
+            Error::FuturePost => write!(f, "Post was created in the future"),
+            Error::UnsupportedPost(version) => write!(f, "Unsupported post version {}", version),
+            Error::MalformedPost => write!(f, "Post has fields its version does not cover"),
+            Error::RotatedKey => write!(f, "Post was signed by a key its author has replaced"),
+            Error::BrokenFeed => write!(f, "Post links to its author's feed the wrong way"),
+            Error::ForkedFeed => write!(f, "Post conflicts with another post in its author's feed"),
+
+            Error::InvalidKey => write!(f, "Public key cannot be used for encryption"),
+            Error::MisdirectedMessage => write!(f, "We got a message that was meant for someone else"),
+            Error::UndecryptableMessage => write!(f, "Could not decrypt message"),
+
+            Error::Locked => write!(f, "Identity is locked, unlock it with its passphrase first"),
+            Error::WrongPassphrase => write!(f, "Wrong passphrase"),
+            Error::IdentityExists => write!(f, "This database already has an identity, force the import to replace it"),
+            Error::Unprotected => write!(f, "The identity this replaces is locked, the imported one needs a passphrase too"),
+            Error::InvalidBackup => write!(f, "Not a valid identity backup"),
+            Error::RecoveringFeed => write!(f, "Still fetching our earlier posts from our peers, try again once one of them answered"),
+            Error::InvalidRotation => write!(f, "Key rotation conflicts with what we know about that key"),
+
+            Error::AlreadyTrusted => write!(f, "Already trusted"),
+            Error::UntrustedPeer => write!(f, "Only trusted peers can do that"),
+            Error::UntrustedIntermediate => write!(f, "Trust referenced untrusted intermediate node"),
+            Error::InsufficientHistory => write!(f, "Post does not contain enough history (we are already directly trusted or node history was tampered)"),
+            Error::SelfTrust => write!(f, "Tried to accept a trust request from ourself"),
+            Error::WrongRecipient => write!(f, "Trust request was meant for another node"),
+            Error::IntermediateIsUs => write!(f, "Trust request used us as the intermediate node"),
+            Error::UnknownPost => write!(f, "Trust referenced an unknown post"),
+            Error::UnsharedPost => write!(f, "Trust request referenced post that we did not send to the intermediate node"),
+            Error::PeerLimitReached => write!(f, "candidate node was not good enough to kick worst trusted node (too many peers)"),
+            Error::MinimumPeers => write!(f, "Hit minimum number of trusted nodes"),
+            Error::UnsolicitedResponse => write!(f, "Got a response we never asked for"),
+            Error::NotBootstrap => write!(f, "We are not a bootstrap node")
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Storage(e) => Some(e),
+            Error::Encoding(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Self {
+        Error::Storage(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::Encoding(e)
+    }
+}
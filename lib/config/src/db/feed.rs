@@ -0,0 +1,373 @@
+use serde::{Serialize, Deserialize};
+
+use crate::db::{NodeDB, Node, RawPost, OutgoingPost, PostId, Error};
+use crate::db::identity::Identity;
+use crate::db::trust::Trust;
+use crate::db::handle_post::{HandlePost, POSTS_TABLE};
+use crate::db::post_sync::MAX_SYNC_PAGE;
+use crate::db::migrate::META_TABLE;
+
+/*
+    Every author's posts form an append only log, like a Scuttlebutt feed.
+
+    A post in the feed carries its position (seq, starting at 1) and the id
+    of the author's previous post. Positions we have no post for are gaps we
+    can ask our peers to fill. An author that signs two different posts for
+    the same position, or links to something that isn't their previous post,
+    has forked their feed: we keep what we saw first and refuse the rest.
+    That includes us: an identity imported into a fresh database doesn't know
+    where its feed is at, so it can't post until a peer sent it back.
+    Once retention drops the oldest posts of a feed their entries go too, up
+    to the first post we still have. Everything below that is forgotten, it is
+    neither a gap nor a place a post can still go.
+*/
+
+pub trait Feed {
+    fn new_post(&self, content: String) -> Result<RawPost, Error>;
+    fn recovering_feed(&self) -> Result<bool, Error>;
+    fn feed_recovered(&self) -> Result<(), Error>;
+    fn feed_head(&self, author: &Node) -> Result<Option<FeedEntry>, Error>;
+    fn feed_gaps(&self, author: &Node) -> Result<Vec<(u64, u64)>, Error>;
+    fn get_fork(&self, author: &Node) -> Result<Option<Fork>, Error>;
+    fn feed_after(&self, requester: &Node, author: &Node, after: u64, limit: usize) -> Result<FeedPage, Error>;
+}
+
+// author public key + seq (big endian) -> FeedEntry, stays around when the post itself is pruned
+const FEED_TABLE:&str = "FEED_TABLE";
+// author public key -> seq of the last entry we pruned
+const FEED_FLOOR_TABLE:&str = "FEED_FLOOR_TABLE";
+// In META_TABLE while an imported identity waits for its own feed
+const FEED_RECOVERY:&[u8] = b"feed_recovery";
+// author public key -> the first Fork we caught them at
+const FORK_TABLE:&str = "FORK_TABLE";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct FeedEntry {
+    pub seq: u64,
+    pub post: PostId,
+    pub prev: Option<PostId>
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Fork {
+    pub seq: u64,
+    pub kept: PostId,   // Already in the feed, at seq or right next to it
+    pub refused: PostId
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct FeedPage {
+    pub posts: Vec<OutgoingPost>,
+    pub last: u64, // seq of the last entry in this page, ask for what comes after it next
+    pub more: bool
+}
+
+fn feed_key(author: &Node, seq: u64) -> Vec<u8> {
+    [&author.public_key[..], &seq.to_be_bytes()[..]].concat()
+}
+
+impl NodeDB {
+    fn feed_entry(&self, author: &Node, seq: u64) -> Result<Option<FeedEntry>, Error> {
+        let feed = self.db.open_tree(FEED_TABLE)?;
+        match feed.get(feed_key(author, seq))? {
+            Some(entry) => Ok(Some(bincode::deserialize(&entry)?)),
+            None => Ok(None)
+        }
+    }
+
+    // The post in the feed that disagrees with this entry, if any
+    fn feed_conflict(&self, author: &Node, entry: &FeedEntry) -> Result<Option<PostId>, Error> {
+        if let Some(existing) = self.feed_entry(author, entry.seq)? {
+            if existing.post != entry.post {
+                return Ok(Some(existing.post));
+            }
+        }
+        if let Some(before) = self.feed_entry(author, entry.seq - 1)? {
+            if Some(&before.post) != entry.prev.as_ref() {
+                return Ok(Some(before.post));
+            }
+        }
+        if let Some(next) = entry.seq.checked_add(1) {
+            if let Some(after) = self.feed_entry(author, next)? {
+                if after.prev.as_ref() != Some(&entry.post) {
+                    return Ok(Some(after.post));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn start_feed_recovery(&self) -> Result<(), Error> {
+        self.db.open_tree(META_TABLE)?.insert(FEED_RECOVERY, &[])?;
+        Ok(())
+    }
+
+    fn feed_floor(&self, author: &Node) -> Result<u64, Error> {
+        let floors = self.db.open_tree(FEED_FLOOR_TABLE)?;
+        match floors.get(author.public_key)? {
+            Some(floor) => Ok(bincode::deserialize(&floor)?),
+            None => Ok(0)
+        }
+    }
+
+    // Called whenever retention drops one of the author's posts, the head stays so new posts still link up
+    pub(crate) fn prune_feed(&self, author: &Node) -> Result<usize, Error> {
+        let feed = self.db.open_tree(FEED_TABLE)?;
+        let posts = self.db.open_tree(POSTS_TABLE)?;
+        let Some(head) = self.feed_head(author)? else {
+            return Ok(0);
+        };
+
+        let mut pruned = 0;
+        for entry in feed.scan_prefix(author.public_key) {
+            let (key, entry) = entry?;
+            let entry: FeedEntry = bincode::deserialize(&entry)?;
+            if entry.seq == head.seq || posts.contains_key(entry.post.raw)? {
+                break;
+            }
+            feed.remove(key)?;
+            self.db.open_tree(FEED_FLOOR_TABLE)?.insert(author.public_key, bincode::serialize(&entry.seq)?)?;
+            pruned += 1;
+        }
+        Ok(pruned)
+    }
+
+    // The newest `limit` entries of the author's feed, newest first
+    pub(crate) fn latest_feed(&self, author: &Node, limit: usize) -> Result<Vec<FeedEntry>, Error> {
+        let feed = self.db.open_tree(FEED_TABLE)?;
+
+        let mut entries = vec![];
+        for entry in feed.scan_prefix(author.public_key).rev().take(limit) {
+            let (_key, entry) = entry?;
+            entries.push(bincode::deserialize(&entry)?);
+        }
+        Ok(entries)
+    }
+
+    // Called for every post we receive, before we store it
+    pub(crate) fn append_feed(&self, post: &RawPost) -> Result<(), Error> {
+        if post.seq == 0 {
+            return Ok(());
+        }
+
+        // We let go of this part of the feed, a fork there would go unnoticed
+        if post.seq <= self.feed_floor(&post.author)? {
+            return Err(Error::ExpiredPost);
+        }
+
+        let entry = FeedEntry { seq: post.seq, post: post.get_id(), prev: post.prev.clone() };
+        if let Some(kept) = self.feed_conflict(&post.author, &entry)? {
+            let forks = self.db.open_tree(FORK_TABLE)?;
+            if !forks.contains_key(post.author.public_key)? {
+                let fork = Fork { seq: post.seq, kept, refused: entry.post };
+                forks.insert(post.author.public_key, bincode::serialize(&fork)?)?;
+            }
+            return Err(Error::ForkedFeed);
+        }
+
+        let feed = self.db.open_tree(FEED_TABLE)?;
+        feed.insert(feed_key(&post.author, post.seq), bincode::serialize(&entry)?)?;
+        Ok(())
+    }
+}
+
+impl Feed for NodeDB {
+    // Our next post, right after the last one we wrote
+    fn new_post(&self, content: String) -> Result<RawPost, Error> {
+        if self.recovering_feed()? {
+            return Err(Error::RecoveringFeed);
+        }
+        let us = self.get_identity()?;
+        let mut post = RawPost::new(us.node.clone(), content);
+
+        match self.feed_head(&us.node)? {
+            Some(head) => {
+                post.seq = head.seq + 1;
+                post.prev = Some(head.post);
+            },
+            None => post.seq = 1
+        }
+        Ok(post)
+    }
+
+    fn recovering_feed(&self) -> Result<bool, Error> {
+        Ok(self.db.open_tree(META_TABLE)?.contains_key(FEED_RECOVERY)?)
+    }
+
+    // A peer sent us all they have of our feed, whatever we write next goes after that
+    fn feed_recovered(&self) -> Result<(), Error> {
+        self.db.open_tree(META_TABLE)?.remove(FEED_RECOVERY)?;
+        Ok(())
+    }
+
+    fn feed_head(&self, author: &Node) -> Result<Option<FeedEntry>, Error> {
+        let feed = self.db.open_tree(FEED_TABLE)?;
+        match feed.scan_prefix(author.public_key).next_back() {
+            Some(entry) => Ok(Some(bincode::deserialize(&entry?.1)?)),
+            None => Ok(None)
+        }
+    }
+
+    // Ranges (inclusive) of seqs missing below the newest post we have, and above what we pruned
+    fn feed_gaps(&self, author: &Node) -> Result<Vec<(u64, u64)>, Error> {
+        let feed = self.db.open_tree(FEED_TABLE)?;
+
+        let mut gaps = vec![];
+        let mut expected = self.feed_floor(author)? + 1;
+        for entry in feed.scan_prefix(author.public_key) {
+            let (_key, entry) = entry?;
+            let entry: FeedEntry = bincode::deserialize(&entry)?;
+            if entry.seq > expected {
+                gaps.push((expected, entry.seq - 1));
+            }
+            expected = entry.seq.saturating_add(1);
+        }
+        Ok(gaps)
+    }
+
+    fn get_fork(&self, author: &Node) -> Result<Option<Fork>, Error> {
+        let forks = self.db.open_tree(FORK_TABLE)?;
+        match forks.get(author.public_key)? {
+            Some(fork) => Ok(Some(bincode::deserialize(&fork)?)),
+            None => Ok(None)
+        }
+    }
+
+    fn feed_after(&self, requester: &Node, author: &Node, after: u64, limit: usize) -> Result<FeedPage, Error> {
+        // Same rules as posts_after
+        if self.bootstrap_nodes.is_some() && !self.is_trusted(requester)? {
+            return Err(Error::UntrustedPeer);
+        }
+
+        let feed = self.db.open_tree(FEED_TABLE)?;
+        let us = self.get_identity()?;
+
+        // One extra, to know if there is another page
+        let limit = limit.min(MAX_SYNC_PAGE);
+        let mut entries = vec![];
+        for entry in feed.range(feed_key(author, after.saturating_add(1))..).take(limit + 1) {
+            let (key, entry) = entry?;
+            if !key.starts_with(&author.public_key) {
+                break;
+            }
+            entries.push(bincode::deserialize::<FeedEntry>(&entry)?);
+        }
+        let more = entries.len() > limit;
+        entries.truncate(limit);
+
+        let last = entries.last().map_or(after, |entry| entry.seq);
+
+        let mut result = vec![];
+        for entry in entries {
+            // Pruned since, that stays a gap for the requester too
+            let post = match self.resolve(&entry.post) {
+                Ok(post) => post,
+                Err(Error::PostNotFound) => continue,
+                Err(e) => return Err(e)
+            };
+
+            self.register_seen(requester, &entry.post)?;
+            result.push(OutgoingPost::from_incoming(&post, &us, requester));
+        }
+
+        Ok(FeedPage { posts: result, last, more })
+    }
+}
+
+#[test]
+fn test_feed() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{IncomingPost, MAX_CLOCK_SKEW};
+
+    let db1 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node1 = db1.get_identity()?;
+
+    let db2 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node2 = db2.get_identity()?;
+
+    let mut posts = vec![];
+    for idx in 0..3 {
+        let raw_post = db1.new_post(format!("post {}", idx))?;
+        let signature = node1.sign(&raw_post.get_id().raw);
+        db1.receive(&IncomingPost::new(&raw_post, &vec![], &signature, &node1, MAX_CLOCK_SKEW)?)?;
+        posts.push((raw_post, signature));
+    }
+    assert_eq!(db1.feed_head(&node1.node)?.map(|head| head.seq), Some(3));
+    assert_eq!(posts[2].0.prev, Some(posts[1].0.get_id()));
+
+    // Missing the middle one
+    for (raw_post, signature) in [&posts[0], &posts[2]] {
+        db2.receive(&IncomingPost::new(raw_post, &vec![], signature, &node2, MAX_CLOCK_SKEW)?)?;
+    }
+    assert_eq!(db2.feed_gaps(&node1.node)?, vec![(2, 2)]);
+
+    // Which is exactly what we ask for
+    let page = db1.feed_after(&node2.node, &node1.node, 1, 1)?;
+    assert_eq!((page.posts.len(), page.last, page.more), (1, 2, true));
+    let synced = &page.posts[0];
+    db2.receive(&IncomingPost::new(&synced.post, &synced.history, &synced.signature, &node2, MAX_CLOCK_SKEW)?)?;
+    assert_eq!(db2.feed_gaps(&node1.node)?, vec![]);
+
+    // A second post at seq 2 is a fork
+    let mut forked = posts[1].0.clone();
+    forked.content = "something else".to_string();
+    let signature = node1.sign(&forked.get_id().raw);
+    let forked_post = IncomingPost::new(&forked, &vec![], &signature, &node2, MAX_CLOCK_SKEW)?;
+    assert!(matches!(db2.receive(&forked_post), Err(Error::ForkedFeed)));
+    assert_eq!(db2.get_fork(&node1.node)?.map(|fork| fork.kept), Some(posts[1].0.get_id()));
+
+    // Only the first post of a feed may link to nothing
+    forked.prev = None;
+    let signature = node1.sign(&forked.get_id().raw);
+    assert!(matches!(IncomingPost::new(&forked, &vec![], &signature, &node2, MAX_CLOCK_SKEW), Err(Error::BrokenFeed)));
+
+    // Every post since version 2 is in the feed, older ones can't claim a place in it
+    forked.seq = 0;
+    let signature = node1.sign(&forked.get_id().raw);
+    assert!(matches!(IncomingPost::new(&forked, &vec![], &signature, &node2, MAX_CLOCK_SKEW), Err(Error::BrokenFeed)));
+    forked.version = 1;
+    forked.seq = 1;
+    let signature = node1.sign(&forked.get_id().raw);
+    assert!(matches!(IncomingPost::new(&forked, &vec![], &signature, &node2, MAX_CLOCK_SKEW), Err(Error::BrokenFeed)));
+
+    Ok(())
+}
+
+#[test]
+fn test_feed_pruning() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{IncomingPost, Settings, MAX_CLOCK_SKEW};
+    use crate::db::retention::Retention;
+
+    let db1 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node1 = db1.get_identity()?;
+
+    let settings = Settings { retention_count: Some(0), ..Settings::default() };
+    let db2 = NodeDB::new(tempfile::TempDir::new()?, None)?.with_settings(settings);
+    let node2 = db2.get_identity()?;
+
+    // Seq 2 never made it here
+    let mut posts = vec![];
+    for idx in 0..4 {
+        let raw_post = db1.new_post(format!("post {}", idx))?;
+        let post = IncomingPost::new(&raw_post, &vec![], &node1.sign(&raw_post.get_id().raw), &node2, MAX_CLOCK_SKEW)?;
+        db1.receive(&post)?;
+        if idx != 1 {
+            db2.receive(&post)?;
+        }
+        posts.push(post);
+    }
+    assert_eq!(db2.feed_gaps(&node1.node)?, vec![(2, 2)]);
+
+    // Only the head is left, and nothing below it is missing anymore
+    let compaction = db2.compact()?;
+    assert_eq!((compaction.posts, compaction.feed), (3, 2));
+    assert_eq!(db2.feed_head(&node1.node)?.map(|head| head.seq), Some(4));
+    assert_eq!(db2.feed_gaps(&node1.node)?, vec![]);
+    assert!(matches!(db2.receive(&posts[1]), Err(Error::ExpiredPost)));
+
+    // The feed carries on from there
+    let raw_post = db1.new_post("post 4".to_string())?;
+    db2.receive(&IncomingPost::new(&raw_post, &vec![], &node1.sign(&raw_post.get_id().raw), &node2, MAX_CLOCK_SKEW)?)?;
+    assert_eq!(db2.feed_head(&node1.node)?.map(|head| head.seq), Some(5));
+
+    Ok(())
+}
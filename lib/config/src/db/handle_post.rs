@@ -1,33 +1,115 @@
 
-use crate::db::{identity::Identity, trust::Trust, IncomingPost, NodeDB, OutgoingPost, PostId, Node};
+use crate::db::{identity::Identity, trust::Trust, IncomingPost, NodeDB, OutgoingPost, PostId, Node, Error};
 use crate::misc::get_epoch;
+use serde::{Serialize, Deserialize};
+use std::ops::Bound;
+use crate::db::text::{tokenize, text_prefix};
+use crate::db::retention::Retention;
+use crate::db::rotation::KeyRotations;
+use crate::db::post_sync::SyncCursor;
 
 pub trait HandlePost {
-    fn resolve(&self, post: &PostId) -> Result<IncomingPost, Box<dyn std::error::Error>>;
-    fn receive(&self, post: &IncomingPost) -> Result<Vec<OutgoingPost>, Box<dyn std::error::Error>>;
-    fn has_seen(&self, node: &Node, post:&PostId) -> Result<bool, Box<dyn std::error::Error>>;
-    fn register_seen(&self, node: &Node, post:&PostId) -> Result<(), Box<dyn std::error::Error>>;
+    fn resolve(&self, post: &PostId) -> Result<IncomingPost, Error>;
+    fn resolve_prefix(&self, prefix: &str) -> Result<IncomingPost, Error>;
+    fn receive(&self, post: &IncomingPost) -> Result<Vec<OutgoingPost>, Error>;
+    fn has_seen(&self, node: &Node, post:&PostId) -> Result<bool, Error>;
+    fn register_seen(&self, node: &Node, post:&PostId) -> Result<(), Error>;
+    fn posts_by_time(&self, from: &Option<PostCursor>, direction: Direction, limit: usize) -> Result<Vec<IncomingPost>, Error>;
+    fn posts_received(&self, from: &Option<SyncCursor>, limit: usize) -> Result<Vec<IncomingPost>, Error>;
 }
 
 pub const SEEN_TABLE:&str = "SEEN_TABLE";
 pub const POSTS_TABLE:&str = "POSTS_TABLE";
 
+// created (big endian, so it sorts) + post id -> nothing, the post lives in POSTS_TABLE
+pub const POST_TIME_INDEX:&str = "POST_TIME_INDEX";
+// received + post id, for syncing peers whatever reached us since they last asked
+pub const RECEIVED_INDEX:&str = "RECEIVED_INDEX";
+// Same as the time index, prefixed by the author public key
+pub const AUTHOR_INDEX:&str = "AUTHOR_INDEX";
+// Same as above, prefixed by the public key of the peer that gave us the post
+pub const VIA_INDEX:&str = "VIA_INDEX";
+// Every word in a post, see text.rs
+pub const TEXT_INDEX:&str = "TEXT_INDEX";
+
+// Where a page of posts ended, posts are ordered by when their author wrote them
+// so an old post that reaches us late doesn't jump to the top
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct PostCursor {
+    pub created: u64,
+    pub post: PostId // Breaks ties between posts written in the same second
+}
+
+impl PostCursor {
+    pub fn of(post: &IncomingPost) -> Self {
+        PostCursor { created: post.created(), post: post.get_id() }
+    }
+
+    pub(crate) fn key(&self) -> Vec<u8> {
+        [&self.created.to_be_bytes()[..], &self.post.raw[..]].concat()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Direction {
+    Forward,  // Oldest first, after the cursor
+    Backward  // Newest first, before the cursor
+}
+
 impl HandlePost for NodeDB {
-    fn resolve(&self, post: &PostId) -> Result<IncomingPost, Box<dyn std::error::Error>> {
+    fn resolve(&self, post: &PostId) -> Result<IncomingPost, Error> {
         let posts = self.db.open_tree(POSTS_TABLE)?;
-        let raw_post = posts.get(post.raw)?.ok_or("Could not find post")?;
+        let raw_post = posts.get(post.raw)?.ok_or(Error::PostNotFound)?;
         let post:IncomingPost = bincode::deserialize(&raw_post)?;
         Ok(post)
     }
 
-    fn receive(&self, post: &IncomingPost) -> Result<Vec<OutgoingPost>, Box<dyn std::error::Error>> {
+    // Ids are typed by hand, so the prefix can end halfway through a byte
+    fn resolve_prefix(&self, prefix: &str) -> Result<IncomingPost, Error> {
+        let prefix = prefix.to_lowercase();
+        if !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::PostNotFound);
+        }
+        let bytes = hex::decode(&prefix[..prefix.len() / 2 * 2]).map_err(|_| Error::PostNotFound)?;
+
+        let posts = self.db.open_tree(POSTS_TABLE)?;
+        let mut matches = posts.scan_prefix(bytes)
+            .filter(|entry| entry.as_ref().map_or(true, |(post_id, _)| hex::encode(post_id).starts_with(&prefix)));
+
+        let (_post_id, raw_post) = matches.next().ok_or(Error::PostNotFound)??;
+        if matches.next().is_some() {
+            return Err(Error::AmbiguousPost);
+        }
+
+        let post:IncomingPost = bincode::deserialize(&raw_post)?;
+        Ok(post)
+    }
+
+    fn receive(&self, post: &IncomingPost) -> Result<Vec<OutgoingPost>, Error> {
         let posts = self.db.open_tree(POSTS_TABLE)?;
         let us = self.get_identity()?;
 
         // Make sure this post hasn't been given to us already
         if self.has_seen(&us.node,&post.get_id() )? {
-            return Err("We have already seen this post")?;
+            return Err(Error::DuplicatePost);
+        }
+        if self.is_tombstoned(&post.get_id())? {
+            return Err(Error::ExpiredPost);
         }
+        // Signed timestamps let us turn away old posts even after their tombstone is gone.
+        // Version 0 posts could be any age, so a deleted one could always come back.
+        if let Some(age) = self.settings.retention_age {
+            if post.post.version == 0 || post.post.created_at < get_epoch().saturating_sub(age) {
+                return Err(Error::ExpiredPost);
+            }
+        }
+        // The author moved on, whoever still signs with the old key is not them
+        if let Some(rotation) = self.rotated_to(&post.post.author)? {
+            if !rotation.precedes(&post.post) {
+                return Err(Error::RotatedKey);
+            }
+        }
+        self.append_feed(&post.post)?;
         self.register_seen(&us.node, &post.get_id())?;
 
         // register seen for each node in history
@@ -37,6 +119,10 @@ impl HandlePost for NodeDB {
 
         // Insert the post into the database for future fetching / searching
         posts.insert(post.get_id().raw, bincode::serialize(&post)?)?;
+        self.index_post(post)?;
+        if post.post.author == us.node {
+            self.pin(&post.get_id())?;
+        }
 
         // Get our peers and add our signature to confirm that we sent it to them 
         let trusted_nodes = self.get_trusted()?;
@@ -55,13 +141,13 @@ impl HandlePost for NodeDB {
         Ok(result)
     }
 
-    fn has_seen(&self, node: &Node, post:&PostId) -> Result<bool, Box<dyn std::error::Error>>{
+    fn has_seen(&self, node: &Node, post:&PostId) -> Result<bool, Error>{
         let seen = self.db.open_tree(SEEN_TABLE)?;
         let key = [node.public_key, post.raw].concat();
         Ok(seen.contains_key(key)?)
     }
 
-    fn register_seen(&self, node: &Node, post:&PostId) -> Result<(), Box<dyn std::error::Error>> {
+    fn register_seen(&self, node: &Node, post:&PostId) -> Result<(), Error> {
         let seen = self.db.open_tree(SEEN_TABLE)?;
         let key = [node.public_key, post.raw].concat();
         let time = get_epoch();
@@ -69,11 +155,100 @@ impl HandlePost for NodeDB {
         Ok(())
     }
 
+    fn posts_by_time(&self, from: &Option<PostCursor>, direction: Direction, limit: usize) -> Result<Vec<IncomingPost>, Error> {
+        self.scan_index(POST_TIME_INDEX, &[], from.as_ref().map(PostCursor::key), direction, limit)
+    }
+
+    // In the order they reached us, for whoever only wants what is new since they last looked
+    fn posts_received(&self, from: &Option<SyncCursor>, limit: usize) -> Result<Vec<IncomingPost>, Error> {
+        self.scan_index(RECEIVED_INDEX, &[], from.as_ref().map(SyncCursor::key), Direction::Forward, limit)
+    }
+
+}
+
+// Every index entry that points to this post
+fn index_keys(post: &IncomingPost) -> Vec<(&'static str, Vec<u8>)> {
+    let key = PostCursor::of(post).key();
+
+    let mut keys = vec![
+        (POST_TIME_INDEX, key.clone()),
+        (RECEIVED_INDEX, SyncCursor::of(post).key()),
+        (AUTHOR_INDEX, [&post.post.author.public_key[..], &key].concat())
+    ];
+    if let Some(last) = post.history.last() { // Our own posts did not come from anyone
+        keys.push((VIA_INDEX, [&last.from.public_key[..], &key].concat()));
+    }
+
+    let mut words = tokenize(&post.post.content);
+    words.sort();
+    words.dedup();
+    for word in words {
+        keys.push((TEXT_INDEX, [&text_prefix(&word)[..], &key].concat()));
+    }
+    keys
+}
+
+impl NodeDB {
+    // Also used to backfill the indexes
+    pub(crate) fn index_post(&self, post: &IncomingPost) -> Result<(), Error> {
+        for (index, key) in index_keys(post) {
+            self.db.open_tree(index)?.insert(key, &[])?;
+        }
+        Ok(())
+    }
+
+    // Whatever the old key saw, the new one has too
+    pub(crate) fn move_seen(&self, old: &Node, new: &Node) -> Result<(), Error> {
+        let seen = self.db.open_tree(SEEN_TABLE)?;
+        for entry in seen.scan_prefix(old.public_key) {
+            let (key, time) = entry?;
+            let moved = [&new.public_key[..], &key[32..]].concat();
+            let _ = seen.compare_and_swap(moved, None as Option<&[u8]>, Some(time))?;
+            seen.remove(key)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn unindex_post(&self, post: &IncomingPost) -> Result<(), Error> {
+        for (index, key) in index_keys(post) {
+            self.db.open_tree(index)?.remove(key)?;
+        }
+        Ok(())
+    }
+
+    // Posts from an index whose keys are prefix + a cursor key (8 byte time + post id)
+    pub(crate) fn scan_index(&self, index: &str, prefix: &[u8], from: Option<Vec<u8>>, direction: Direction, limit: usize) -> Result<Vec<IncomingPost>, Error> {
+        let index = self.db.open_tree(index)?;
+        let posts = self.db.open_tree(POSTS_TABLE)?;
+
+        // The cursor itself was on the last page already
+        let keys = match (from, direction) {
+            (None, _) => index.scan_prefix(prefix),
+            (Some(from), Direction::Forward) => {
+                let from = [prefix, &from[..]].concat();
+                index.range::<Vec<u8>, _>((Bound::Excluded(from), Bound::Unbounded))
+            },
+            (Some(from), Direction::Backward) => index.range(prefix.to_vec()..[prefix, &from[..]].concat())
+        };
+        let keys: Box<dyn Iterator<Item = _>> = match direction {
+            Direction::Forward => Box::new(keys),
+            Direction::Backward => Box::new(keys.rev())
+        };
+        let keys = keys.take_while(|key| key.as_ref().map_or(true, |(key, _)| key.starts_with(prefix)));
+
+        let mut results = vec![];
+        for key in keys.take(limit) {
+            let (key, _) = key?;
+            let post = posts.get(&key[prefix.len() + 8..])?.ok_or(Error::PostNotFound)?;
+            results.push(bincode::deserialize(&post)?);
+        }
+        Ok(results)
+    }
 }
 
 #[test]
 fn check_seen() -> Result<(), Box<dyn std::error::Error>> {
-    use crate::db::{Us, RawPost};
+    use crate::db::{RawPost, MAX_CLOCK_SKEW};
 
     let db = NodeDB::new(tempfile::TempDir::new()?, None)?;
     let us = db.get_identity()?;
@@ -83,13 +258,140 @@ fn check_seen() -> Result<(), Box<dyn std::error::Error>> {
         &raw_post, 
         &vec![],
         &signature,
-        &us
+        &us,
+        MAX_CLOCK_SKEW
     )?;
 
     let result = db.receive(&post)?;
     let built_post = db.resolve(&post.get_id())?;
 
     assert_eq!(built_post, post);
+
+    // Seeing it again is not a failure, but callers need to be able to tell
+    assert!(matches!(db.receive(&post), Err(Error::DuplicatePost)));
     
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn check_resolve_prefix() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{RawPost, MAX_CLOCK_SKEW};
+
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let us = db.get_identity()?;
+    let raw_post = RawPost::new(us.node.clone(),"".to_string());
+    let signature = us.sign(&raw_post.get_id().raw);
+    let post = IncomingPost::new(&raw_post, &vec![], &signature, &us, MAX_CLOCK_SKEW)?;
+
+    db.receive(&post)?;
+
+    let short = post.get_id().short();
+    assert_eq!(db.resolve_prefix(&short)?, post);
+    assert_eq!(db.resolve_prefix(&short[..5])?, post);
+    assert_eq!(db.resolve_prefix(&short.to_uppercase())?, post);
+    assert!(db.resolve_prefix(&"0".repeat(64)).is_err());
+    assert!(db.resolve_prefix("xyz").is_err());
+
+    // The odd nibble has to match too
+    let last = u8::from_str_radix(&short[4..5], 16)?;
+    let other = format!("{}{:x}", &short[..4], (last + 1) % 16);
+    assert!(matches!(db.resolve_prefix(&other), Err(Error::PostNotFound)));
+
+    Ok(())
+}
+
+#[test]
+fn check_posts_by_time() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::MAX_CLOCK_SKEW;
+    use crate::db::feed::Feed;
+
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let us = db.get_identity()?;
+
+    // All of these land in the same second, the post id keeps them apart
+    for idx in 0..5 {
+        let raw_post = db.new_post(format!("post {}", idx))?;
+        let signature = us.sign(&raw_post.get_id().raw);
+        db.receive(&IncomingPost::new(&raw_post, &vec![], &signature, &us, MAX_CLOCK_SKEW)?)?;
+    }
+
+    let first = db.posts_by_time(&None, Direction::Forward, 3)?;
+    assert_eq!(first.len(), 3);
+    let rest = db.posts_by_time(&Some(PostCursor::of(first.last().unwrap())), Direction::Forward, 3)?;
+    assert_eq!(rest.len(), 2);
+
+    // Walking back from the newest post gives the same posts in reverse
+    let newest = rest.last().unwrap();
+    let mut back = db.posts_by_time(&Some(PostCursor::of(newest)), Direction::Backward, 10)?;
+    back.reverse();
+    assert_eq!(back.len(), 4);
+    assert_eq!(back, [&first[..], &rest[..1]].concat());
+
+    Ok(())
+}
+
+#[test]
+fn check_backfilled_order() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{RawPost, MAX_CLOCK_SKEW};
+    use crate::db::feed::Feed;
+    use crate::db::post_sync::{PostSync, SyncCursor};
+
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let us = db.get_identity()?;
+    let author = NodeDB::new(tempfile::TempDir::new()?, None)?.get_identity()?;
+
+    let raw_post = db.new_post("just now".to_string())?;
+    let signature = us.sign(&raw_post.get_id().raw);
+    db.receive(&IncomingPost::new(&raw_post, &vec![], &signature, &us, MAX_CLOCK_SKEW)?)?;
+
+    // Written an hour ago, and only reaching us now
+    let mut late = RawPost::new(author.node.clone(), "an hour ago".to_string());
+    late.created_at = get_epoch() - 60 * 60;
+    let signature = author.sign(&late.get_id().raw);
+    db.receive(&IncomingPost::new(&late, &vec![], &signature, &author, MAX_CLOCK_SKEW)?)?;
+
+    let newest = db.posts_by_time(&None, Direction::Backward, 10)?;
+    assert_eq!(newest.iter().map(|post| post.post.clone()).collect::<Vec<_>>(), vec![raw_post, late.clone()]);
+
+    // Peers that synced up to a second ago still get it
+    let synced = SyncCursor { received: get_epoch() - 1, post: PostId { raw: [0xff; 32] } };
+    let page = db.posts_after(&Node::new([7; 32]), &Some(synced), 10)?;
+    assert!(page.posts.iter().any(|post| post.post == late));
+
+    Ok(())
+}
+
+#[test]
+fn check_timestamps() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{RawPost, Settings, MAX_CLOCK_SKEW, POST_VERSION};
+    use crate::misc::get_epoch;
+
+    let settings = Settings { retention_age: Some(60 * 60 * 24 * 30), ..Settings::default() };
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?.with_settings(settings);
+    let us = db.get_identity()?;
+    let sign = |raw_post: &RawPost| us.sign(&raw_post.get_id().raw);
+
+    let mut raw_post = RawPost::new(us.node.clone(), "".to_string());
+    raw_post.created_at = get_epoch() + MAX_CLOCK_SKEW * 2;
+    assert!(matches!(IncomingPost::new(&raw_post, &vec![], &sign(&raw_post), &us, MAX_CLOCK_SKEW), Err(Error::FuturePost)));
+
+    // The timestamp is signed, so nobody along the way can make a post look newer
+    let signature = sign(&raw_post);
+    raw_post.created_at = get_epoch();
+    assert!(IncomingPost::new(&raw_post, &vec![], &signature, &us, MAX_CLOCK_SKEW).is_err());
+
+    // Older than we keep posts around, so it can't be a fresh one
+    raw_post.created_at = 1;
+    let post = IncomingPost::new(&raw_post, &vec![], &sign(&raw_post), &us, MAX_CLOCK_SKEW)?;
+    assert!(matches!(db.receive(&post), Err(Error::ExpiredPost)));
+
+    // No signed time at all, it could be one we deleted long ago
+    let mut raw_post = RawPost { version: 0, created_at: 0, seq: 0, ..RawPost::new(us.node.clone(), "".to_string()) };
+    let post = IncomingPost::new(&raw_post, &vec![], &sign(&raw_post), &us, MAX_CLOCK_SKEW)?;
+    assert!(matches!(db.receive(&post), Err(Error::ExpiredPost)));
+
+    raw_post.version = POST_VERSION + 1;
+    assert!(matches!(IncomingPost::new(&raw_post, &vec![], &sign(&raw_post), &us, MAX_CLOCK_SKEW), Err(Error::UnsupportedPost(_))));
+
+    Ok(())
+}
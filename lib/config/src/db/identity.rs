@@ -1,20 +1,111 @@
-use crate::db::{NodeDB, Node, Us};
+use serde::{Serialize, Deserialize};
+
+use crate::crypto::{self, Locked};
+use crate::db::{NodeDB, Us, Error, NEEDS_REWRITE};
+use crate::db::migrate::META_TABLE;
+use crate::db::feed::Feed;
+
+/*
+    Our private key, in the clear or locked with a passphrase.
+
+    A locked key has to be unlocked once per run before anything can sign,
+    after that it is kept in memory. Unlocking a key that is still in the
+    clear locks it with that passphrase, which is how older databases move over.
+    The database is rewritten the next time it is opened, so the key in the
+    clear does not linger in sled's files.
+
+    To move to another machine, the key can be exported to a file locked with
+    its own passphrase, or written down as 24 BIP39 words. Neither knows where
+    our feed is at, so an import without our posts fetches them first (see feed.rs).
+*/
 
 pub trait Identity {
-    fn generate_identity(&self) -> Result<Us, Box<dyn std::error::Error>>;
-    fn get_identity(&self) -> Result<Us, Box<dyn std::error::Error>>;
+    fn generate_identity(&self) -> Result<Us, Error>;
+    fn get_identity(&self) -> Result<Us, Error>;
+    fn is_locked(&self) -> Result<bool, Error>;
+    fn unlock(&self, passphrase: &str) -> Result<Us, Error>;
+    fn change_passphrase(&self, passphrase: Option<&str>) -> Result<(), Error>;
+    fn export_identity(&self, passphrase: &str) -> Result<Vec<u8>, Error>;
+    fn import_identity(&self, backup: &[u8], passphrase: &str, force: bool, lock_with: Option<&str>) -> Result<Us, Error>;
+    fn export_mnemonic(&self) -> Result<String, Error>;
+    fn import_mnemonic(&self, words: &str, force: bool, lock_with: Option<&str>) -> Result<Us, Error>;
 }
 
 const IDENTITY_TABLE:&str = "IDENTITY_TABLE";
 
+// What an exported identity file holds
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+struct Backup {
+    public_key: [u8; 32], // To tell the files apart, and to check what we unlocked
+    locked: Locked
+}
+
+impl NodeDB {
+    pub(crate) fn remember(&self, us: &Us) {
+        *self.identity.write().unwrap() = Some(us.clone());
+    }
+
+    // Either one or the other is stored. sled keeps old copies around in its files,
+    // so a key that was in the clear is only really gone once the database is rewritten.
+    pub(crate) fn store_identity(&self, us: &Us, passphrase: Option<&str>) -> Result<(), Error> {
+        let identity = self.db.open_tree(IDENTITY_TABLE)?;
+        match passphrase {
+            Some(passphrase) => {
+                let locked = crypto::lock(passphrase, &us.private_key)?;
+                identity.insert(b"locked_key", bincode::serialize(&locked)?)?;
+                if identity.remove(b"private_key")?.is_some() {
+                    self.db.open_tree(META_TABLE)?.insert(NEEDS_REWRITE, &[])?;
+                }
+            },
+            None => {
+                identity.insert(b"private_key", bincode::serialize(&us.private_key)?)?;
+                identity.remove(b"locked_key")?;
+            }
+        }
+        identity.flush()?;
+        Ok(())
+    }
+
+    // A key that was locked stays locked, even if it is a different key now
+    fn replace_identity(&self, us: Us, force: bool, lock_with: Option<&str>) -> Result<Us, Error> {
+        let identity = self.db.open_tree(IDENTITY_TABLE)?;
+        let clear = identity.contains_key(b"private_key")?;
+        let locked = identity.contains_key(b"locked_key")?;
+        if (clear || locked) && !force {
+            return Err(Error::IdentityExists);
+        }
+        if locked && lock_with.is_none() {
+            return Err(Error::Unprotected);
+        }
+
+        self.store_identity(&us, lock_with)?;
+        if clear {
+            self.db.open_tree(META_TABLE)?.insert(NEEDS_REWRITE, &[])?; // The key we replaced is still in sled's files
+        }
+        self.remember(&us);
+
+        if self.feed_head(&us.node)?.is_none() {
+            self.start_feed_recovery()?;
+        }
+        Ok(us)
+    }
+}
+
 impl Identity for NodeDB {
-    fn generate_identity(&self) -> Result<Us, Box<dyn std::error::Error>> {
+    fn generate_identity(&self) -> Result<Us, Error> {
         let mut secret = [0u8; 32];
         rand::fill(&mut secret[..]); 
         Ok(Us::new(secret))
     }
-    fn get_identity(&self) -> Result<Us, Box<dyn std::error::Error>> {
+    fn get_identity(&self) -> Result<Us, Error> {
+        if let Some(us) = self.identity.read().unwrap().as_ref() {
+            return Ok(us.clone());
+        }
+
         let identity = self.db.open_tree(IDENTITY_TABLE)?;
+        if identity.contains_key(b"locked_key")? {
+            return Err(Error::Locked);
+        }
         let private_key = identity.get(b"private_key")?;
 
         let private_key:[u8; 32] = match private_key {
@@ -28,6 +119,204 @@ impl Identity for NodeDB {
                 secret
             }
         };
-        Ok(Us::new(private_key))
+        let us = Us::new(private_key);
+        self.remember(&us);
+        Ok(us)
+    }
+
+    fn is_locked(&self) -> Result<bool, Error> {
+        let identity = self.db.open_tree(IDENTITY_TABLE)?;
+        Ok(self.identity.read().unwrap().is_none() && identity.contains_key(b"locked_key")?)
+    }
+
+    fn unlock(&self, passphrase: &str) -> Result<Us, Error> {
+        let identity = self.db.open_tree(IDENTITY_TABLE)?;
+        let us = match identity.get(b"locked_key")? {
+            Some(locked) => {
+                let locked: Locked = bincode::deserialize(&locked)?;
+                let private_key: [u8; 32] = crypto::unlock(passphrase, &locked)?.try_into().map_err(|_| Error::WrongPassphrase)?;
+                Us::new(private_key)
+            },
+            None => {
+                // A fresh database gets its key locked right away, it never touches disk in the clear
+                let us = match identity.get(b"private_key")? {
+                    Some(private_key) => Us::new(bincode::deserialize(&private_key)?),
+                    None => self.generate_identity()?
+                };
+                self.store_identity(&us, Some(passphrase))?;
+                us
+            }
+        };
+        self.remember(&us);
+        Ok(us)
+    }
+
+    // None stores the key in the clear again, only works while unlocked
+    fn change_passphrase(&self, passphrase: Option<&str>) -> Result<(), Error> {
+        let us = self.get_identity()?;
+        self.store_identity(&us, passphrase)
+    }
+
+    fn export_identity(&self, passphrase: &str) -> Result<Vec<u8>, Error> {
+        let us = self.get_identity()?;
+        let backup = Backup { public_key: us.node.public_key, locked: crypto::lock(passphrase, &us.private_key)? };
+        Ok(bincode::serialize(&backup)?)
     }
-}
\ No newline at end of file
+
+    fn import_identity(&self, backup: &[u8], passphrase: &str, force: bool, lock_with: Option<&str>) -> Result<Us, Error> {
+        let backup: Backup = bincode::deserialize(backup).map_err(|_| Error::InvalidBackup)?;
+        let private_key: [u8; 32] = crypto::unlock(passphrase, &backup.locked)?.try_into().map_err(|_| Error::InvalidBackup)?;
+
+        let us = Us::new(private_key);
+        if us.node.public_key != backup.public_key {
+            return Err(Error::InvalidBackup);
+        }
+        self.replace_identity(us, force, lock_with)
+    }
+
+    // Anyone with these words is us, they are as secret as the key itself
+    fn export_mnemonic(&self) -> Result<String, Error> {
+        let us = self.get_identity()?;
+        let mnemonic = bip39::Mnemonic::from_entropy(&us.private_key).map_err(|_| Error::InvalidBackup)?;
+        Ok(mnemonic.to_string())
+    }
+
+    fn import_mnemonic(&self, words: &str, force: bool, lock_with: Option<&str>) -> Result<Us, Error> {
+        let mnemonic = bip39::Mnemonic::parse(words.trim()).map_err(|_| Error::InvalidBackup)?;
+        let private_key: [u8; 32] = mnemonic.to_entropy().try_into().map_err(|_| Error::InvalidBackup)?;
+        self.replace_identity(Us::new(private_key), force, lock_with)
+    }
+}
+
+#[test]
+fn test_passphrase() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+
+    // Starts out in the clear, like older databases
+    let db = NodeDB::new(dir.path(), None)?;
+    let us = db.get_identity()?;
+    assert_eq!(db.unlock("first")?, us);
+    drop(db);
+
+    let db = NodeDB::new(dir.path(), None)?;
+    assert!(db.is_locked()?);
+    assert!(matches!(db.get_identity(), Err(Error::Locked)));
+    assert!(matches!(db.unlock("wrong"), Err(Error::WrongPassphrase)));
+    assert_eq!(db.unlock("first")?, us);
+
+    db.change_passphrase(Some("second"))?;
+    drop(db);
+
+    let db = NodeDB::new(dir.path(), None)?;
+    assert!(db.unlock("first").is_err());
+    assert_eq!(db.unlock("second")?, us);
+
+    // And back out in the clear
+    assert!(!db.needs_rewrite()?);
+    db.change_passphrase(None)?;
+    drop(db);
+    let db = NodeDB::new(dir.path(), None)?;
+    assert!(!db.is_locked()?);
+    assert_eq!(db.get_identity()?, us);
+
+    Ok(())
+}
+
+#[test]
+fn test_fresh_locked() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+    let db = NodeDB::new(dir.path(), None)?;
+    let us = db.unlock("first")?;
+    assert_eq!(db.get_identity()?, us);
+
+    let identity = db.db.open_tree(IDENTITY_TABLE)?;
+    assert!(!identity.contains_key(b"private_key")?);
+    assert!(!db.needs_rewrite()?); // Nothing in the clear to get rid of
+    drop((identity, db));
+
+    let db = NodeDB::new(dir.path(), None)?;
+    assert!(db.is_locked()?);
+    assert_eq!(db.unlock("first")?, us);
+
+    Ok(())
+}
+
+#[test]
+fn test_backup() -> Result<(), Box<dyn std::error::Error>> {
+    let db1 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let us = db1.get_identity()?;
+    let backup = db1.export_identity("backup")?;
+    let words = db1.export_mnemonic()?;
+    assert_eq!(words.split(' ').count(), 24);
+
+    // A fresh database has no identity until someone asks for it
+    let db2 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    assert!(matches!(db2.import_identity(&backup, "wrong", false, None), Err(Error::WrongPassphrase)));
+    assert!(matches!(db2.import_identity(b"junk", "backup", false, None), Err(Error::InvalidBackup)));
+    assert_eq!(db2.import_identity(&backup, "backup", false, None)?, us);
+    assert_eq!(db2.get_identity()?, us);
+
+    // Never replaces an identity by accident
+    let db3 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let other = db3.get_identity()?;
+    assert!(matches!(db3.import_mnemonic(&words, false, None), Err(Error::IdentityExists)));
+    assert_eq!(db3.get_identity()?, other);
+    assert_eq!(db3.import_mnemonic(&words, true, None)?, us);
+    assert_eq!(db3.get_identity()?, us);
+    assert!(db3.needs_rewrite()?);
+
+    // Or leaves it in the clear where it was locked
+    let dir = tempfile::TempDir::new()?;
+    let db4 = NodeDB::new(dir.path(), None)?;
+    db4.generate_identity().and_then(|other| db4.store_identity(&other, Some("locked")))?;
+    assert!(matches!(db4.import_mnemonic(&words, true, None), Err(Error::Unprotected)));
+    assert_eq!(db4.import_mnemonic(&words, true, Some("still locked"))?, us);
+    drop(db4);
+    let db4 = NodeDB::new(dir.path(), None)?;
+    assert!(db4.is_locked()?);
+    assert_eq!(db4.unlock("still locked")?, us);
+
+    // None of them know where our feed is at, so nothing gets posted until a peer told us
+    assert!(db4.recovering_feed()?);
+    assert!(matches!(db4.new_post("too early".to_string()), Err(Error::RecoveringFeed)));
+    db4.feed_recovered()?;
+    assert_eq!(db4.new_post("hello again".to_string())?.seq, 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_rewrite() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+    let files_contain = |needle: &[u8]| -> Result<bool, std::io::Error> {
+        let mut dirs = vec![dir.path().to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if std::fs::read(&path)?.windows(needle.len()).any(|bytes| bytes == needle) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    };
+
+    let db = NodeDB::new(dir.path(), None)?;
+    let us = db.get_identity()?;
+    db.db.flush()?;
+    assert!(files_contain(&us.private_key)?);
+
+    // Locking it is not enough, sled still has the old value
+    db.unlock("secret")?;
+    assert!(db.needs_rewrite()?);
+    drop(db);
+
+    let db = NodeDB::new(dir.path(), None)?;
+    assert!(!db.needs_rewrite()?);
+    assert!(!files_contain(&us.private_key)?);
+    assert_eq!(db.unlock("secret")?, us);
+
+    Ok(())
+}
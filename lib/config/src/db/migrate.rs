@@ -0,0 +1,301 @@
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+
+use crate::db::{NodeDB, Node, Path, RawPost, PostId, IncomingPost, Error};
+use crate::db::identity::Identity;
+use crate::db::retention::{Retention, Tombstone, TOMBSTONE_TABLE};
+use crate::db::handle_post::{POSTS_TABLE, POST_TIME_INDEX, RECEIVED_INDEX, AUTHOR_INDEX, VIA_INDEX, TEXT_INDEX};
+use crate::db::outbox::OUTBOX_TABLE;
+
+/*
+    Databases written by older versions are brought up to date when they are opened.
+    Bump SCHEMA_VERSION and add a step below whenever a table changes shape,
+    or a new index needs to be filled from the posts we already have.
+*/
+
+pub(crate) const META_TABLE:&str = "META_TABLE";
+pub const SCHEMA_VERSION:u32 = 8;
+
+// Post layouts older databases were written with, only used to read them
+#[derive(Serialize, Deserialize)]
+struct RawPostV0 { // Before schema 5
+    author: Node,
+    content: String,
+    message_id: u128
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawPostV1 { // Before schema 6
+    author: Node,
+    content: String,
+    message_id: u128,
+    version: u8,
+    created_at: u64
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawPostV2 { // Before schema 7
+    author: Node,
+    content: String,
+    message_id: u128,
+    version: u8,
+    created_at: u64,
+    seq: u64,
+    prev: Option<PostId>
+}
+
+// Same shape as IncomingPost, OutgoingPost and OutboxEntry around an older RawPost
+#[derive(Serialize, Deserialize)]
+struct LegacyIncomingPost<P> {
+    post: P,
+    history: Vec<Path>,
+    received: u64,
+    signature: String
+}
+
+#[derive(Serialize, Deserialize)]
+struct LegacyOutgoingPost<P> {
+    post: P,
+    history: Vec<Path>,
+    signature: String
+}
+
+#[derive(Serialize, Deserialize)]
+struct LegacyOutboxEntry<P> {
+    post: LegacyOutgoingPost<P>,
+    queued: u64,
+    attempts: u32,
+    next_attempt: u64
+}
+
+impl From<RawPostV0> for RawPostV1 {
+    fn from(post: RawPostV0) -> Self {
+        RawPostV1 { author: post.author, content: post.content, message_id: post.message_id, version: 0, created_at: 0 }
+    }
+}
+
+impl From<RawPostV1> for RawPostV2 {
+    fn from(post: RawPostV1) -> Self {
+        RawPostV2 {
+            author: post.author,
+            content: post.content,
+            message_id: post.message_id,
+            version: post.version,
+            created_at: post.created_at,
+            seq: 0,
+            prev: None
+        }
+    }
+}
+
+impl From<RawPostV2> for RawPost {
+    fn from(post: RawPostV2) -> Self {
+        RawPost {
+            author: post.author,
+            content: post.content,
+            message_id: post.message_id,
+            version: post.version,
+            created_at: post.created_at,
+            seq: post.seq,
+            prev: post.prev,
+            audience: None
+        }
+    }
+}
+
+impl NodeDB {
+    pub(crate) fn migrate(&self) -> Result<(), Error> {
+        let meta = self.db.open_tree(META_TABLE)?;
+        let version:u32 = match meta.get(b"schema_version")? {
+            Some(version) => bincode::deserialize(&version)?,
+            None => 0
+        };
+
+        // 5: posts carry a version and created_at, 6: their place in the author's feed, 7: an audience
+        // Everything below reads posts so these go first
+        if version < 5 {
+            self.upgrade_post_layout::<RawPostV0, RawPostV1>()?;
+            self.upgrade_tombstones()?;
+        }
+        if version < 6 {
+            self.upgrade_post_layout::<RawPostV1, RawPostV2>()?;
+        }
+        if version < 7 {
+            self.upgrade_post_layout::<RawPostV2, RawPost>()?;
+        }
+
+        // 1: time index, 2: author and via indexes, 3: text index,
+        // 8: keyed on when the author wrote the post, plus the receive index for sync
+        if version < 8 {
+            self.reindex_posts()?;
+        }
+        // 4: our own posts are pinned, so retention never drops them
+        if version < 4 {
+            self.pin_own_posts()?;
+        }
+
+        meta.insert(b"schema_version", bincode::serialize(&SCHEMA_VERSION)?)?;
+        Ok(())
+    }
+
+    fn upgrade_post_layout<Old, New>(&self) -> Result<(), Error>
+    where Old: DeserializeOwned + Into<New>, New: Serialize {
+        // Collected first, sled iterators could see the rewritten values
+        let posts = self.db.open_tree(POSTS_TABLE)?;
+        let old:Vec<_> = posts.iter().collect::<Result<_, _>>()?;
+        for (post_id, post) in old {
+            let post: LegacyIncomingPost<Old> = bincode::deserialize(&post)?;
+            let post = LegacyIncomingPost::<New> { post: post.post.into(), history: post.history, received: post.received, signature: post.signature };
+            posts.insert(post_id, bincode::serialize(&post)?)?;
+        }
+
+        let outbox = self.db.open_tree(OUTBOX_TABLE)?;
+        let old:Vec<_> = outbox.iter().collect::<Result<_, _>>()?;
+        for (key, entry) in old {
+            let entry: LegacyOutboxEntry<Old> = bincode::deserialize(&entry)?;
+            let post = LegacyOutgoingPost::<New> { post: entry.post.post.into(), history: entry.post.history, signature: entry.post.signature };
+            let entry = LegacyOutboxEntry { post, queued: entry.queued, attempts: entry.attempts, next_attempt: entry.next_attempt };
+            outbox.insert(key, bincode::serialize(&entry)?)?;
+        }
+
+        Ok(())
+    }
+
+    fn upgrade_tombstones(&self) -> Result<(), Error> {
+        // Tombstones used to hold only when we dropped the post, none of those were signed posts
+        let tombstones = self.db.open_tree(TOMBSTONE_TABLE)?;
+        let old:Vec<_> = tombstones.iter().collect::<Result<_, _>>()?;
+        for (post_id, dropped_at) in old {
+            let tombstone = Tombstone { created_at: None, dropped_at: bincode::deserialize(&dropped_at)? };
+            tombstones.insert(post_id, bincode::serialize(&tombstone)?)?;
+        }
+
+        Ok(())
+    }
+
+    fn pin_own_posts(&self) -> Result<(), Error> {
+        let posts = self.db.open_tree(POSTS_TABLE)?;
+        // Fresh databases get their identity later, it might be imported
+        if posts.is_empty() {
+            return Ok(());
+        }
+        let us = self.get_identity()?;
+
+        for post in posts.iter() {
+            let (_post_id, post) = post?;
+            let post: IncomingPost = bincode::deserialize(&post)?;
+            if post.post.author == us.node {
+                self.pin(&post.get_id())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn reindex_posts(&self) -> Result<(), Error> {
+        // Whatever is left would point at posts under their old keys
+        for index in [POST_TIME_INDEX, RECEIVED_INDEX, AUTHOR_INDEX, VIA_INDEX, TEXT_INDEX] {
+            self.db.open_tree(index)?.clear()?;
+        }
+
+        let posts = self.db.open_tree(POSTS_TABLE)?;
+
+        for post in posts.iter() {
+            let (_post_id, post) = post?;
+            let post: IncomingPost = bincode::deserialize(&post)?;
+            self.index_post(&post)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_backfill() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::MAX_CLOCK_SKEW;
+    use crate::db::identity::Identity;
+    use crate::db::handle_post::{HandlePost, Direction};
+
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let us = db.get_identity()?;
+    let raw_post = RawPost { author: us.node.clone(), content: "".to_string(), message_id: 1, version: 0, created_at: 0, seq: 0, prev: None, audience: None };
+    let signature = us.sign(&raw_post.get_id().raw);
+    let post = IncomingPost::new(&raw_post, &vec![], &signature, &us, MAX_CLOCK_SKEW)?;
+    db.receive(&post)?;
+
+    // Pretend this database was written before the index existed (and posts had a version)
+    let legacy = LegacyIncomingPost {
+        post: RawPostV0 { author: us.node.clone(), content: "".to_string(), message_id: 1 },
+        history: vec![],
+        received: post.received,
+        signature: signature.clone()
+    };
+    db.db.open_tree(POSTS_TABLE)?.insert(raw_post.get_id().raw, bincode::serialize(&legacy)?)?;
+    db.db.open_tree(POST_TIME_INDEX)?.clear()?;
+    db.db.open_tree(META_TABLE)?.clear()?;
+    assert_eq!(db.posts_by_time(&None, Direction::Forward, 10)?.len(), 0);
+
+    db.migrate()?;
+    assert_eq!(db.posts_by_time(&None, Direction::Forward, 10)?, vec![post]);
+
+    Ok(())
+}
+
+#[test]
+fn test_reindex_by_created() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::MAX_CLOCK_SKEW;
+    use crate::db::identity::Identity;
+    use crate::db::handle_post::{HandlePost, PostCursor};
+    use crate::misc::get_epoch;
+
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let us = db.get_identity()?;
+    let mut raw_post = RawPost::new(us.node.clone(), "".to_string());
+    raw_post.created_at = get_epoch() - 60 * 60;
+    let signature = us.sign(&raw_post.get_id().raw);
+    let post = IncomingPost::new(&raw_post, &vec![], &signature, &us, MAX_CLOCK_SKEW)?;
+    db.receive(&post)?;
+
+    // Schema 7 kept the time index by when we received the post
+    let index = db.db.open_tree(POST_TIME_INDEX)?;
+    index.clear()?;
+    index.insert([&post.received.to_be_bytes()[..], &raw_post.get_id().raw[..]].concat(), &[])?;
+    db.db.open_tree(RECEIVED_INDEX)?.clear()?;
+    db.db.open_tree(META_TABLE)?.insert(b"schema_version", bincode::serialize(&7u32)?)?;
+
+    db.migrate()?;
+    let keys:Vec<Vec<u8>> = index.iter().keys().map(|key| key.map(|key| key.to_vec())).collect::<Result<_, _>>()?;
+    assert_eq!(keys, vec![PostCursor::of(&post).key()]);
+    assert_eq!(db.db.open_tree(RECEIVED_INDEX)?.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_legacy_posts() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::MAX_CLOCK_SKEW;
+    use crate::db::handle_post::HandlePost;
+
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let us = db.get_identity()?;
+
+    // A post the way it was stored before posts had a version
+    let raw_post = RawPost { author: us.node.clone(), content: "old".to_string(), message_id: 7, version: 0, created_at: 0, seq: 0, prev: None, audience: None };
+    let signature = us.sign(&raw_post.get_id().raw);
+    let legacy = LegacyIncomingPost {
+        post: RawPostV0 { author: us.node.clone(), content: "old".to_string(), message_id: 7 },
+        history: vec![],
+        received: 1,
+        signature: signature.clone()
+    };
+    db.db.open_tree(POSTS_TABLE)?.insert(raw_post.get_id().raw, bincode::serialize(&legacy)?)?;
+    db.db.open_tree(META_TABLE)?.insert(b"schema_version", bincode::serialize(&4u32)?)?;
+
+    db.migrate()?;
+
+    // Same id, and the old signature still checks out
+    let post = db.resolve(&raw_post.get_id())?;
+    assert_eq!(post.post, raw_post);
+    assert_eq!(post.created(), 1);
+    IncomingPost::new(&post.post, &post.history, &post.signature, &us, MAX_CLOCK_SKEW)?;
+
+    Ok(())
+}
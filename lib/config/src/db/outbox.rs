@@ -0,0 +1,241 @@
+use serde::{Serialize, Deserialize};
+
+use crate::db::{NodeDB, Node, Us, OutgoingPost, PostId, Error, construct_path_msg};
+use crate::db::score::Score;
+use crate::db::trust::Trust;
+use crate::misc::get_epoch;
+
+/*
+    Posts we could not deliver because the peer was unreachable.
+
+    Every failed attempt doubles the wait before the next one (up to
+    settings.outbox_max_backoff). Every time we could not reach the peer costs
+    them some score, once no matter how many posts were waiting. Once a post
+    is older than settings.outbox_ttl we give up on it, and stop trusting
+    a peer that has been gone for that long.
+*/
+
+pub trait Outbox {
+    fn queue_outgoing(&self, post: &OutgoingPost) -> Result<(), Error>;
+    fn defer_outgoing(&self, post: &OutgoingPost) -> Result<(), Error>;
+    fn due_outgoing(&self) -> Result<Vec<OutboxEntry>, Error>;
+    fn delivered(&self, recipient: &Node, post: &PostId) -> Result<(), Error>;
+    fn delivery_failed(&self, recipient: &Node, post: &PostId) -> Result<Retry, Error>;
+    fn unreachable(&self, peer: &Node) -> Result<(), Error>;
+}
+
+// recipient public key + post id -> OutboxEntry
+pub(crate) const OUTBOX_TABLE:&str = "OUTBOX_TABLE";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct OutboxEntry {
+    pub post: OutgoingPost,
+    pub queued: u64,
+    pub attempts: u32,
+    pub next_attempt: u64
+}
+
+impl OutboxEntry {
+    pub fn recipient(&self) -> &Node {
+        &self.post.history.last().expect("outgoing posts always have a last hop").to
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Retry {
+    At(u64),
+    GaveUp
+}
+
+fn outbox_key(recipient: &Node, post: &PostId) -> Vec<u8> {
+    [recipient.public_key, post.raw].concat()
+}
+
+impl NodeDB {
+    // A rotation on either end of the last hop, which we sign again for whoever we and they are now
+    pub(crate) fn readdress_outbox(&self, us: &Us, old: &Node, new: &Node) -> Result<(), Error> {
+        let outbox = self.db.open_tree(OUTBOX_TABLE)?;
+        for entry in outbox.iter() {
+            let (key, entry) = entry?;
+            let mut entry: OutboxEntry = bincode::deserialize(&entry)?;
+            let post = entry.post.post.get_id();
+            let Some(last) = entry.post.history.last_mut() else { continue };
+            if &last.from != old && &last.to != old {
+                continue;
+            }
+
+            if &last.from == old {
+                last.from = us.node.clone();
+            }
+            if &last.to == old {
+                last.to = new.clone();
+            }
+            last.signature = us.sign(&construct_path_msg(&post, &last.from, &last.to));
+
+            outbox.remove(key)?;
+            let key = outbox_key(entry.recipient(), &post);
+            let _ = outbox.compare_and_swap(key, None as Option<&[u8]>, Some(bincode::serialize(&entry)?))?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn backoff(&self, attempts: u32) -> u64 {
+        let backoff = self.settings.outbox_backoff.saturating_mul(1u64 << attempts.min(32));
+        backoff.min(self.settings.outbox_max_backoff)
+    }
+}
+
+impl Outbox for NodeDB {
+    // Called after the first attempt already failed
+    fn queue_outgoing(&self, post: &OutgoingPost) -> Result<(), Error> {
+        let outbox = self.db.open_tree(OUTBOX_TABLE)?;
+        let now = get_epoch();
+
+        let entry = OutboxEntry {
+            post: post.clone(),
+            queued: now,
+            attempts: 0,
+            next_attempt: now
+        };
+
+        // Already waiting from an earlier attempt, the ttl still counts from back then
+        let key = outbox_key(entry.recipient(), &post.post.get_id());
+        let _ = outbox.compare_and_swap(key, None as Option<&[u8]>, Some(bincode::serialize(&entry)?))?;
+
+        self.unreachable(entry.recipient())?;
+        self.delivery_failed(entry.recipient(), &post.post.get_id())?;
+        Ok(())
+    }
+
+    // Never tried, we were too busy to send it right away. The peer did nothing wrong.
+    fn defer_outgoing(&self, post: &OutgoingPost) -> Result<(), Error> {
+        let outbox = self.db.open_tree(OUTBOX_TABLE)?;
+        let now = get_epoch();
+
+        let entry = OutboxEntry {
+            post: post.clone(),
+            queued: now,
+            attempts: 0,
+            next_attempt: now
+        };
+
+        // One that is already waiting keeps its schedule
+        let key = outbox_key(entry.recipient(), &post.post.get_id());
+        let _ = outbox.compare_and_swap(key, None as Option<&[u8]>, Some(bincode::serialize(&entry)?))?;
+        Ok(())
+    }
+
+    fn due_outgoing(&self) -> Result<Vec<OutboxEntry>, Error> {
+        let outbox = self.db.open_tree(OUTBOX_TABLE)?;
+        let now = get_epoch();
+
+        let mut results = vec![];
+        for entry in outbox.iter() {
+            let (_key, entry) = entry?;
+            let entry: OutboxEntry = bincode::deserialize(&entry)?;
+            if entry.next_attempt <= now {
+                results.push(entry);
+            }
+        }
+        Ok(results)
+    }
+
+    fn delivered(&self, recipient: &Node, post: &PostId) -> Result<(), Error> {
+        let outbox = self.db.open_tree(OUTBOX_TABLE)?;
+        outbox.remove(outbox_key(recipient, post))?;
+        Ok(())
+    }
+
+    fn delivery_failed(&self, recipient: &Node, post: &PostId) -> Result<Retry, Error> {
+        let outbox = self.db.open_tree(OUTBOX_TABLE)?;
+        let key = outbox_key(recipient, post);
+        let now = get_epoch();
+
+        let mut entry: OutboxEntry = match outbox.get(&key)? {
+            Some(entry) => bincode::deserialize(&entry)?,
+            None => return Ok(Retry::GaveUp)
+        };
+
+        entry.attempts += 1;
+        entry.next_attempt = now + self.backoff(entry.attempts - 1);
+
+        if entry.next_attempt > entry.queued + self.settings.outbox_ttl {
+            outbox.remove(&key)?;
+
+            // Gone for a whole ttl, make room for someone who is around (bootstrap nodes stay)
+            let is_bootstrap = self.bootstrap_nodes.iter().flatten().any(|bootstrap| bootstrap == recipient);
+            if !is_bootstrap {
+                match self.untrust(recipient) {
+                    Ok(()) | Err(Error::MinimumPeers) => {},
+                    Err(e) => return Err(e)
+                }
+            }
+            return Ok(Retry::GaveUp);
+        }
+
+        outbox.insert(key, bincode::serialize(&entry)?)?;
+        Ok(Retry::At(entry.next_attempt))
+    }
+
+    // Once per attempt to reach them, not per post that was waiting
+    fn unreachable(&self, peer: &Node) -> Result<(), Error> {
+        self.penalize(peer, self.settings.unreachable_penalty)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_outbox() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{RawPost, IncomingPost, Settings, MAX_CLOCK_SKEW};
+    use crate::db::identity::Identity;
+    use crate::db::handle_post::HandlePost;
+
+    let settings = Settings { outbox_ttl: 100, outbox_backoff: 0, outbox_max_backoff: 0, unreachable_penalty: 10, ..Settings::default() };
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?.with_settings(settings);
+    let us = db.get_identity()?;
+    let peer = Node::new([1u8; 32]);
+    db.trust(&peer)?;
+    db.trust(&Node::new([2u8; 32]))?;
+    db.trust(&Node::new([3u8; 32]))?;
+
+    let raw_post = RawPost::new(us.node.clone(), "".to_string());
+    let signature = us.sign(&raw_post.get_id().raw);
+    let post = IncomingPost::new(&raw_post, &vec![], &signature, &us, MAX_CLOCK_SKEW)?;
+    let out = db.receive(&post)?;
+    let out_post = out.iter().find(|out| out.history.last().unwrap().to == peer).unwrap();
+
+    db.queue_outgoing(out_post)?;
+    assert_eq!(db.get_score(&peer, 1200)?, 1190);
+
+    let due = db.due_outgoing()?;
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].recipient(), &peer);
+    assert_eq!(due[0].attempts, 1);
+
+    // Pushed back without another penalty, that is up to whoever tried to reach them
+    assert!(matches!(db.delivery_failed(&peer, &raw_post.get_id())?, Retry::At(_)));
+    assert_eq!(db.get_score(&peer, 1200)?, 1190);
+
+    db.delivered(&peer, &raw_post.get_id())?;
+    assert_eq!(db.due_outgoing()?.len(), 0);
+
+    // Deferred ones go out with the next retry, at no cost to the peer
+    db.defer_outgoing(out_post)?;
+    assert_eq!(db.due_outgoing()?[0].attempts, 0);
+    assert_eq!(db.get_score(&peer, 1200)?, 1190);
+    db.delivered(&peer, &raw_post.get_id())?;
+
+    // Past the ttl we give up, and stop trusting them. Failing again along the way
+    // doesn't buy them more time.
+    db.queue_outgoing(out_post)?;
+    db.db.open_tree(OUTBOX_TABLE)?.update_and_fetch(outbox_key(&peer, &raw_post.get_id()), |entry| {
+        let mut entry: OutboxEntry = bincode::deserialize(entry?).ok()?;
+        entry.queued -= 200;
+        bincode::serialize(&entry).ok()
+    })?;
+    db.queue_outgoing(out_post)?;
+    assert_eq!(db.due_outgoing()?.len(), 0);
+    assert!(!db.is_trusted(&peer)?);
+
+    Ok(())
+}
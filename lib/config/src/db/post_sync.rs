@@ -0,0 +1,139 @@
+use serde::{Serialize, Deserialize};
+
+use crate::db::{NodeDB, Node, OutgoingPost, IncomingPost, PostId, Error};
+use crate::db::identity::Identity;
+use crate::db::trust::Trust;
+use crate::db::handle_post::HandlePost;
+
+/*
+    Pull based catch up for nodes that were offline.
+
+    A trusted peer asks for every post we received after a cursor, and gets
+    them back in pages. Each post gets a fresh history hop from us to them,
+    exactly like it would if we had pushed it while they were online.
+*/
+
+pub trait PostSync {
+    fn posts_after(&self, requester: &Node, after: &Option<SyncCursor>, limit: usize) -> Result<SyncPage, Error>;
+    fn get_sync_cursor(&self, peer: &Node) -> Result<Option<SyncCursor>, Error>;
+    fn set_sync_cursor(&self, peer: &Node, cursor: &SyncCursor) -> Result<(), Error>;
+}
+
+pub const MAX_SYNC_PAGE:usize = 64;
+
+// How far we got syncing with each peer, so we only ask for what's new
+pub(crate) const SYNC_TABLE:&str = "SYNC_TABLE";
+
+// Unlike feeds, sync goes by when we received a post, so one that reached us late
+// still goes out to peers that synced past the time it was written
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SyncCursor {
+    pub received: u64,
+    pub post: PostId // Breaks ties between posts received in the same second
+}
+
+impl SyncCursor {
+    pub fn of(post: &IncomingPost) -> Self {
+        SyncCursor { received: post.received, post: post.get_id() }
+    }
+
+    pub(crate) fn key(&self) -> Vec<u8> {
+        [&self.received.to_be_bytes()[..], &self.post.raw[..]].concat()
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SyncPage {
+    pub posts: Vec<OutgoingPost>,
+    pub cursor: Option<SyncCursor>, // Position of the last post in this page
+    pub more: bool
+}
+
+impl PostSync for NodeDB {
+    fn posts_after(&self, requester: &Node, after: &Option<SyncCursor>, limit: usize) -> Result<SyncPage, Error> {
+        // Bootstrap nodes share with everyone, like they do for pushed posts
+        if self.bootstrap_nodes.is_some() && !self.is_trusted(requester)? {
+            return Err(Error::UntrustedPeer);
+        }
+
+        let us = self.get_identity()?;
+
+        // One extra, to know if there is another page
+        let limit = limit.min(MAX_SYNC_PAGE);
+        let mut candidates = self.posts_received(after, limit + 1)?;
+        let more = candidates.len() > limit;
+        candidates.truncate(limit);
+
+        let cursor = candidates.last().map(SyncCursor::of);
+
+        let mut result = vec![];
+        for post in candidates {
+            // No point sending them their own posts, or posts they gave us
+            let from_requester = post.history.last().is_some_and(|path| &path.from == requester);
+            if &post.post.author == requester || from_requester {
+                continue;
+            }
+
+            self.register_seen(requester, &post.get_id())?;
+            result.push(OutgoingPost::from_incoming(&post, &us, requester));
+        }
+
+        Ok(SyncPage { posts: result, cursor, more })
+    }
+
+    fn get_sync_cursor(&self, peer: &Node) -> Result<Option<SyncCursor>, Error> {
+        let cursors = self.db.open_tree(SYNC_TABLE)?;
+        match cursors.get(peer.public_key)? {
+            Some(cursor) => Ok(Some(bincode::deserialize(&cursor)?)),
+            None => Ok(None)
+        }
+    }
+
+    fn set_sync_cursor(&self, peer: &Node, cursor: &SyncCursor) -> Result<(), Error> {
+        let cursors = self.db.open_tree(SYNC_TABLE)?;
+        cursors.insert(peer.public_key, bincode::serialize(cursor)?)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_posts_after() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{IncomingPost, MAX_CLOCK_SKEW};
+    use crate::db::feed::Feed;
+
+    let db1 = NodeDB::new(tempfile::TempDir::new()?, Some(vec![]))?;
+    let node1 = db1.get_identity()?;
+
+    let db2 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node2 = db2.get_identity()?;
+
+    // Untrusted nodes get nothing
+    assert!(db1.posts_after(&node2.node, &None, 10).is_err());
+    db1.trust(&node2.node)?;
+
+    for idx in 0..3 {
+        let raw_post = db1.new_post(format!("post {}", idx))?;
+        let signature = node1.sign(&raw_post.get_id().raw);
+        let post = IncomingPost::new(&raw_post, &vec![], &signature, &node1, MAX_CLOCK_SKEW)?;
+        db1.receive(&post)?;
+    }
+
+    let page = db1.posts_after(&node2.node, &None, 2)?;
+    assert_eq!(page.posts.len(), 2);
+    assert!(page.more);
+
+    // Every synced post has to be accepted by the requester
+    for out_post in &page.posts {
+        IncomingPost::new(&out_post.post, &out_post.history, &out_post.signature, &node2, MAX_CLOCK_SKEW)?;
+    }
+
+    let page = db1.posts_after(&node2.node, &page.cursor, 2)?;
+    assert_eq!(page.posts.len(), 1);
+    assert!(!page.more);
+
+    let page = db1.posts_after(&node2.node, &page.cursor, 2)?;
+    assert_eq!(page.posts.len(), 0);
+    assert_eq!(page.cursor, None);
+
+    Ok(())
+}
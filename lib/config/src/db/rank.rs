@@ -0,0 +1,146 @@
+use crate::db::{NodeDB, IncomingPost, Error};
+use crate::db::identity::Identity;
+use crate::db::score::Score;
+use crate::db::trust::Trust;
+
+/*
+    Your feed, your algorithm. A ranker gives every post a number (higher shows up first),
+    or None to leave it out of the feed entirely. Search ranks the newest RANK_WINDOW
+    posts as a whole, so the ranker decides what makes the first page, not just its order.
+*/
+
+pub trait Ranker: Send + Sync {
+    fn rank(&self, db: &NodeDB, post: &IncomingPost, now: u64) -> Result<Option<f64>, Error>;
+}
+
+// Newest first
+pub struct Chronological;
+
+// Reddit's hot, with the author's score standing in for votes
+pub struct Hot;
+
+// Authors we rate highly first, slowly fading with age
+pub struct ScoreWeighted;
+
+// Only posts written by us or by nodes we trust, ranked by the inner ranker
+pub struct TrustedOnly<R: Ranker>(pub R);
+
+const HOT_DECAY:f64 = 45000.0; // seconds for the order of magnitude of score to matter as much as age
+const SCORE_HALF_LIFE:f64 = 60.0 * 60.0;
+
+impl Ranker for Chronological {
+    fn rank(&self, _db: &NodeDB, post: &IncomingPost, _now: u64) -> Result<Option<f64>, Error> {
+        Ok(Some(post.created() as f64))
+    }
+}
+
+impl Ranker for Hot {
+    fn rank(&self, db: &NodeDB, post: &IncomingPost, _now: u64) -> Result<Option<f64>, Error> {
+        let score = db.get_score(&post.post.author, 1200)?.max(1) as f64;
+        Ok(Some(score.log10() + post.created() as f64 / HOT_DECAY))
+    }
+}
+
+impl Ranker for ScoreWeighted {
+    fn rank(&self, db: &NodeDB, post: &IncomingPost, now: u64) -> Result<Option<f64>, Error> {
+        let score = db.get_score(&post.post.author, 1200)? as f64;
+        let age = now.saturating_sub(post.created()) as f64;
+        Ok(Some(score / (1.0 + age / SCORE_HALF_LIFE)))
+    }
+}
+
+impl<R: Ranker> Ranker for TrustedOnly<R> {
+    fn rank(&self, db: &NodeDB, post: &IncomingPost, now: u64) -> Result<Option<f64>, Error> {
+        let author = &post.post.author;
+        if author != &db.get_identity()?.node && !db.is_trusted(author)? {
+            return Ok(None);
+        }
+        self.0.rank(db, post, now)
+    }
+}
+
+pub const RANKERS: &[&str] = &["chronological", "hot", "score", "trusted"];
+
+// For picking one from the command line
+pub fn by_name(name: &str) -> Option<Box<dyn Ranker>> {
+    match name {
+        "chronological" => Some(Box::new(Chronological)),
+        "hot" => Some(Box::new(Hot)),
+        "score" => Some(Box::new(ScoreWeighted)),
+        "trusted" => Some(Box::new(TrustedOnly(Chronological))),
+        _ => None
+    }
+}
+
+#[test]
+fn test_rankers() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{RawPost, MAX_CLOCK_SKEW};
+    use crate::db::search::Search;
+    use crate::db::handle_post::HandlePost;
+
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let us = db.get_identity()?;
+    let stranger = db.generate_identity()?;
+
+    for (author, content) in [(&us, "ours"), (&stranger, "theirs")] {
+        let raw_post = RawPost::new(author.node.clone(), content.to_string());
+        let signature = author.sign(&raw_post.get_id().raw);
+        let mut post = IncomingPost::new(&raw_post, &vec![], &signature, &us, MAX_CLOCK_SKEW)?;
+        post.post.created_at -= if content == "ours" { 10 } else { 0 };
+        db.receive(&post)?;
+    }
+
+    // Received this very second, which used to divide by zero
+    for name in RANKERS {
+        let page = db.search_posts(by_name(name).unwrap().as_ref(), &None, 10)?;
+        assert!(page.posts.iter().all(|(_post, rank)| rank.is_finite()));
+    }
+
+    let page = db.search_posts(&Chronological, &None, 10)?;
+    let contents:Vec<&str> = page.posts.iter().map(|(post, _rank)| post.post.content.as_str()).collect();
+    assert_eq!(contents, vec!["theirs", "ours"]);
+
+    let page = db.search_posts(&TrustedOnly(Chronological), &None, 10)?;
+    assert_eq!(page.posts.len(), 1);
+    assert_eq!(page.posts[0].0.post.content, "ours");
+
+    Ok(())
+}
+
+#[test]
+fn test_rank_window() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{RawPost, MAX_CLOCK_SKEW};
+    use crate::db::search::Search;
+    use crate::db::handle_post::HandlePost;
+
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let us = db.get_identity()?;
+
+    // Someone we rate highly posted a while ago, and three others since
+    for idx in 0..4 {
+        let author = db.generate_identity()?;
+        let mut raw_post = RawPost::new(author.node.clone(), format!("post {}", idx));
+        raw_post.created_at -= 60 * (4 - idx);
+        if idx == 0 {
+            db.set_score(&author.node, 5000)?;
+        }
+        let signature = author.sign(&raw_post.get_id().raw);
+        db.receive(&IncomingPost::new(&raw_post, &vec![], &signature, &us, MAX_CLOCK_SKEW)?)?;
+    }
+
+    let contents = |page: &crate::db::search::SearchPage| -> Vec<String> {
+        page.posts.iter().map(|(post, _rank)| post.post.content.clone()).collect()
+    };
+
+    // Not just the newest page reordered
+    let page = db.search_posts(&ScoreWeighted, &None, 2)?;
+    assert_eq!(contents(&page)[0], "post 0");
+
+    // And the next page picks up after the last rank
+    let page = db.search_posts(&Chronological, &None, 2)?;
+    assert_eq!(contents(&page), vec!["post 3", "post 2"]);
+    let page = db.search_posts(&Chronological, &page.cursor, 10)?;
+    assert_eq!(contents(&page), vec!["post 1", "post 0"]);
+
+    Ok(())
+}
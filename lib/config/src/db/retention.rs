@@ -0,0 +1,286 @@
+use serde::{Serialize, Deserialize};
+
+use crate::db::{NodeDB, PostId, IncomingPost, Error};
+use crate::db::handle_post::{POSTS_TABLE, POST_TIME_INDEX, SEEN_TABLE};
+use crate::db::migrate::META_TABLE;
+use crate::misc::get_epoch;
+
+/*
+    Keeps the database from growing forever.
+
+    compact() drops the oldest posts until we are within settings.retention_*
+    again, skipping pinned posts (our own and the ones we promoted). Every
+    dropped post leaves a tombstone, so a peer replaying it later gets turned
+    away even though its seen markers are gone. Once a signed post is older than
+    settings.retention_age, receive() rejects it by itself and the tombstone goes too.
+    With a retention_age receive() rejects version 0 posts outright, they have no
+    signed time to tell a deleted one from a new one. Without it tombstones are kept
+    for TOMBSTONE_AGE after the drop, a replay after that gets in and is dropped again.
+    Nothing is limited unless asked for, see Settings.
+*/
+
+pub trait Retention {
+    fn pin(&self, post: &PostId) -> Result<(), Error>;
+    fn unpin(&self, post: &PostId) -> Result<(), Error>;
+    fn is_pinned(&self, post: &PostId) -> Result<bool, Error>;
+    fn is_tombstoned(&self, post: &PostId) -> Result<bool, Error>;
+    fn compact(&self) -> Result<Compaction, Error>;
+}
+
+// post id -> nothing
+const PINNED_TABLE:&str = "PINNED_TABLE";
+// post id -> Tombstone
+pub(crate) const TOMBSTONE_TABLE:&str = "TOMBSTONE_TABLE";
+// Where the last compaction stopped going through SEEN_TABLE, in META_TABLE
+const SEEN_CURSOR:&[u8] = b"seen_cursor";
+
+// How long a tombstone outlives its post when there is no signed time to go by
+pub const TOMBSTONE_AGE:u64 = 60 * 60 * 24 * 30;
+// Seen markers looked at per compaction, the rest wait for the next one
+const SEEN_BATCH:usize = 10_000;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub(crate) struct Tombstone {
+    pub created_at: Option<u64>, // When the author wrote it, None for version 0 posts
+    pub dropped_at: u64
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct Compaction {
+    pub posts: usize,
+    pub seen: usize,
+    pub tombstones: usize,
+    pub feed: usize
+}
+
+impl NodeDB {
+    // How many feed entries went with it
+    fn drop_post(&self, post_id: &[u8]) -> Result<usize, Error> {
+        let posts = self.db.open_tree(POSTS_TABLE)?;
+        let mut created_at:Option<u64> = None;
+        let mut pruned = 0;
+        if let Some(post) = posts.remove(post_id)? {
+            let post: IncomingPost = bincode::deserialize(&post)?;
+            self.unindex_post(&post)?;
+            if post.post.version >= 1 {
+                created_at = Some(post.post.created_at);
+            }
+            if post.post.seq >= 1 {
+                pruned = self.prune_feed(&post.post.author)?;
+            }
+        }
+        let tombstone = Tombstone { created_at, dropped_at: get_epoch() };
+        self.db.open_tree(TOMBSTONE_TABLE)?.insert(post_id, bincode::serialize(&tombstone)?)?;
+        Ok(pruned)
+    }
+
+    // Seen markers only matter for posts we still have (the tombstone covers the rest),
+    // and only while receive() could still take the post
+    fn stale_marker(&self, post_id: &[u8], seen_at: u64, cutoff: Option<u64>) -> Result<bool, Error> {
+        if self.db.open_tree(TOMBSTONE_TABLE)?.contains_key(post_id)? {
+            return Ok(true);
+        }
+        let Some(cutoff) = cutoff else {
+            return Ok(false);
+        };
+        match self.db.open_tree(POSTS_TABLE)?.get(post_id)? {
+            Some(post) => {
+                let post: IncomingPost = bincode::deserialize(&post)?;
+                Ok(post.post.version == 0 || post.post.created_at < cutoff)
+            },
+            None => Ok(seen_at < cutoff)
+        }
+    }
+}
+
+impl Retention for NodeDB {
+    fn pin(&self, post: &PostId) -> Result<(), Error> {
+        self.db.open_tree(PINNED_TABLE)?.insert(post.raw, &[])?;
+        Ok(())
+    }
+
+    fn unpin(&self, post: &PostId) -> Result<(), Error> {
+        self.db.open_tree(PINNED_TABLE)?.remove(post.raw)?;
+        Ok(())
+    }
+
+    fn is_pinned(&self, post: &PostId) -> Result<bool, Error> {
+        Ok(self.db.open_tree(PINNED_TABLE)?.contains_key(post.raw)?)
+    }
+
+    fn is_tombstoned(&self, post: &PostId) -> Result<bool, Error> {
+        Ok(self.db.open_tree(TOMBSTONE_TABLE)?.contains_key(post.raw)?)
+    }
+
+    fn compact(&self) -> Result<Compaction, Error> {
+        let posts = self.db.open_tree(POSTS_TABLE)?;
+        let index = self.db.open_tree(POST_TIME_INDEX)?;
+        let pinned = self.db.open_tree(PINNED_TABLE)?;
+        let tombstones = self.db.open_tree(TOMBSTONE_TABLE)?;
+        let settings = &self.settings;
+        let mut result = Compaction::default();
+
+        let cutoff = settings.retention_age.map(|age| get_epoch().saturating_sub(age));
+        let mut count = posts.len();
+        let mut bytes:u64 = 0;
+        for post in posts.iter() {
+            bytes += post?.1.len() as u64;
+        }
+
+        // Oldest first, until we are within every limit
+        for key in index.iter() {
+            let (key, _) = key?;
+            let created = u64::from_be_bytes(key[..8].try_into().unwrap());
+            let post_id = &key[8..];
+
+            let too_old = cutoff.is_some_and(|cutoff| created < cutoff);
+            let too_many = settings.retention_count.is_some_and(|max| count > max);
+            let too_big = settings.retention_bytes.is_some_and(|max| bytes > max);
+            if !too_old && !too_many && !too_big {
+                break;
+            }
+
+            if pinned.contains_key(post_id)? {
+                continue;
+            }
+
+            if let Some(post) = posts.get(post_id)? {
+                bytes -= post.len() as u64;
+            }
+            result.feed += self.drop_post(post_id)?;
+            count -= 1;
+            result.posts += 1;
+        }
+
+        // A batch at a time, picking up where the last compaction stopped
+        let seen = self.db.open_tree(SEEN_TABLE)?;
+        let meta = self.db.open_tree(META_TABLE)?;
+        let cursor = meta.get(SEEN_CURSOR)?.map(|cursor| cursor.to_vec()).unwrap_or_default();
+        let mut next = None;
+        for (idx, marker) in seen.range(cursor..).enumerate() {
+            let (key, seen_at) = marker?;
+            if idx == SEEN_BATCH {
+                next = Some(key);
+                break;
+            }
+            if self.stale_marker(&key[32..], bincode::deserialize(&seen_at)?, cutoff)? {
+                seen.remove(&key)?;
+                result.seen += 1;
+            }
+        }
+        match next {
+            Some(key) => meta.insert(SEEN_CURSOR, key)?,
+            None => meta.remove(SEEN_CURSOR)?
+        };
+
+        let forget_before = get_epoch().saturating_sub(TOMBSTONE_AGE);
+        for tombstone in tombstones.iter() {
+            let (post_id, tombstone) = tombstone?;
+            let tombstone: Tombstone = bincode::deserialize(&tombstone)?;
+            let expired = match (cutoff, tombstone.created_at) {
+                // receive() turns these away by itself from now on
+                (Some(cutoff), Some(created_at)) => created_at < cutoff,
+                (Some(_), None) => true,
+                (None, _) => tombstone.dropped_at < forget_before
+            };
+            if expired {
+                tombstones.remove(&post_id)?;
+                result.tombstones += 1;
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+#[test]
+fn test_compact() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{RawPost, Settings, MAX_CLOCK_SKEW};
+    use crate::db::identity::Identity;
+    use crate::db::handle_post::{HandlePost, Direction};
+    use crate::db::search::Search;
+    use crate::db::rank::Chronological;
+
+    let settings = Settings { retention_count: Some(1), ..Settings::default() };
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?.with_settings(settings);
+    let us = db.get_identity()?;
+    let author = db.generate_identity()?;
+
+    let mut theirs:Vec<IncomingPost> = vec![];
+    for content in ["old news", "older news"] {
+        let mut raw_post = RawPost::new(author.node.clone(), content.to_string());
+        if let Some(prev) = theirs.last() {
+            raw_post.seq = prev.post.seq + 1;
+            raw_post.prev = Some(prev.get_id());
+        }
+        let signature = author.sign(&raw_post.get_id().raw);
+        let post = IncomingPost::new(&raw_post, &vec![], &signature, &us, MAX_CLOCK_SKEW)?;
+        db.receive(&post)?;
+        theirs.push(post);
+    }
+
+    let raw_post = RawPost::new(us.node.clone(), "our news".to_string());
+    let signature = us.sign(&raw_post.get_id().raw);
+    let ours = IncomingPost::new(&raw_post, &vec![], &signature, &us, MAX_CLOCK_SKEW)?;
+    db.receive(&ours)?;
+
+    // Our own post is pinned, so both of theirs have to go to get down to one
+    let compaction = db.compact()?;
+    assert_eq!(compaction.posts, 2);
+    assert!(compaction.seen >= 2);
+    assert_eq!(compaction.feed, 1); // Their head stays
+
+    assert_eq!(db.posts_by_time(&None, Direction::Forward, 10)?, vec![ours]);
+    assert_eq!(db.search_text("news", &Chronological, &None, 10)?.posts.len(), 1);
+    assert!(matches!(db.resolve(&theirs[0].get_id()), Err(Error::PostNotFound)));
+    assert!(!db.has_seen(&us.node, &theirs[0].get_id())?);
+
+    // Replays are still turned away
+    assert!(matches!(db.receive(&theirs[0]), Err(Error::ExpiredPost)));
+
+    assert_eq!(db.compact()?, Compaction::default());
+
+    Ok(())
+}
+
+#[test]
+fn test_compact_age() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{RawPost, MAX_CLOCK_SKEW};
+    use crate::db::identity::Identity;
+    use crate::db::handle_post::HandlePost;
+
+    // Nothing goes unless asked for
+    let mut db = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let us = db.get_identity()?;
+    let author = db.generate_identity()?;
+
+    let mut raw_post = RawPost::new(author.node.clone(), "promoted".to_string());
+    raw_post.created_at -= 60 * 60;
+    let signature = author.sign(&raw_post.get_id().raw);
+    let post = IncomingPost::new(&raw_post, &vec![], &signature, &us, MAX_CLOCK_SKEW)?;
+    db.receive(&post)?;
+    db.pin(&post.get_id())?;
+
+    // A version 0 post dropped long ago, and one dropped just now
+    let tombstones = db.db.open_tree(TOMBSTONE_TABLE)?;
+    let old = Tombstone { created_at: None, dropped_at: get_epoch() - TOMBSTONE_AGE - 1 };
+    let new = Tombstone { created_at: None, dropped_at: get_epoch() };
+    tombstones.insert([1u8; 32], bincode::serialize(&old)?)?;
+    tombstones.insert([2u8; 32], bincode::serialize(&new)?)?;
+
+    let compaction = db.compact()?;
+    assert_eq!((compaction.posts, compaction.seen, compaction.tombstones), (0, 0, 1));
+    assert!(db.has_seen(&us.node, &post.get_id())?);
+    assert!(db.is_tombstoned(&PostId { raw: [2u8; 32] })?);
+
+    // Pinned posts stay, but nothing needs their seen markers once receive() turns them away
+    db.settings.retention_age = Some(60);
+    let compaction = db.compact()?;
+    assert_eq!((compaction.posts, compaction.seen), (0, 1));
+    assert_eq!(db.resolve(&post.get_id())?, post);
+    assert!(!db.has_seen(&us.node, &post.get_id())?);
+    assert!(matches!(db.receive(&post), Err(Error::ExpiredPost)));
+    assert!(!db.is_tombstoned(&PostId { raw: [2u8; 32] })?); // Version 0, refused from now on
+
+    Ok(())
+}
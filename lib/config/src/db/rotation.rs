@@ -0,0 +1,428 @@
+use serde::{Serialize, Deserialize};
+
+use std::cmp::Ordering;
+
+use crate::db::{NodeDB, Node, Us, Error, RawPost, PostId};
+use crate::db::identity::Identity;
+use crate::db::trust::{Trust, TRUST_TABLE, CANDIDATE_REQUEST_TABLE};
+use crate::db::score::SCORES_TABLE;
+use crate::db::trust_request::PENDING_TABLE;
+use crate::db::post_sync::SYNC_TABLE;
+use crate::db::feed::{Feed, FeedEntry};
+use crate::misc::{get_epoch, sha256};
+
+/*
+    Moving to a new key without starting over.
+
+    The old key certifies the new one, and the new key signs too so nobody
+    can point a rotation at a key they don't hold. Every node that hears of
+    it moves everything it keeps about the old key over to the new one, passes
+    it on to its peers, and from then on only takes the posts the old key
+    wrote before: the rotation names the head of the old feed, so a post has
+    to fit below it, whatever time it claims to be from.
+    The first rotation we see for a key wins, a stolen key can race its owner.
+    Our own rotations wait in ANNOUNCE_TABLE until each peer took them.
+*/
+
+pub trait KeyRotations {
+    fn rotate_identity(&self, passphrase: Option<&str>) -> Result<KeyRotation, Error>;
+    fn receive_rotation(&self, rotation: &KeyRotation) -> Result<bool, Error>;
+    fn rotated_to(&self, old: &Node) -> Result<Option<KeyRotation>, Error>;
+    fn due_announcements(&self) -> Result<Vec<(Node, KeyRotation)>, Error>;
+    fn announcements_for(&self, peer: &Node) -> Result<Vec<KeyRotation>, Error>;
+    fn rotation_announced(&self, peer: &Node, rotation: &KeyRotation) -> Result<(), Error>;
+    fn announcement_failed(&self, peer: &Node, rotation: &KeyRotation) -> Result<Option<u64>, Error>;
+}
+
+// old public key -> KeyRotation
+const ROTATION_TABLE:&str = "ROTATION_TABLE";
+// peer public key + old public key -> Announcement
+const ANNOUNCE_TABLE:&str = "ANNOUNCE_TABLE";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct KeyRotation {
+    pub old: Node,
+    pub new: Node,
+    pub rotated_at: u64,
+    pub last_seq: u64, // Head of the old key's feed, 0 if it never posted
+    pub last_post: Option<PostId>, // The post at last_seq
+    pub old_signature: String, // sign(id, old private key)
+    pub new_signature: String  // sign(id, new private key)
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+struct Announcement {
+    rotation: KeyRotation,
+    attempts: u32,
+    next_attempt: u64
+}
+
+fn announce_key(peer: &Node, old: &Node) -> Vec<u8> {
+    [peer.public_key, old.public_key].concat()
+}
+
+impl KeyRotation {
+    pub fn id(&self) -> [u8; 32] {
+        sha256(bincode::serialize(&(&self.old, &self.new, self.rotated_at, self.last_seq, &self.last_post)).unwrap())
+    }
+
+    fn new(old: &Us, new: &Us, head: Option<FeedEntry>) -> Self {
+        let mut rotation = KeyRotation {
+            old: old.node.clone(),
+            new: new.node.clone(),
+            rotated_at: get_epoch(),
+            last_seq: head.as_ref().map_or(0, |head| head.seq),
+            last_post: head.map(|head| head.post),
+            old_signature: String::new(),
+            new_signature: String::new()
+        };
+        rotation.old_signature = old.sign(&rotation.id());
+        rotation.new_signature = new.sign(&rotation.id());
+        rotation
+    }
+
+    // Whether the old key wrote it before rotating. Anything above the head is new,
+    // and the feed links hold whatever comes below it to the head we signed.
+    pub fn precedes(&self, post: &RawPost) -> bool {
+        match post.seq.cmp(&self.last_seq) {
+            Ordering::Less => post.version >= 2 && post.seq >= 1,
+            Ordering::Equal => self.last_post.as_ref() == Some(&post.get_id()),
+            Ordering::Greater => false
+        }
+    }
+}
+
+impl NodeDB {
+    // Whatever old had in `table` goes to new, unless new already has its own
+    fn move_entry(&self, table: &str, old: &Node, new: &Node) -> Result<(), Error> {
+        let table = self.db.open_tree(table)?;
+        if let Some(value) = table.remove(old.public_key)? {
+            let _ = table.compare_and_swap(new.public_key, None as Option<&[u8]>, Some(value))?;
+        }
+        Ok(())
+    }
+
+    // The better of the two, someone rotating shouldn't cost or gain them anything
+    fn move_score(&self, old: &Node, new: &Node) -> Result<(), Error> {
+        let scores = self.db.open_tree(SCORES_TABLE)?;
+        let Some(score) = scores.remove(old.public_key)? else { return Ok(()) };
+        let score: usize = bincode::deserialize(&score)?;
+        let score = match scores.get(new.public_key)? {
+            Some(other) => score.max(bincode::deserialize(&other)?),
+            None => score
+        };
+        scores.insert(new.public_key, bincode::serialize(&score)?)?;
+        Ok(())
+    }
+
+    fn queue_announcement(&self, peer: &Node, rotation: &KeyRotation) -> Result<(), Error> {
+        // The first attempt is on its way already
+        let entry = Announcement { rotation: rotation.clone(), attempts: 0, next_attempt: get_epoch() + self.backoff(0) };
+        self.db.open_tree(ANNOUNCE_TABLE)?.insert(announce_key(peer, &rotation.old), bincode::serialize(&entry)?)?;
+        Ok(())
+    }
+}
+
+impl KeyRotations for NodeDB {
+    // The new key is locked with `passphrase`, peers still have to hear about it
+    fn rotate_identity(&self, passphrase: Option<&str>) -> Result<KeyRotation, Error> {
+        // Where our old feed ends goes into the rotation, so we have to know it
+        if self.recovering_feed()? {
+            return Err(Error::RecoveringFeed);
+        }
+
+        let old = self.get_identity()?;
+        let new = self.generate_identity()?;
+        let rotation = KeyRotation::new(&old, &new, self.feed_head(&old.node)?);
+
+        // We are the new key from here on, even if something below fails
+        self.store_identity(&new, passphrase)?;
+        self.remember(&new);
+        self.reseal_messages(&old, &new)?;
+        self.receive_rotation(&rotation)?;
+
+        let mut peers:Vec<Node> = self.get_trusted()?.into_iter().map(|(node, _score)| node).collect();
+        peers.extend(self.bootstrap_nodes.iter().flatten().cloned());
+        for peer in peers {
+            if peer != rotation.old && peer != rotation.new {
+                self.queue_announcement(&peer, &rotation)?;
+            }
+        }
+        Ok(rotation)
+    }
+
+    // false if we already knew, so it does not go around in circles
+    fn receive_rotation(&self, rotation: &KeyRotation) -> Result<bool, Error> {
+        if rotation.old == rotation.new {
+            return Err(Error::InvalidRotation);
+        }
+        rotation.old.verify(&rotation.id(), &rotation.old_signature)?;
+        rotation.new.verify(&rotation.id(), &rotation.new_signature)?;
+
+        match self.rotated_to(&rotation.old)? {
+            Some(known) if &known == rotation => return Ok(false),
+            Some(_) => return Err(Error::InvalidRotation),
+            None => {}
+        }
+
+        let rotations = self.db.open_tree(ROTATION_TABLE)?;
+        rotations.insert(rotation.old.public_key, bincode::serialize(rotation)?)?;
+
+        // Same standing as before, just under the new key
+        let (old, new) = (&rotation.old, &rotation.new);
+        for table in [TRUST_TABLE, PENDING_TABLE, CANDIDATE_REQUEST_TABLE, SYNC_TABLE] {
+            self.move_entry(table, old, new)?;
+        }
+        self.move_score(old, new)?;
+        self.move_candidates(old, new)?;
+        self.move_seen(old, new)?;
+
+        let us = self.get_identity()?;
+        self.readdress_outbox(&us, old, new)?;
+        self.move_messages(&us, old, new)?;
+        Ok(true)
+    }
+
+    fn rotated_to(&self, old: &Node) -> Result<Option<KeyRotation>, Error> {
+        let rotations = self.db.open_tree(ROTATION_TABLE)?;
+        match rotations.get(old.public_key)? {
+            Some(rotation) => Ok(Some(bincode::deserialize(&rotation)?)),
+            None => Ok(None)
+        }
+    }
+
+    fn due_announcements(&self) -> Result<Vec<(Node, KeyRotation)>, Error> {
+        let announcements = self.db.open_tree(ANNOUNCE_TABLE)?;
+        let now = get_epoch();
+
+        let mut results = vec![];
+        for entry in announcements.iter() {
+            let (key, entry) = entry?;
+            let entry: Announcement = bincode::deserialize(&entry)?;
+            if entry.next_attempt <= now {
+                let peer = Node::new(key[..32].try_into().map_err(|_| Error::InvalidKey)?);
+                results.push((peer, entry.rotation));
+            }
+        }
+        Ok(results)
+    }
+
+    // Due or not, for when we reach them anyway
+    fn announcements_for(&self, peer: &Node) -> Result<Vec<KeyRotation>, Error> {
+        let announcements = self.db.open_tree(ANNOUNCE_TABLE)?;
+
+        let mut results = vec![];
+        for entry in announcements.scan_prefix(peer.public_key) {
+            let (_key, entry) = entry?;
+            let entry: Announcement = bincode::deserialize(&entry)?;
+            results.push(entry.rotation);
+        }
+        Ok(results)
+    }
+
+    fn rotation_announced(&self, peer: &Node, rotation: &KeyRotation) -> Result<(), Error> {
+        self.db.open_tree(ANNOUNCE_TABLE)?.remove(announce_key(peer, &rotation.old))?;
+        Ok(())
+    }
+
+    // None if it wasn't waiting for them. There is no giving up, the peer still knows us by the old key.
+    fn announcement_failed(&self, peer: &Node, rotation: &KeyRotation) -> Result<Option<u64>, Error> {
+        let announcements = self.db.open_tree(ANNOUNCE_TABLE)?;
+        let key = announce_key(peer, &rotation.old);
+        let mut entry: Announcement = match announcements.get(&key)? {
+            Some(entry) => bincode::deserialize(&entry)?,
+            None => return Ok(None)
+        };
+
+        entry.attempts += 1;
+        entry.next_attempt = get_epoch() + self.backoff(entry.attempts - 1);
+        announcements.insert(key, bincode::serialize(&entry)?)?;
+        Ok(Some(entry.next_attempt))
+    }
+}
+
+#[test]
+fn test_rotation() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{IncomingPost, MAX_CLOCK_SKEW};
+    use crate::db::score::Score;
+    use crate::db::handle_post::HandlePost;
+
+    let db1 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let old = db1.get_identity()?;
+
+    let db2 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node2 = db2.get_identity()?;
+    db2.trust(&old.node)?;
+    db2.set_score(&old.node, 1500)?;
+
+    // Written before the rotation, still fine afterwards
+    let before = db1.new_post("before".to_string())?;
+    let signature = old.sign(&before.get_id().raw);
+    db1.receive(&IncomingPost::new(&before, &vec![], &signature, &old, MAX_CLOCK_SKEW)?)?;
+    let before = IncomingPost::new(&before, &vec![], &signature, &node2, MAX_CLOCK_SKEW)?;
+
+    let rotation = db1.rotate_identity(None)?;
+    assert_eq!(db1.get_identity()?.node, rotation.new);
+    assert_eq!((rotation.last_seq, rotation.last_post.clone()), (1, Some(before.get_id())));
+
+    // Nobody can forge one, or point the key somewhere else afterwards
+    let mut forged = rotation.clone();
+    forged.new = node2.node.clone();
+    assert!(db2.receive_rotation(&forged).is_err());
+    let mut forged = rotation.clone();
+    forged.last_seq += 1;
+    assert!(db2.receive_rotation(&forged).is_err());
+
+    assert!(db2.receive_rotation(&rotation)?);
+    assert!(!db2.receive_rotation(&rotation)?);
+    assert!(matches!(db2.receive_rotation(&KeyRotation::new(&old, &node2, None)), Err(Error::InvalidRotation)));
+
+    assert!(!db2.is_trusted(&old.node)?);
+    assert!(db2.is_trusted(&rotation.new)?);
+    assert_eq!(db2.get_score(&rotation.new, 1200)?, 1500);
+
+    // Anything the old key writes from now on is refused
+    db2.receive(&before)?;
+    let after = RawPost { seq: 2, prev: Some(before.get_id()), ..RawPost::new(old.node.clone(), "after".to_string()) };
+    let after = IncomingPost::new(&after, &vec![], &old.sign(&after.get_id().raw), &node2, MAX_CLOCK_SKEW)?;
+    assert!(matches!(db2.receive(&after), Err(Error::RotatedKey)));
+
+    Ok(())
+}
+
+#[test]
+fn test_rotation_backdated() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{IncomingPost, MAX_CLOCK_SKEW};
+    use crate::db::handle_post::HandlePost;
+
+    let db1 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let old = db1.get_identity()?;
+    let db2 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node2 = db2.get_identity()?;
+
+    let first = db1.new_post("first".to_string())?;
+    let signature = old.sign(&first.get_id().raw);
+    db1.receive(&IncomingPost::new(&first, &vec![], &signature, &old, MAX_CLOCK_SKEW)?)?;
+    let rotation = db1.rotate_identity(None)?;
+    db2.receive_rotation(&rotation)?;
+
+    // Whoever stole the old key claims to have written these an hour before the rotation
+    let mut backdated = RawPost { seq: 2, prev: Some(first.get_id()), ..RawPost::new(old.node.clone(), "backdated".to_string()) };
+    backdated.created_at = rotation.rotated_at - 60 * 60;
+    let backdated = IncomingPost::new(&backdated, &vec![], &old.sign(&backdated.get_id().raw), &node2, MAX_CLOCK_SKEW)?;
+    assert!(matches!(db2.receive(&backdated), Err(Error::RotatedKey)));
+
+    let mut replaced = RawPost::new(old.node.clone(), "not the first".to_string());
+    replaced.created_at = first.created_at;
+    let replaced = IncomingPost::new(&replaced, &vec![], &old.sign(&replaced.get_id().raw), &node2, MAX_CLOCK_SKEW)?;
+    assert!(matches!(db2.receive(&replaced), Err(Error::RotatedKey)));
+
+    // The real one still goes through
+    db2.receive(&IncomingPost::new(&first, &vec![], &signature, &node2, MAX_CLOCK_SKEW)?)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_rotation_moves_everything() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{IncomingPost, Settings, MAX_CLOCK_SKEW, construct_path_msg};
+    use crate::db::post_sync::SyncCursor;
+    use crate::db::trust::CANDIDATE_TABLE;
+    use crate::db::trust_request::HandleBlessing;
+    use crate::db::post_sync::PostSync;
+    use crate::db::handle_post::HandlePost;
+    use crate::db::outbox::Outbox;
+    use crate::db::direct_message::DirectMessages;
+    use crate::db::score::Score;
+
+    let settings = Settings { outbox_backoff: 0, ..Settings::default() };
+    let db1 = NodeDB::new(tempfile::TempDir::new()?, None)?.with_settings(settings.clone());
+    let old = db1.get_identity()?;
+    let db2 = NodeDB::new(tempfile::TempDir::new()?, None)?.with_settings(settings);
+    let node2 = db2.get_identity()?;
+    let (other, third) = (db2.generate_identity()?.node, db2.generate_identity()?.node);
+
+    // db2 knows old in every way it can
+    db2.trust(&old.node)?;
+    db2.set_score(&old.node, 1500)?;
+    db2.register_pending(&old.node)?;
+    db2.request_candidates(&other)?;
+    db2.add_candidates(&other, std::slice::from_ref(&old.node))?;
+    db2.request_candidates(&old.node)?;
+    db2.add_candidates(&old.node, std::slice::from_ref(&third))?;
+    db2.request_candidates(&old.node)?;
+    db2.set_sync_cursor(&old.node, &SyncCursor { received: 5, post: PostId { raw: [1; 32] } })?;
+
+    db1.trust(&node2.node)?;
+    db2.receive_message(&db1.write_message(&node2.node, "meet me at noon")?)?;
+    db2.write_message(&old.node, "see you there")?;
+
+    let post = db2.new_post("hello".to_string())?;
+    let signature = node2.sign(&post.get_id().raw);
+    for outgoing in db2.receive(&IncomingPost::new(&post, &vec![], &signature, &node2, MAX_CLOCK_SKEW)?)? {
+        db2.defer_outgoing(&outgoing)?;
+    }
+
+    let rotation = db1.rotate_identity(None)?;
+    let new = db1.get_identity()?;
+    assert!(db2.receive_rotation(&rotation)?);
+
+    // Nothing is left on the old key
+    assert!(db2.is_trusted(&new.node)?);
+    assert_eq!(db2.get_score(&new.node, 1200)?, 1500);
+    assert!(db2.has_seen(&new.node, &post.get_id())?);
+    assert!(!db2.has_seen(&old.node, &post.get_id())?);
+    assert!(db2.get_sync_cursor(&new.node)?.is_some());
+    assert!(db2.get_sync_cursor(&old.node)?.is_none());
+    for table in [PENDING_TABLE, CANDIDATE_REQUEST_TABLE, CANDIDATE_TABLE] {
+        assert!(!db2.db.open_tree(table)?.contains_key(old.node.public_key)?);
+    }
+    assert!(db2.db.open_tree(PENDING_TABLE)?.contains_key(new.node.public_key)?);
+    assert!(db2.db.open_tree(CANDIDATE_REQUEST_TABLE)?.contains_key(new.node.public_key)?);
+    assert_eq!(db2.get_candidates()?.into_iter().find(|(node, _)| node == &third).map(|(_, candidate)| candidate.via), Some(new.node.clone()));
+
+    // What was on its way goes to the new key, signed for it
+    let entries = db2.due_outgoing()?;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].recipient(), &new.node);
+    let last = entries[0].post.history.last().ok_or("no last hop")?;
+    last.from.verify(&construct_path_msg(&post.get_id(), &last.from, &last.to), &last.signature)?;
+
+    let inbox = db2.inbox(None, 10)?;
+    assert_eq!(inbox.len(), 2);
+    assert!(inbox.iter().all(|message| message.peer == new.node));
+    let due = db2.due_messages()?;
+    assert_eq!(due.len(), 1);
+    assert_eq!(db1.receive_message(&due[0])?.content, "see you there");
+
+    // Our own copies open with the new key, and what we still owe goes out from it
+    let inbox = db1.inbox(Some(&node2.node), 10)?;
+    assert_eq!(inbox.len(), 2);
+    assert!(inbox.iter().any(|message| message.content == "meet me at noon"));
+    assert_eq!(db1.due_messages()?[0].sender, new.node);
+
+    Ok(())
+}
+
+#[test]
+fn test_announcements() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::Settings;
+
+    let settings = Settings { outbox_backoff: 0, ..Settings::default() };
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?.with_settings(settings);
+    let peer = db.generate_identity()?;
+    db.trust(&peer.node)?;
+
+    // Every peer hears about it until they took it
+    let rotation = db.rotate_identity(None)?;
+    assert_eq!(db.due_announcements()?, vec![(peer.node.clone(), rotation.clone())]);
+    assert_eq!(db.announcements_for(&peer.node)?, vec![rotation.clone()]);
+    assert!(db.announcement_failed(&peer.node, &rotation)?.is_some());
+    assert_eq!(db.due_announcements()?.len(), 1);
+
+    db.rotation_announced(&peer.node, &rotation)?;
+    assert_eq!(db.due_announcements()?, vec![]);
+    assert_eq!(db.announcement_failed(&peer.node, &rotation)?, None);
+
+    Ok(())
+}
@@ -1,6 +1,6 @@
 use tempfile::tempfile;
 
-use crate::db::{NodeDB, Node, PostId, TrustRequest};
+use crate::db::{NodeDB, Node, PostId, TrustRequest, Error};
 // use crate::misc::get_epoch;
 use crate::db::identity::Identity;
 use crate::db::handle_post::HandlePost;
@@ -8,6 +8,7 @@ use crate::db::trust_request::HandleBlessing;
 
 use super::trust::Trust;
 use super::IncomingPost;
+use crate::db::retention::Retention;
 
 fn calculate_p_win(winner_rating: usize, loser_rating: usize) -> f64 {
     let (winner_rating, loser_rating) = (winner_rating as f64, loser_rating as f64);
@@ -25,14 +26,15 @@ fn calculate_new_elo(winner_rating: usize, loser_rating: usize) -> (usize, usize
 }
 
 pub trait Score {
-    fn set_score(&self, node:&Node, value:usize) -> Result<(), Box<dyn std::error::Error>>;
-    fn update_scores(&self, promote_us:bool, post: &IncomingPost) -> Result<Option<RecommendedAction>, Box<dyn std::error::Error>>;
-    fn promote(&self, post: &PostId) -> Result<Option<TrustRequest>, Box<dyn std::error::Error>>;
-    fn demote(&self, post: &PostId) -> Result<Option<TrustRequest>, Box<dyn std::error::Error>>;
-    fn get_score(&self, node: &Node, default_score:usize) -> Result<usize, Box<dyn std::error::Error>>;
+    fn set_score(&self, node:&Node, value:usize) -> Result<(), Error>;
+    fn update_scores(&self, promote_us:bool, post: &IncomingPost) -> Result<Option<RecommendedAction>, Error>;
+    fn promote(&self, post: &PostId) -> Result<Option<TrustRequest>, Error>;
+    fn demote(&self, post: &PostId) -> Result<Option<TrustRequest>, Error>;
+    fn get_score(&self, node: &Node, default_score:usize) -> Result<usize, Error>;
+    fn penalize(&self, node: &Node, amount:usize) -> Result<usize, Error>;
 }
 
-const SCORES_TABLE:&str = "SCORE_TABLE";
+pub(crate) const SCORES_TABLE:&str = "SCORE_TABLE";
 
 pub enum RecommendedAction {
     Trust(TrustRequest),
@@ -41,18 +43,18 @@ pub enum RecommendedAction {
 
 impl Score for NodeDB {
 
-    fn set_score(&self, node:&Node, value:usize) -> Result<(), Box<dyn std::error::Error>> {
+    fn set_score(&self, node:&Node, value:usize) -> Result<(), Error> {
         let scores = self.db.open_tree(SCORES_TABLE)?;
         scores.insert(&node.public_key, bincode::serialize(&value)?)?;
         return Ok(());
     }
     
-    fn update_scores(&self, promote_us:bool, post: &IncomingPost) -> Result<Option<RecommendedAction>, Box<dyn std::error::Error>> {
+    fn update_scores(&self, promote_us:bool, post: &IncomingPost) -> Result<Option<RecommendedAction>, Error> {
         let us = self.get_identity()?;
         let author = post.post.author.clone();
 
         if author == us.node {
-            return Err("Cannot promote our own post")?;
+            return Err(Error::OwnPost);
         }
 
         let mut our_score = self.get_score(&us.node, 1200)?;
@@ -68,11 +70,15 @@ impl Score for NodeDB {
         self.set_score(&author, their_score)?;
 
         if calculate_p_win(their_score, our_score) > 0.5 + 0.1 {
-            let blessing = self.construct_blessing(post)?;
-            return Ok(Some(RecommendedAction::Trust(blessing)));
+            // No blessing if we already trust them, or the post came straight from them
+            return match self.construct_blessing(post) {
+                Ok(blessing) => Ok(Some(RecommendedAction::Trust(blessing))),
+                Err(Error::AlreadyTrusted | Error::InsufficientHistory) => Ok(None),
+                Err(e) => Err(e)
+            };
         }
 
-        if calculate_p_win(their_score, our_score) > 0.5 - 0.1 {
+        if calculate_p_win(their_score, our_score) < 0.5 - 0.1 {
             return Ok(Some(RecommendedAction::Distrust));
         }
 
@@ -81,10 +87,11 @@ impl Score for NodeDB {
         Ok(None)
     }
 
-    fn promote(&self, post_id: &PostId) -> Result<Option<TrustRequest>, Box<dyn std::error::Error>> {
+    fn promote(&self, post_id: &PostId) -> Result<Option<TrustRequest>, Error> {
         let post =self.resolve(post_id)?;
 
         let action = self.update_scores(false, &post)?;
+        self.pin(post_id)?; // We liked it, so keep it around
 
         match action {
             Some(RecommendedAction::Trust(blessing)) => {
@@ -94,9 +101,10 @@ impl Score for NodeDB {
         }
     }
     
-    fn demote(&self, post: &PostId) -> Result<Option<TrustRequest>, Box<dyn std::error::Error>> {
+    fn demote(&self, post: &PostId) -> Result<Option<TrustRequest>, Error> {
         let post = self.resolve(post)?;
         let action = self.update_scores(true, &post)?;
+        self.unpin(&post.get_id())?;
 
         match action {
             Some(RecommendedAction::Distrust) => {
@@ -109,7 +117,7 @@ impl Score for NodeDB {
 
     }
 
-    fn get_score(&self, node: &Node, default_score:usize) -> Result<usize, Box<dyn std::error::Error>> {
+    fn get_score(&self, node: &Node, default_score:usize) -> Result<usize, Error> {
         let scores = self.db.open_tree(SCORES_TABLE)?;
         let score = scores.get(&node.public_key)?;
 
@@ -120,6 +128,13 @@ impl Score for NodeDB {
         
         return Ok(default_score); //Err("User was not scored yet - need to accept a blessing from them first")?;
     }
+
+    // Misbehaving (or unreachable) peers sink, so they are the first to get kicked
+    fn penalize(&self, node: &Node, amount:usize) -> Result<usize, Error> {
+        let score = self.get_score(node, 1200)?.saturating_sub(amount);
+        self.set_score(node, score)?;
+        Ok(score)
+    }
 }
 
 #[test]
@@ -129,12 +144,15 @@ fn basic_scoring() -> Result<(), Box<dyn std::error::Error>> {
     assert_eq!(db.get_score(&node, 1200)?, 1200);
     db.set_score(&node, 1000)?;
     assert_eq!(db.get_score(&node, 1200)?, 1000);
+
+    assert_eq!(db.penalize(&node, 50)?, 950);
+    assert_eq!(db.penalize(&node, 2000)?, 0);
     Ok(())
 }
 
 #[test]
 fn promote_test() -> Result<(), Box<dyn std::error::Error>> {
-    use crate::db::RawPost;
+    use crate::db::{RawPost, MAX_CLOCK_SKEW};
     let db = NodeDB::new(tempfile::TempDir::new()?, None)?;
     let us = db.get_identity()?;
     let author = db.generate_identity()?;
@@ -145,7 +163,8 @@ fn promote_test() -> Result<(), Box<dyn std::error::Error>> {
         &raw_post, 
         &vec![],
         &signature,
-        &us
+        &us,
+        MAX_CLOCK_SKEW
     )?;    
 
     assert_eq!(db.get_score(&us.node, 1200)?, 1200);
@@ -158,4 +177,32 @@ fn promote_test() -> Result<(), Box<dyn std::error::Error>> {
     assert_eq!(db.get_score(&author.node, 1200)? > 1200, true);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn demote_test() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{RawPost, MAX_CLOCK_SKEW};
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let us = db.get_identity()?;
+    let author = db.generate_identity()?;
+    db.trust(&author.node)?;
+    for _ in 0..2 { // Enough peers left to talk to once they are gone
+        db.trust(&db.generate_identity()?.node)?;
+    }
+
+    let raw_post = RawPost::new(author.node.clone(), "".to_string());
+    let signature = author.sign(&raw_post.get_id().raw);
+    let post = IncomingPost::new(&raw_post, &vec![], &signature, &us, MAX_CLOCK_SKEW)?;
+    db.receive(&post)?;
+
+    // One bad post is not enough to drop them
+    db.demote(&post.get_id())?;
+    assert!(db.is_trusted(&author.node)?);
+
+    // But once they fall far enough behind us they are out
+    db.demote(&post.get_id())?;
+    db.demote(&post.get_id())?;
+    assert!(!db.is_trusted(&author.node)?);
+
+    Ok(())
+}
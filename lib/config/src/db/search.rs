@@ -1,51 +1,184 @@
-use crate::db::score::Score;
-use crate::db::{NodeDB, IncomingPost, PostId};
-use crate::db::handle_post::POSTS_TABLE;
-use log::info;
-use crate::db::handle_post::HandlePost;
+use crate::db::{NodeDB, IncomingPost, Node, PostId, Error};
+use crate::db::handle_post::{HandlePost, PostCursor, Direction, AUTHOR_INDEX, VIA_INDEX, TEXT_INDEX};
+use crate::db::rank::Ranker;
+use crate::db::text::{Query, text_prefix};
 
 use crate::misc::get_epoch;
 
 pub trait Search {
-    fn search_posts(&self, after: &Option<PostId>, max_results:usize) -> Result<Vec<(IncomingPost, f64)>, Box<dyn std::error::Error>>;
+    fn search_posts(&self, ranker: &dyn Ranker, from: &Option<RankCursor>, max_results:usize) -> Result<SearchPage, Error>;
+    fn posts_by_author(&self, author: &Node, from: &Option<PostCursor>, direction: Direction, max_results:usize) -> Result<Vec<IncomingPost>, Error>;
+    fn posts_via(&self, peer: &Node, from: &Option<PostCursor>, direction: Direction, max_results:usize) -> Result<Vec<IncomingPost>, Error>;
+    fn search_text(&self, query: &str, ranker: &dyn Ranker, from: &Option<RankCursor>, max_results:usize) -> Result<SearchPage, Error>;
 }
 
-impl Search for NodeDB {
-    fn search_posts(&self, after: &Option<PostId>, max_results:usize) -> Result<Vec<(IncomingPost, f64)>, Box<dyn std::error::Error>> {
-        let posts = self.db.open_tree(POSTS_TABLE)?;
+// How many of the newest posts a ranker gets to pick from, older ones are only
+// reachable through posts_by_time
+pub const RANK_WINDOW:usize = 1000;
 
-        let mut all_posts = vec![];
-        let current_time = get_epoch();
-        let posts = posts.iter().enumerate();
-
-        let after_time = if let Some(after) = after {
-            let post = self.resolve(after)?;
-            post.received
-        } else {0u64};
-        
-        for (_idx, post) in posts {
-            
-            if all_posts.len() >= max_results {
-                continue;
-            }
+#[derive(Debug)]
+pub struct SearchPage {
+    pub posts: Vec<(IncomingPost, f64)>, // Best first
+    pub cursor: Option<RankCursor> // Last post of this page, None once there is nothing left
+}
 
-            if let Ok((_post_id, post)) = post {
-                let post: IncomingPost = bincode::deserialize(&post)?;
+// Where a ranked page ended. Every page is ranked as of the first one,
+// so posts don't move around while someone pages through them.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RankCursor {
+    pub now: u64,
+    pub rank: f64,
+    pub post: PostId // Breaks ties between posts with the same rank
+}
 
-                if post.received > after_time {
-                    let author_score = self.get_score(&post.post.author, 1200)? as f64;
-                    let seconds_ago = (current_time - post.received) as f64;
+impl NodeDB {
+    // Ranks the newest RANK_WINDOW posts `next` walks through (newest first),
+    // and returns the best max_results of those the cursor did not get to yet
+    fn rank_page<F>(&self, ranker: &dyn Ranker, from: &Option<RankCursor>, max_results:usize, mut next: F, keep: impl Fn(&IncomingPost) -> bool) -> Result<SearchPage, Error>
+        where F: FnMut(&Option<PostCursor>) -> Result<Vec<IncomingPost>, Error>
+    {
+        let now = from.as_ref().map_or_else(get_epoch, |from| from.now);
 
-                    let post_score = author_score.log10() / seconds_ago; // reddit rank
-                    all_posts.push((post, post_score));
+        let mut cursor = None;
+        let mut looked_at = 0;
+        let mut all_posts = vec![];
+        'window: loop {
+            let batch = next(&cursor)?;
+            if batch.is_empty() {
+                break;
+            }
 
+            for post in batch {
+                if looked_at >= RANK_WINDOW {
+                    break 'window;
                 }
+                looked_at += 1;
+                cursor = Some(PostCursor::of(&post));
 
-                
+                if !keep(&post) {
+                    continue;
+                }
+                if let Some(rank) = ranker.rank(self, &post, now)? {
+                    all_posts.push((post, rank));
+                }
             }
         }
 
-        Ok(all_posts)
+        all_posts.sort_by(|(post_a, rank_a), (post_b, rank_b)| {
+            rank_b.total_cmp(rank_a).then_with(|| post_a.get_id().raw.cmp(&post_b.get_id().raw))
+        });
+        if let Some(from) = from {
+            all_posts.retain(|(post, rank)| from.rank.total_cmp(rank).then_with(|| post.get_id().raw.cmp(&from.post.raw)).is_gt());
+        }
+        all_posts.truncate(max_results);
+
+        let cursor = all_posts.last().map(|(post, rank)| RankCursor { now, rank: *rank, post: post.get_id() });
+        Ok(SearchPage { posts: all_posts, cursor })
+    }
+}
+
+impl Search for NodeDB {
+    fn search_posts(&self, ranker: &dyn Ranker, from: &Option<RankCursor>, max_results:usize) -> Result<SearchPage, Error> {
+        self.rank_page(ranker, from, max_results,
+            |cursor| self.posts_by_time(cursor, Direction::Backward, RANK_WINDOW),
+            |_post| true)
+    }
+
+    // Walks the index of one word, and checks the rest of the query against each post
+    fn search_text(&self, query: &str, ranker: &dyn Ranker, from: &Option<RankCursor>, max_results:usize) -> Result<SearchPage, Error> {
+        let query = Query::parse(query);
+        let driver = match query.driver() {
+            Some(driver) => text_prefix(driver),
+            None => return Ok(SearchPage { posts: vec![], cursor: None })
+        };
+
+        self.rank_page(ranker, from, max_results,
+            |cursor| self.scan_index(TEXT_INDEX, &driver, cursor.as_ref().map(PostCursor::key), Direction::Backward, RANK_WINDOW),
+            |post| query.matches(&post.post.content))
+    }
+
+    // Profile pages
+    fn posts_by_author(&self, author: &Node, from: &Option<PostCursor>, direction: Direction, max_results:usize) -> Result<Vec<IncomingPost>, Error> {
+        self.scan_index(AUTHOR_INDEX, &author.public_key, from.as_ref().map(PostCursor::key), direction, max_results)
     }
 
-}
\ No newline at end of file
+    // Everything a peer handed to us directly
+    fn posts_via(&self, peer: &Node, from: &Option<PostCursor>, direction: Direction, max_results:usize) -> Result<Vec<IncomingPost>, Error> {
+        self.scan_index(VIA_INDEX, &peer.public_key, from.as_ref().map(PostCursor::key), direction, max_results)
+    }
+}
+
+#[test]
+fn test_author_and_via() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::{RawPost, MAX_CLOCK_SKEW};
+    use crate::db::identity::Identity;
+    use crate::db::trust::Trust;
+
+    let db1 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node1 = db1.get_identity()?;
+
+    let db2 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node2 = db2.get_identity()?;
+    db2.trust(&node1.node)?;
+
+    // Node2 writes a post and hands it to node1
+    let raw_post = RawPost::new(node2.node.clone(), "".to_string());
+    let signature = node2.sign(&raw_post.get_id().raw);
+    let out = db2.receive(&IncomingPost::new(&raw_post, &vec![], &signature, &node2, MAX_CLOCK_SKEW)?)?;
+    let out_post = out.last().unwrap();
+    db1.receive(&IncomingPost::new(&out_post.post, &out_post.history, &out_post.signature, &node1, MAX_CLOCK_SKEW)?)?;
+
+    // And node1 writes one of their own
+    let raw_post = RawPost::new(node1.node.clone(), "".to_string());
+    let signature = node1.sign(&raw_post.get_id().raw);
+    db1.receive(&IncomingPost::new(&raw_post, &vec![], &signature, &node1, MAX_CLOCK_SKEW)?)?;
+
+    assert_eq!(db1.posts_by_author(&node2.node, &None, Direction::Forward, 10)?.len(), 1);
+    assert_eq!(db1.posts_by_author(&node1.node, &None, Direction::Backward, 10)?.len(), 1);
+    assert_eq!(db1.posts_via(&node2.node, &None, Direction::Forward, 10)?.len(), 1);
+    assert_eq!(db1.posts_via(&node1.node, &None, Direction::Forward, 10)?.len(), 0);
+
+    // Paging past the only post leaves nothing
+    let first = db1.posts_by_author(&node2.node, &None, Direction::Forward, 1)?;
+    let cursor = Some(PostCursor::of(&first[0]));
+    assert_eq!(db1.posts_by_author(&node2.node, &cursor, Direction::Forward, 10)?.len(), 0);
+    assert_eq!(db1.posts_by_author(&node2.node, &cursor, Direction::Backward, 10)?.len(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_search_text() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::MAX_CLOCK_SKEW;
+    use crate::db::identity::Identity;
+    use crate::db::feed::Feed;
+    use crate::db::rank::Chronological;
+
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let us = db.get_identity()?;
+
+    for content in ["Sled tree posts are neat", "the tree of sleds", "nothing to see", "A SLED TREE again"] {
+        let raw_post = db.new_post(content.to_string())?;
+        let signature = us.sign(&raw_post.get_id().raw);
+        db.receive(&IncomingPost::new(&raw_post, &vec![], &signature, &us, MAX_CLOCK_SKEW)?)?;
+    }
+
+    let found = |query: &str, max_results: usize| -> Result<usize, Error> {
+        Ok(db.search_text(query, &Chronological, &None, max_results)?.posts.len())
+    };
+    assert_eq!(found("tree", 10)?, 3);
+    assert_eq!(found("sled tree", 10)?, 2);
+    assert_eq!(found("\"sled tree\"", 10)?, 2);
+    assert_eq!(found("\"tree sled\"", 10)?, 0);
+    assert_eq!(found("", 10)?, 0);
+
+    // One page at a time
+    let page = db.search_text("tree", &Chronological, &None, 2)?;
+    assert_eq!(page.posts.len(), 2);
+    let page = db.search_text("tree", &Chronological, &page.cursor, 2)?;
+    assert_eq!(page.posts.len(), 1);
+    let page = db.search_text("tree", &Chronological, &page.cursor, 2)?;
+    assert_eq!((page.posts.len(), page.cursor), (0, None));
+
+    Ok(())
+}
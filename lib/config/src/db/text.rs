@@ -0,0 +1,76 @@
+/*
+    Helpers for the full text index.
+    Words are lowercased runs of letters and digits, everything else separates them.
+    Only public posts are indexed: a sealed post's content is ciphertext, and we don't
+    keep the words of what we opened lying around in the clear, so search never finds those.
+*/
+
+pub fn tokenize(content: &str) -> Vec<String> {
+    content.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+// Keys in the text index are word + 0 + PostCursor::key, words never contain a 0
+pub(crate) fn text_prefix(word: &str) -> Vec<u8> {
+    [word.as_bytes(), &[0u8]].concat()
+}
+
+// Every word has to show up somewhere, every phrase has to show up as is
+#[derive(Debug, PartialEq, Clone)]
+pub struct Query {
+    pub keywords: Vec<String>,
+    pub phrases: Vec<Vec<String>>
+}
+
+impl Query {
+    // rust "sled tree" -> keywords [rust], phrases [[sled, tree]]
+    pub fn parse(query: &str) -> Self {
+        let mut keywords = vec![];
+        let mut phrases = vec![];
+
+        for (idx, part) in query.split('"').enumerate() {
+            if idx % 2 == 1 {
+                let phrase = tokenize(part);
+                match phrase.len() {
+                    0 => {},
+                    1 => keywords.extend(phrase),
+                    _ => phrases.push(phrase)
+                }
+            } else {
+                keywords.extend(tokenize(part));
+            }
+        }
+
+        Query { keywords, phrases }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keywords.is_empty() && self.phrases.is_empty()
+    }
+
+    // The word we walk the index with, longer words tend to be rarer
+    pub fn driver(&self) -> Option<&String> {
+        self.keywords.iter().chain(self.phrases.iter().flatten())
+            .max_by_key(|word| word.len())
+    }
+
+    pub fn matches(&self, content: &str) -> bool {
+        let words = tokenize(content);
+        self.keywords.iter().all(|keyword| words.contains(keyword))
+            && self.phrases.iter().all(|phrase| words.windows(phrase.len()).any(|window| window == &phrase[..]))
+    }
+}
+
+#[test]
+fn test_query() {
+    let query = Query::parse("Rust \"sled  Tree\" \"x\"");
+    assert_eq!(query.keywords, vec!["rust", "x"]);
+    assert_eq!(query.phrases, vec![vec!["sled".to_string(), "tree".to_string()]]);
+    assert_eq!(query.driver().map(|word| word.len()), Some(4));
+
+    assert!(query.matches("A sled tree, in RUST! x"));
+    assert!(!query.matches("A tree sled, in rust x"));
+    assert!(Query::parse(" \"\" ").is_empty());
+}
@@ -1,37 +1,85 @@
-use crate::db::{NodeDB, Node};
+use crate::db::{NodeDB, Node, Error};
+use crate::db::identity::Identity;
 use crate::misc::get_epoch;
+use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
 
 use super::score::Score;
 
 pub trait Trust {
-    fn trust(&self, node: &Node) -> Result<(), Box<dyn std::error::Error>>;
-    fn untrust(&self, node: &Node) -> Result<(), Box<dyn std::error::Error>>;
-    fn is_trusted(&self, node: &Node) -> Result<bool, Box<dyn std::error::Error>>;
-    fn get_trusted(&self) -> Result<Vec<(Node, usize)>, Box<dyn std::error::Error>>;
-    fn num_trusted(&self) ->  Result<usize, Box<dyn std::error::Error>>;
+    fn trust(&self, node: &Node) -> Result<(), Error>;
+    fn untrust(&self, node: &Node) -> Result<(), Error>;
+    fn is_trusted(&self, node: &Node) -> Result<bool, Error>;
+    fn get_trusted(&self) -> Result<Vec<(Node, usize)>, Error>;
+    fn num_trusted(&self) ->  Result<usize, Error>;
+    fn sample_trusted(&self, requester: &Node, limit: Option<usize>) -> Result<Vec<Node>, Error>;
+    fn request_candidates(&self, peer: &Node) -> Result<(), Error>;
+    fn add_candidates(&self, via: &Node, nodes: &[Node]) -> Result<usize, Error>;
+    fn get_candidates(&self) -> Result<Vec<(Node, Candidate)>, Error>;
+    fn candidate_asked(&self, node: &Node) -> Result<(), Error>;
+    fn remove_candidate(&self, node: &Node) -> Result<(), Error>;
 }
 
+// Most peers we hand out to someone asking for our secondary peers
+pub const MAX_SECONDARY_PEERS:usize = 16;
+
 // If a node is within the table, then they were trusted
 // Unseen nodes are by default untrusted
-const TRUST_TABLE:&str = "TRUST_TABLE";
+pub(crate) const TRUST_TABLE:&str = "TRUST_TABLE";
+
+// Nodes our peers told us about, that we could try to trust later
+// node -> Candidate
+pub(crate) const CANDIDATE_TABLE:&str = "CANDIDATE_TABLE";
+// Peers we asked for their secondary peers, but have not heard back from yet
+pub(crate) const CANDIDATE_REQUEST_TABLE:&str = "CANDIDATE_REQUEST_TABLE";
+
+// Anything past this is dropped until we got through the ones we have
+pub const MAX_CANDIDATES:usize = 64;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Candidate {
+    pub via: Node, // The peer that told us about them
+    pub asked: bool // Whether we already asked them for the candidate's feed
+}
+
+impl NodeDB {
+    // Someone rotated their key: they are still the same candidate, and still the one who told us about others
+    pub(crate) fn move_candidates(&self, old: &Node, new: &Node) -> Result<(), Error> {
+        let candidates = self.db.open_tree(CANDIDATE_TABLE)?;
+        if let Some(candidate) = candidates.remove(old.public_key)? {
+            if !self.is_trusted(new)? {
+                let _ = candidates.compare_and_swap(new.public_key, None as Option<&[u8]>, Some(candidate))?;
+            }
+        }
+
+        for entry in candidates.iter() {
+            let (node, candidate) = entry?;
+            let candidate: Candidate = bincode::deserialize(&candidate)?;
+            if &candidate.via == old {
+                candidates.insert(node, bincode::serialize(&Candidate { via: new.clone(), ..candidate })?)?;
+            }
+        }
+        Ok(())
+    }
+}
 
 impl Trust for NodeDB {
-    fn trust(&self, node: &Node) -> Result<(), Box<dyn std::error::Error>> {
+    fn trust(&self, node: &Node) -> Result<(), Error> {
         let trusted = self.db.open_tree(TRUST_TABLE)?;
         trusted.insert(&node.public_key,  bincode::serialize(&get_epoch())?)?;
         Ok(())
     }
 
-    fn num_trusted(&self) ->  Result<usize, Box<dyn std::error::Error>> {
+    fn num_trusted(&self) ->  Result<usize, Error> {
         let trusted = self.db.open_tree(TRUST_TABLE)?;
         Ok(trusted.len())
     }
 
-    fn untrust(&self, node: &Node) -> Result<(), Box<dyn std::error::Error>> {
+    fn untrust(&self, node: &Node) -> Result<(), Error> {
         if self.is_trusted(node)? {
 
             if self.num_trusted()? <= 2 { // Ourselves and the bootstrap node
-                Err("Hit minimum number of trusted nodes")?;
+                return Err(Error::MinimumPeers);
             }
 
             let trusted = self.db.open_tree(TRUST_TABLE)?;
@@ -41,12 +89,12 @@ impl Trust for NodeDB {
         Ok(())
     }
 
-    fn is_trusted(&self, node: &Node) -> Result<bool, Box<dyn std::error::Error>> {
+    fn is_trusted(&self, node: &Node) -> Result<bool, Error> {
         let trusted = self.db.open_tree(TRUST_TABLE)?;
         Ok(trusted.contains_key(&node.public_key.clone())?)
     }
 
-    fn get_trusted(&self) -> Result<Vec<(Node, usize)>, Box<dyn std::error::Error>> {
+    fn get_trusted(&self) -> Result<Vec<(Node, usize)>, Error> {
         let trusted = self.db.open_tree(TRUST_TABLE)?;
         
         let mut results = vec![];
@@ -63,8 +111,84 @@ impl Trust for NodeDB {
 
         Ok(results)
     }
-}
 
+    fn sample_trusted(&self, requester: &Node, limit: Option<usize>) -> Result<Vec<Node>, Error> {
+        // A new node only knows its bootstrap node, which is where it finds its first candidates
+        if self.bootstrap_nodes.is_some() && !self.is_trusted(requester)? {
+            return Err(Error::UntrustedPeer);
+        }
+
+        let mut nodes:Vec<Node> = self.get_trusted()?.into_iter()
+            .map(|(node, _score)| node)
+            .filter(|node| node != requester)
+            .collect();
+
+        // Random so nobody can map out all of our peers by asking over and over
+        nodes.shuffle(&mut rand::rng());
+        nodes.truncate(limit.unwrap_or(MAX_SECONDARY_PEERS).min(MAX_SECONDARY_PEERS));
+
+        Ok(nodes)
+    }
+
+    fn request_candidates(&self, peer: &Node) -> Result<(), Error> {
+        let requests = self.db.open_tree(CANDIDATE_REQUEST_TABLE)?;
+        requests.insert(peer.public_key, bincode::serialize(&get_epoch())?)?;
+        Ok(())
+    }
+
+    // Only from peers we asked, and never more than MAX_CANDIDATES in total
+    fn add_candidates(&self, via: &Node, nodes: &[Node]) -> Result<usize, Error> {
+        let requests = self.db.open_tree(CANDIDATE_REQUEST_TABLE)?;
+        if requests.remove(via.public_key)?.is_none() {
+            return Err(Error::UnsolicitedResponse);
+        }
+
+        let us = self.get_identity()?;
+        let candidates = self.db.open_tree(CANDIDATE_TABLE)?;
+
+        let mut added = 0;
+        for node in nodes {
+            if candidates.len() >= MAX_CANDIDATES {
+                break;
+            }
+            if node == &us.node || self.is_trusted(node)? || candidates.contains_key(node.public_key)? {
+                continue;
+            }
+
+            let candidate = Candidate { via: via.clone(), asked: false };
+            candidates.insert(node.public_key, bincode::serialize(&candidate)?)?;
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    fn get_candidates(&self) -> Result<Vec<(Node, Candidate)>, Error> {
+        let candidates = self.db.open_tree(CANDIDATE_TABLE)?;
+
+        let mut results = vec![];
+        for candidate in candidates.iter() {
+            let (node, candidate) = candidate?;
+            results.push((bincode::deserialize(&node)?, bincode::deserialize(&candidate)?));
+        }
+
+        Ok(results)
+    }
+
+    fn candidate_asked(&self, node: &Node) -> Result<(), Error> {
+        let candidates = self.db.open_tree(CANDIDATE_TABLE)?;
+        if let Some(candidate) = candidates.get(node.public_key)? {
+            let candidate = Candidate { asked: true, ..bincode::deserialize(&candidate)? };
+            candidates.insert(node.public_key, bincode::serialize(&candidate)?)?;
+        }
+        Ok(())
+    }
+
+    fn remove_candidate(&self, node: &Node) -> Result<(), Error> {
+        let candidates = self.db.open_tree(CANDIDATE_TABLE)?;
+        candidates.remove(node.public_key)?;
+        Ok(())
+    }
+}
 
 
 #[test]
@@ -99,4 +223,57 @@ fn trust_fetching() -> Result<(), Box<dyn std::error::Error>> {
     assert_eq!(trusted.last().unwrap(), &(node1, 1200 as usize));
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn secondary_peers() -> Result<(), Box<dyn std::error::Error>> {
+    let db = NodeDB::new(tempfile::TempDir::new()?, Some(vec![]))?;
+    let requester = Node::new([0u8; 32]);
+
+    assert!(db.sample_trusted(&requester, None).is_err());
+    db.trust(&requester)?;
+
+    for idx in 1..=(MAX_SECONDARY_PEERS as u8 + 4) {
+        db.trust(&Node::new([idx; 32]))?;
+    }
+
+    let sample = db.sample_trusted(&requester, None)?;
+    assert_eq!(sample.len(), MAX_SECONDARY_PEERS);
+    assert!(!sample.contains(&requester));
+
+    assert_eq!(db.sample_trusted(&requester, Some(3))?.len(), 3);
+
+    Ok(())
+}
+
+#[test]
+fn candidates() -> Result<(), Box<dyn std::error::Error>> {
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let us = db.get_identity()?;
+    let via = Node::new([0u8; 32]);
+    let trusted = Node::new([1u8; 32]);
+    db.trust(&trusted)?;
+
+    // Nobody gets to fill up our candidates without being asked
+    assert!(matches!(db.add_candidates(&via, &[Node::new([2u8; 32])]), Err(Error::UnsolicitedResponse)));
+
+    // Ourselves and the nodes we already trust are no use
+    db.request_candidates(&via)?;
+    let nodes:Vec<Node> = (2..=(MAX_CANDIDATES as u8 + 4)).map(|idx| Node::new([idx; 32])).collect();
+    let offered = [vec![us.node.clone(), trusted.clone()], nodes].concat();
+    assert_eq!(db.add_candidates(&via, &offered)?, MAX_CANDIDATES);
+
+    let candidates = db.get_candidates()?;
+    assert_eq!(candidates.len(), MAX_CANDIDATES);
+    assert!(candidates.iter().all(|(node, candidate)| node != &us.node && node != &trusted && candidate.via == via && !candidate.asked));
+
+    // Each request is good for one answer
+    assert!(matches!(db.add_candidates(&via, &[]), Err(Error::UnsolicitedResponse)));
+
+    db.candidate_asked(&candidates[0].0)?;
+    assert!(db.get_candidates()?[0].1.asked);
+    db.remove_candidate(&candidates[0].0)?;
+    assert_eq!(db.get_candidates()?.len(), MAX_CANDIDATES - 1);
+
+    Ok(())
+}
@@ -1,33 +1,44 @@
 use crate::db::identity::Identity;
 use crate::db::trust::Trust;
-use crate::db::{NodeDB, IncomingPost, TrustRequest, construct_path_msg, Node};
+use crate::db::{NodeDB, IncomingPost, TrustRequest, construct_path_msg, Node, Error};
 use crate::db::handle_post::HandlePost;
 use crate::db::score::Score;
+use crate::misc::get_epoch;
 
 pub trait HandleBlessing {
-    fn construct_blessing(&self, post: &IncomingPost) -> Result<TrustRequest, Box<dyn std::error::Error>>;
-    fn check_blessing(&self, trust_request: TrustRequest, from:&Node) -> Result<(), Box<dyn std::error::Error>>;
+    fn construct_blessing(&self, post: &IncomingPost) -> Result<TrustRequest, Error>;
+    fn candidate_blessing(&self, candidate: &Node) -> Result<Option<TrustRequest>, Error>;
+    fn check_blessing(&self, trust_request: TrustRequest, from:&Node) -> Result<(), Error>;
+    fn accept_bootstrap(&self, from:&Node) -> Result<(), Error>;
+    fn register_pending(&self, recipient:&Node) -> Result<(), Error>;
+    fn complete_blessing(&self, from:&Node, accepted:bool) -> Result<bool, Error>;
 }
 
 const MAX_PEERS:usize = 32;
 
+// How far back into a candidate's feed we look for a post that proves we know them
+const CANDIDATE_POSTS:usize = 16;
+
+// Nodes we sent a trust request to, but have not heard back from yet
+pub(crate) const PENDING_TABLE:&str = "PENDING_TRUST_TABLE";
+
 
 impl HandleBlessing for NodeDB {
-    fn construct_blessing(&self, post: &IncomingPost) -> Result<TrustRequest, Box<dyn std::error::Error>> {
+    fn construct_blessing(&self, post: &IncomingPost) -> Result<TrustRequest, Error> {
 
         if post.history.len() < 2 {
-            return Err("Post does not contain enough history (we are already directly trusted or node history was tampered)")?;
+            return Err(Error::InsufficientHistory);
         }
 
         let mut history = post.history.clone();
         history.reverse();
 
-        let given_to_us = history.get(0).ok_or("path history too short (we received it from a node that does not wish to share secondary peers")?;
-        let given_to_inter = history.get(1).ok_or("path history too short (we are already directly connected to author)")?;
+        let given_to_us = history.first().ok_or(Error::InsufficientHistory)?;
+        let given_to_inter = history.get(1).ok_or(Error::InsufficientHistory)?;
         
 
         if self.is_trusted(&given_to_inter.from)? {
-            return Err("Already trusted")?;
+            return Err(Error::AlreadyTrusted);
         }
 
         let intermediate = given_to_inter.to.clone();
@@ -40,33 +51,55 @@ impl HandleBlessing for NodeDB {
 
     }
 
-    fn check_blessing(&self, trust_request: TrustRequest, from:&Node) -> Result<(), Box<dyn std::error::Error>> {
+    // A post the candidate gave straight to one of our peers, who then gave it to us
+    fn candidate_blessing(&self, candidate: &Node) -> Result<Option<TrustRequest>, Error> {
+        for entry in self.latest_feed(candidate, CANDIDATE_POSTS)? {
+            let post = match self.resolve(&entry.post) {
+                Ok(post) => post,
+                Err(Error::PostNotFound) => continue,
+                Err(e) => return Err(e)
+            };
+
+            match self.construct_blessing(&post) {
+                Ok(blessing) if &blessing.recipient == candidate => return Ok(Some(blessing)),
+                Ok(_) | Err(Error::AlreadyTrusted | Error::InsufficientHistory) => continue,
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(None)
+    }
+
+    fn check_blessing(&self, trust_request: TrustRequest, from:&Node) -> Result<(), Error> {
         
         let us = self.get_identity()?;
 
         if us.node.public_key == from.public_key {
-            return Err("Tried to accept a trust request from ourself")?;
+            return Err(Error::SelfTrust);
+        }
+
+        if us.node.public_key != trust_request.recipient.public_key {
+            return Err(Error::WrongRecipient);
         }
 
         if us.node.public_key == trust_request.intermediate.public_key {
-            return Err("Trust request used us as the intermediate node")?;
+            return Err(Error::IntermediateIsUs);
         }
 
         if !self.is_trusted(&trust_request.intermediate)? {
-            return Err("Trust referenced untrusted intermediate node")?;
+            return Err(Error::UntrustedIntermediate);
         }
 
         if !self.has_seen(&us.node, &trust_request.post)? {
-            return Err("Trust referenced an unknown post")?;
+            return Err(Error::UnknownPost);
         }
 
         if !self.has_seen(&trust_request.intermediate, &trust_request.post)? {
-            return Err("Trust request referenced post that we did not send to the intermediate node")?;
+            return Err(Error::UnsharedPost);
         }
 
         let message = construct_path_msg(&trust_request.post, &trust_request.intermediate, &from);
         if trust_request.intermediate.verify(&message, &trust_request.signature).is_err() {
-            return Err("Trust request cannot prove that they received post from intermediate node (signature failed)")?;
+            return Err(Error::InvalidSignature);
         }
 
         let mut trusted_nodes = self.get_trusted()?;
@@ -82,8 +115,9 @@ impl HandleBlessing for NodeDB {
             if let Some((worst_trust, worst_score)) = first_node {
                 if *worst_score < from_score {
                     self.untrust(worst_trust)?;
+                    self.trust(&from)?;
                 } else {
-                    Err("candidate node was not good enough to kick worst trusted node (too many peers)")?;
+                    return Err(Error::PeerLimitReached);
                 }
             }
 
@@ -95,11 +129,42 @@ impl HandleBlessing for NodeDB {
         Ok(())
     }
 
+    fn accept_bootstrap(&self, from:&Node) -> Result<(), Error> {
+        // Only bootstrap nodes let anyone in without proof, everyone else needs a blessing
+        if self.bootstrap_nodes.is_some() {
+            return Err(Error::NotBootstrap);
+        }
+
+        self.trust(from)?;
+        Ok(())
+    }
+
+    fn register_pending(&self, recipient:&Node) -> Result<(), Error> {
+        let pending = self.db.open_tree(PENDING_TABLE)?;
+        pending.insert(recipient.public_key, bincode::serialize(&get_epoch())?)?;
+        Ok(())
+    }
+
+    fn complete_blessing(&self, from:&Node, accepted:bool) -> Result<bool, Error> {
+        let pending = self.db.open_tree(PENDING_TABLE)?;
+
+        if pending.remove(from.public_key)?.is_none() {
+            return Err(Error::UnsolicitedResponse);
+        }
+
+        // They trust us now, so we trust them back
+        if accepted {
+            self.trust(from)?;
+        }
+
+        Ok(accepted)
+    }
+
 }
 
 #[test]
 fn test_trust_request() -> Result<(), Box<dyn std::error::Error>> {
-    use crate::db::RawPost;
+    use crate::db::{RawPost, MAX_CLOCK_SKEW};
 
     let db1 = NodeDB::new(tempfile::TempDir::new()?, None)?;
     let node1 = db1.get_identity()?;
@@ -121,7 +186,8 @@ fn test_trust_request() -> Result<(), Box<dyn std::error::Error>> {
         &raw_post, 
         &vec![],
         &signature,
-        &node1
+        &node1,
+        MAX_CLOCK_SKEW
     )?;
 
     // Node1 sending post to Node2
@@ -129,12 +195,12 @@ fn test_trust_request() -> Result<(), Box<dyn std::error::Error>> {
     let out_post = out.last().expect("author did not send any posts").clone();
 
     // Node2 receiving post from Node1, then sending it to Node3
-    let in_post = IncomingPost::new(&out_post.post, &out_post.history, &out_post.signature, &node2)?;
+    let in_post = IncomingPost::new(&out_post.post, &out_post.history, &out_post.signature, &node2, MAX_CLOCK_SKEW)?;
     let out = db2.receive(&in_post)?;
     let out_post = out.last().expect("author did not send any posts").clone();
 
     // Node3 receiving post from Node2
-    let in_post = IncomingPost::new(&out_post.post, &out_post.history, &out_post.signature, &node3)?;
+    let in_post = IncomingPost::new(&out_post.post, &out_post.history, &out_post.signature, &node3, MAX_CLOCK_SKEW)?;
     let _out = db3.receive(&in_post)?;
 
     // Node3 creating trust request
@@ -147,3 +213,63 @@ fn test_trust_request() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[test]
+fn test_pending_blessing() -> Result<(), Box<dyn std::error::Error>> {
+    let db = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node1 = Node::new([1u8; 32]);
+    let node2 = Node::new([2u8; 32]);
+
+    // Unsolicited responses are ignored
+    assert!(db.complete_blessing(&node1, true).is_err());
+    assert!(!db.is_trusted(&node1)?);
+
+    db.register_pending(&node1)?;
+    assert!(db.complete_blessing(&node1, true)?);
+    assert!(db.is_trusted(&node1)?);
+
+    db.register_pending(&node2)?;
+    assert!(!db.complete_blessing(&node2, false)?);
+    assert!(!db.is_trusted(&node2)?);
+
+    // A pending request can only be answered once
+    assert!(db.complete_blessing(&node2, true).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_candidate_blessing() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::db::MAX_CLOCK_SKEW;
+    use crate::db::feed::Feed;
+
+    let db1 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node1 = db1.get_identity()?;
+
+    let db2 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node2 = db2.get_identity()?;
+
+    let db3 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node3 = db3.get_identity()?;
+
+    // Node2 told node3 about node1, but node3 has nothing of theirs yet
+    db1.trust(&node2.node)?;
+    db2.trust(&node3.node)?;
+    assert_eq!(db3.candidate_blessing(&node1.node)?, None);
+
+    // Node1 -> node2 -> node3
+    let raw_post = db1.new_post("".to_string())?;
+    let signature = node1.sign(&raw_post.get_id().raw);
+    let out_post = db1.receive(&IncomingPost::new(&raw_post, &vec![], &signature, &node1, MAX_CLOCK_SKEW)?)?.remove(0);
+    let in_post = IncomingPost::new(&out_post.post, &out_post.history, &out_post.signature, &node2, MAX_CLOCK_SKEW)?;
+    let out_post = db2.receive(&in_post)?.remove(0);
+    db3.receive(&IncomingPost::new(&out_post.post, &out_post.history, &out_post.signature, &node3, MAX_CLOCK_SKEW)?)?;
+
+    // Which is all node1 needs to trust node3
+    let blessing = db3.candidate_blessing(&node1.node)?.ok_or("no blessing for node1")?;
+    assert_eq!(blessing.recipient, node1.node);
+    db1.check_blessing(blessing, &node3.node)?;
+    assert!(db1.is_trusted(&node3.node)?);
+
+    Ok(())
+}
@@ -1,3 +1,4 @@
 
 mod misc;
-pub mod db;
\ No newline at end of file
+pub mod db;
+pub mod crypto;
\ No newline at end of file
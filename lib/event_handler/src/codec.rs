@@ -0,0 +1,162 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::str::FromStr;
+use crate::pipe::NetworkEventError;
+
+/*
+    How a NetworkEvent is turned into the payload of a frame.
+
+    Every payload starts with the id of the codec that wrote it, so the
+    receiving side never has to guess. Json is easy to read when debugging,
+    postcard is what you want in production (posts with long histories
+    and base58 signatures are a lot smaller).
+
+    Events are externally tagged so postcard can read them, but json keeps
+    the {"type": ..., "data": ...} layout it always had on the wire.
+*/
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Json,
+    Postcard,
+}
+
+impl Codec {
+    pub const SUPPORTED: [Codec; 2] = [Codec::Postcard, Codec::Json];
+
+    pub fn id(&self) -> u8 {
+        match self {
+            Codec::Json => 0,
+            Codec::Postcard => 1,
+        }
+    }
+
+    // What the hello exchange calls it, same as serde would
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Json => "Json",
+            Codec::Postcard => "Postcard",
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Codec::Json),
+            1 => Some(Codec::Postcard),
+            _ => None,
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, event: &T) -> Result<Vec<u8>, NetworkEventError> {
+        let body = match self {
+            Codec::Json => {
+                let value = serde_json::to_value(event).map_err(NetworkEventError::Json)?;
+                serde_json::to_vec(&tag(value)).map_err(NetworkEventError::Json)?
+            },
+            Codec::Postcard => postcard::to_allocvec(event).map_err(NetworkEventError::Postcard)?,
+        };
+        Ok([&[self.id()][..], &body].concat())
+    }
+
+    /// Decodes a payload with whichever codec the sender used.
+    pub fn decode<T: for<'de> Deserialize<'de>>(payload: &[u8]) -> Result<T, NetworkEventError> {
+        let (id, body) = payload.split_first().ok_or(NetworkEventError::IncompleteData)?;
+        let codec = Codec::from_id(*id).ok_or(NetworkEventError::UnknownCodec(*id))?;
+
+        match codec {
+            Codec::Json => {
+                let value = serde_json::from_slice(body).map_err(NetworkEventError::Json)?;
+                serde_json::from_value(untag(value)).map_err(NetworkEventError::Json)
+            },
+            Codec::Postcard => postcard::from_bytes(body).map_err(NetworkEventError::Postcard),
+        }
+    }
+}
+
+// {"Variant": data} -> {"type": "Variant", "data": data}, and "Variant" -> {"type": "Variant"}
+fn tag(value: Value) -> Value {
+    match value {
+        Value::Object(map) if map.len() == 1 => {
+            let (variant, data) = map.into_iter().next().unwrap();
+            json!({ "type": variant, "data": data })
+        },
+        Value::String(variant) => json!({ "type": variant }),
+        other => other
+    }
+}
+
+fn untag(value: Value) -> Value {
+    let Value::Object(mut map) = value else { return value };
+    match (map.remove("type"), map.remove("data")) {
+        (Some(Value::String(variant)), Some(data)) if map.is_empty() => json!({ variant: data }),
+        (Some(Value::String(variant)), None) if map.is_empty() => Value::String(variant),
+        (variant, data) => {
+            // Not something we tagged, leave it for serde to complain about
+            map.extend(variant.map(|variant| ("type".to_string(), variant)));
+            map.extend(data.map(|data| ("data".to_string(), data)));
+            Value::Object(map)
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Codec::Json),
+            "postcard" => Ok(Codec::Postcard),
+            _ => Err(format!("Unknown codec {:?} (expected json or postcard)", s)),
+        }
+    }
+}
+
+#[test]
+fn test_json_layout() -> Result<(), NetworkEventError> {
+    use crate::handlers::{NetworkEvent, ping::Ping};
+
+    let payload = Codec::Json.encode(&NetworkEvent::Ping(Ping{}))?;
+    assert_eq!(payload[0], Codec::Json.id());
+    let body: Value = serde_json::from_slice(&payload[1..]).map_err(NetworkEventError::Json)?;
+    assert_eq!(body, json!({ "type": "Ping", "data": {} }));
+
+    // Same as what peers have always sent
+    let payload = [&[Codec::Json.id()][..], br#"{"type":"Ping","data":{}}"#].concat();
+    assert!(matches!(Codec::decode::<NetworkEvent>(&payload)?, NetworkEvent::Ping(_)));
+
+    Ok(())
+}
+
+#[test]
+fn test_round_trip() -> Result<(), NetworkEventError> {
+    use crate::handlers::{NetworkEvent, error::{Error, ErrorCode}};
+
+    for codec in Codec::SUPPORTED {
+        let event = NetworkEvent::Error(Error::new(ErrorCode::PeerLimit, "full\nsorry").retry_after(60));
+        let payload = codec.encode(&event)?;
+        assert_eq!(Codec::from_id(payload[0]), Some(codec));
+
+        // Whoever reads it doesn't need to know what the sender picked
+        match Codec::decode::<NetworkEvent>(&payload)? {
+            NetworkEvent::Error(error) => {
+                assert_eq!((error.code, error.message.as_str(), error.retry_after), (ErrorCode::PeerLimit, "full\nsorry", Some(60)));
+            },
+            other => panic!("Expected an error, got {:?}", other)
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_bad_payloads() {
+    use crate::handlers::NetworkEvent;
+
+    assert!(matches!(Codec::decode::<NetworkEvent>(&[]), Err(NetworkEventError::IncompleteData)));
+    assert!(matches!(Codec::decode::<NetworkEvent>(&[7, 0]), Err(NetworkEventError::UnknownCodec(7))));
+    assert!(matches!(Codec::decode::<NetworkEvent>(&[Codec::Json.id(), b'{']), Err(NetworkEventError::Json(_))));
+    assert!(matches!(Codec::decode::<NetworkEvent>(&[Codec::Postcard.id(), 200]), Err(NetworkEventError::Postcard(_))));
+
+    assert_eq!("PostCard".parse::<Codec>(), Ok(Codec::Postcard));
+    assert!("bincode".parse::<Codec>().is_err());
+}
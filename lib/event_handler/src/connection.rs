@@ -1,26 +1,156 @@
 use crate::handlers::NetworkEvent;
 use crate::pipe::{NetworkEventError, Pipe};
 use crate::handlers::Handle;
-use std::sync::Arc;
+use crate::handlers::hello::{Hello, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
+use crate::handlers::error::{Error, ErrorCode};
+use crate::handlers::close_request::CloseRequest;
+use crate::codec::Codec;
 use log::{info, warn};
+use tokio::sync::watch;
 
 pub struct ConnectionLogic {
-    pub pipe: Pipe<NetworkEvent>
+    pub pipe: Pipe<NetworkEvent>,
+    greeted: bool, // Whether the hello exchange already happened
+    pub requested: bool, // Whether we opened this exchange with a request, so errors are answers to it
+    pub refused: Option<ErrorCode>, // How the other side answered that request, if they turned it down
+    shutdown: Option<watch::Receiver<bool>> // Flips to true when the node is going down
 }
 
 impl ConnectionLogic {
     pub fn new(pipe: Pipe<NetworkEvent>) -> Self {
-        ConnectionLogic { pipe }
+        ConnectionLogic { pipe, greeted: false, requested: false, refused: None, shutdown: None }
     }
 
-    pub async fn handle(&mut self) {
+    /// Politely closes the exchange once the node starts shutting down
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Dialing side of the hello exchange
+    pub async fn greet(&mut self, preferred: Codec) -> Result<(), NetworkEventError> {
+        self.pipe.send(NetworkEvent::Hello(Hello::new(preferred))).await?;
+
+        let ack = match self.pipe.receive().await? {
+            NetworkEvent::HelloAck(ack) => ack,
+            NetworkEvent::Error(error) => {
+                self.pipe.close().await;
+                return Err(NetworkEventError::Handshake(error.message));
+            },
+            other => {
+                self.pipe.close().await;
+                return Err(NetworkEventError::Handshake(format!("Expected a hello ack, got {:?}", other)));
+            }
+        };
+
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&ack.version) {
+            self.pipe.close().await;
+            return Err(NetworkEventError::Handshake(format!("Peer answered with protocol version {}", ack.version)));
+        }
+
+        let Ok(codec) = ack.codec.parse() else {
+            self.pipe.close().await;
+            return Err(NetworkEventError::Handshake(format!("Peer picked a codec we never offered: {}", ack.codec)));
+        };
+
+        self.pipe.codec = codec;
+        self.pipe.features = ack.features;
+        self.pipe.version = ack.version;
+        self.greeted = true;
+        Ok(())
+    }
+
+    /// Accepting side of the hello exchange
+    pub async fn welcome(&mut self) -> Result<(), NetworkEventError> {
+        let hello = match self.pipe.receive().await {
+            Ok(NetworkEvent::Hello(hello)) => hello,
+            Ok(other) => {
+                let reason = format!("Expected a hello, got {:?}", other);
+                self.reject(&reason).await;
+                return Err(NetworkEventError::Handshake(reason));
+            },
+            // Whatever they sent arrived, we just can't make sense of it
+            Err(e @ (NetworkEventError::Json(_) | NetworkEventError::Postcard(_) | NetworkEventError::UnknownCodec(_) | NetworkEventError::FrameTooLarge(_))) => {
+                let reason = format!("Could not read the hello: {}", e);
+                self.reject(&reason).await;
+                return Err(NetworkEventError::Handshake(reason));
+            },
+            Err(e) => return Err(e)
+        };
+
+        let ack = match hello.answer() {
+            Ok(ack) => ack,
+            Err(reason) => {
+                self.reject(&reason).await;
+                return Err(NetworkEventError::Handshake(reason));
+            }
+        };
+
+        let (codec, features, version) = (ack.codec.parse().map_err(NetworkEventError::Handshake)?, ack.features.clone(), ack.version);
+        self.pipe.send(NetworkEvent::HelloAck(ack)).await?; // Still in json
+
+        self.pipe.codec = codec;
+        self.pipe.features = features;
+        self.pipe.version = version;
+        self.greeted = true;
+        Ok(())
+    }
+
+    /// Picks up what was agreed on an earlier stream of the same connection
+    pub fn resume(&mut self, codec: Codec, features: Vec<String>, version: u32) {
+        self.pipe.codec = codec;
+        self.pipe.features = features;
+        self.pipe.version = version;
+        self.greeted = true;
+    }
+
+    /// Opens the exchange with something we want from the other side
+    pub async fn request(&mut self, event: NetworkEvent) -> Result<(), NetworkEventError> {
+        self.pipe.send(event).await?;
+        self.requested = true;
+        Ok(())
+    }
+
+    // The connection is dropped right after, so make sure the error made it over first
+    async fn reject(&mut self, reason: &str) {
+        if let Err(e) = self.pipe.send(NetworkEvent::Error(Error::new(ErrorCode::Protocol, reason))).await {
+            warn!("Could not tell {:?} why we hung up: {:?}", self.pipe.public, e);
+        }
+        self.pipe.linger().await;
+    }
+
+    /// Runs the exchange until either side closes it, Ok only if the other side saw it through
+    pub async fn handle(&mut self) -> Result<(), NetworkEventError> {
         let outcome;
+
+        if !self.greeted {
+            if let Err(e) = self.welcome().await {
+                warn!("Handshake with {:?} failed: {:?}", self.pipe.public, e);
+                return Err(e);
+            }
+        }
+
+        let mut closing = false;
         loop {
-            let response = self.pipe.receive().await;
+            let response = tokio::select! {
+                response = self.pipe.receive() => Some(response),
+                _ = shutdown_requested(&mut self.shutdown), if !closing => None
+            };
+
+            // Ask the other side to wrap up, and keep reading until they confirm
+            let Some(response) = response else {
+                closing = true;
+                if let Err(e) = self.pipe.send(NetworkEvent::CloseRequest(CloseRequest{})).await {
+                    outcome = Err(e); break;
+                }
+                continue;
+            };
 
             match response {
                 Ok(response) => {
-                    response.action(self).await;
+                    if let Err(e) = response.action(self).await {
+                        outcome = Err(e); break;
+                    }
 
                     // Special commands that require stop
                     match response {
@@ -35,16 +165,71 @@ impl ConnectionLogic {
             }
         }
 
-        match outcome {
+        match &outcome {
             Ok(_r) => info!("Connection with {:?} safely stopped", self.pipe.public),
-            Err(e) => {
-                let e = Box::new(e);
-                warn!("Connection stopped {:?} with error {:?}", self.pipe.public, e);
-            }
+            Err(e) => warn!("Connection stopped {:?} with error {:?}", self.pipe.public, e)
         };
+        outcome
+    }
+}
+
+async fn shutdown_requested(shutdown: &mut Option<watch::Receiver<bool>>) {
+    match shutdown {
+        Some(shutdown) => { let _ = shutdown.wait_for(|stop| *stop).await; },
+        None => std::future::pending().await
+    }
+}
+
+#[tokio::test]
+async fn test_reject() -> Result<(), Box<dyn std::error::Error>> {
+    use std::sync::Arc;
+    use config::db::NodeDB;
+    use crate::pipe::TestPipes;
+
+    let db1 = Arc::new(NodeDB::new(tempfile::TempDir::new()?, None)?);
+    let db2 = Arc::new(NodeDB::new(tempfile::TempDir::new()?, None)?);
+    let mut pipes = TestPipes::new(db1, db2).await?;
 
+    let mut hello = Hello::new(Codec::Postcard);
+    hello.version = MIN_PROTOCOL_VERSION - 1;
+    pipes.dialer.send(NetworkEvent::Hello(hello)).await?;
+
+    // Hangs up on the whole connection, the reason still arrives
+    let mut accepter = ConnectionLogic::new(pipes.accepter);
+    assert!(matches!(accepter.welcome().await, Err(NetworkEventError::Handshake(_))));
+    drop(accepter);
+
+    match pipes.dialer.receive().await? {
+        NetworkEvent::Error(error) => assert_eq!(error.code, ErrorCode::Protocol),
+        other => panic!("Expected an error, got {:?}", other)
     }
 
+    Ok(())
+}
 
+#[tokio::test]
+async fn test_reject_unreadable() -> Result<(), Box<dyn std::error::Error>> {
+    use std::sync::Arc;
+    use config::db::NodeDB;
+    use crate::pipe::TestPipes;
+    use crate::frame;
 
-}
\ No newline at end of file
+    let db1 = Arc::new(NodeDB::new(tempfile::TempDir::new()?, None)?);
+    let db2 = Arc::new(NodeDB::new(tempfile::TempDir::new()?, None)?);
+    let mut pipes = TestPipes::new(db1, db2).await?;
+
+    // A hello from some node that changed what a version looks like
+    let payload = [&[Codec::Json.id()][..], br#"{"type":"Hello","data":{"version":"two","codecs":[],"features":[]}}"#].concat();
+    pipes.dialer.send.write_all(&frame::encode(&payload)?).await?;
+
+    let mut accepter = ConnectionLogic::new(pipes.accepter);
+    assert!(matches!(accepter.welcome().await, Err(NetworkEventError::Handshake(_))));
+    drop(accepter);
+
+    match pipes.dialer.receive().await? {
+        NetworkEvent::Error(error) => assert_eq!(error.code, ErrorCode::Protocol),
+        other => panic!("Expected an error, got {:?}", other)
+    }
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use crate::pipe::NetworkEventError;
+
+/*
+    Every message on the wire is a frame:
+
+        [ length: u32, big endian ][ payload: length bytes ]
+
+    The payload is opaque to this layer, so binary or multi-line content
+    is never cut short like it was with newline delimited json.
+*/
+
+pub const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024; // 4MiB, way more than any sane post
+const LENGTH_PREFIX: usize = 4;
+
+pub fn encode(payload: &[u8]) -> Result<Vec<u8>, NetworkEventError> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(NetworkEventError::FrameTooLarge(payload.len()));
+    }
+
+    let length = (payload.len() as u32).to_be_bytes();
+    Ok([&length[..], payload].concat())
+}
+
+/// Takes the first complete frame out of the buffer, leaving any trailing
+/// bytes in place for the next call. Returns None if a frame has not fully arrived yet.
+pub fn decode(buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, NetworkEventError> {
+    if buffer.len() < LENGTH_PREFIX {
+        return Ok(None);
+    }
+
+    let length: [u8; LENGTH_PREFIX] = buffer[..LENGTH_PREFIX].try_into().unwrap();
+    let length = u32::from_be_bytes(length) as usize;
+
+    if length > MAX_FRAME_SIZE {
+        return Err(NetworkEventError::FrameTooLarge(length));
+    }
+
+    if buffer.len() < LENGTH_PREFIX + length {
+        return Ok(None);
+    }
+
+    let rest = buffer.split_off(LENGTH_PREFIX + length);
+    let frame = buffer.split_off(LENGTH_PREFIX);
+    *buffer = rest;
+
+    Ok(Some(frame))
+}
+
+#[test]
+fn test_round_trip() -> Result<(), NetworkEventError> {
+    let mut buffer = encode(b"hello")?;
+    assert_eq!(decode(&mut buffer)?, Some(b"hello".to_vec()));
+    assert!(buffer.is_empty());
+
+    // Nothing is a valid payload too
+    let mut buffer = encode(b"")?;
+    assert_eq!(decode(&mut buffer)?, Some(vec![]));
+
+    Ok(())
+}
+
+#[test]
+fn test_partial_read() -> Result<(), NetworkEventError> {
+    let frame = encode(b"hello")?;
+
+    // Not even the whole length yet, then everything but the last byte
+    let mut buffer = frame[..2].to_vec();
+    assert_eq!(decode(&mut buffer)?, None);
+    buffer.extend_from_slice(&frame[2..frame.len() - 1]);
+    assert_eq!(decode(&mut buffer)?, None);
+
+    buffer.push(frame[frame.len() - 1]);
+    assert_eq!(decode(&mut buffer)?, Some(b"hello".to_vec()));
+
+    Ok(())
+}
+
+#[test]
+fn test_leftover_bytes() -> Result<(), NetworkEventError> {
+    let mut buffer = [encode(b"first")?, encode(b"second")?, encode(b"third")?[..3].to_vec()].concat();
+
+    assert_eq!(decode(&mut buffer)?, Some(b"first".to_vec()));
+    assert_eq!(decode(&mut buffer)?, Some(b"second".to_vec()));
+    assert_eq!(decode(&mut buffer)?, None);
+    assert_eq!(buffer.len(), 3);
+
+    Ok(())
+}
+
+#[test]
+fn test_oversize() -> Result<(), NetworkEventError> {
+    assert_eq!(encode(&vec![0; MAX_FRAME_SIZE])?.len(), MAX_FRAME_SIZE + LENGTH_PREFIX);
+    assert!(matches!(encode(&vec![0; MAX_FRAME_SIZE + 1]), Err(NetworkEventError::FrameTooLarge(_))));
+
+    // Refused as soon as the length arrives, before buffering any of it
+    let mut buffer = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes().to_vec();
+    assert!(matches!(decode(&mut buffer), Err(NetworkEventError::FrameTooLarge(_))));
+
+    Ok(())
+}
@@ -2,12 +2,14 @@ use serde::{Serialize, Deserialize};
 
 use crate::handlers::{Handle, NetworkEvent, close_response::CloseResponse};
 use crate::connection::ConnectionLogic;
+use crate::pipe::NetworkEventError;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CloseRequest {}
 impl Handle for CloseRequest {
-    async fn action(&self, connection: &mut ConnectionLogic) {
-        connection.pipe.send(NetworkEvent::CloseResponse(CloseResponse{})).await;
-        connection.pipe.wait_for_close().await;
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        connection.pipe.send(NetworkEvent::CloseResponse(CloseResponse{})).await?;
+        connection.pipe.close().await;
+        Ok(())
     }
 }
\ No newline at end of file
@@ -2,11 +2,13 @@ use serde::{Serialize, Deserialize};
 
 use crate::handlers::Handle;
 use crate::connection::ConnectionLogic;
+use crate::pipe::NetworkEventError;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CloseResponse {}
 impl Handle for CloseResponse {
-    async fn action(&self, connection: &mut ConnectionLogic) {
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
         connection.pipe.close().await;
+        Ok(())
     }
 }
\ No newline at end of file
@@ -1,16 +1,147 @@
 use serde::{Serialize, Deserialize};
+use log::{info, warn};
 
-use crate::handlers::{Handle, NetworkEvent};
+use config::db::{Node, Error as DbError};
+use config::db::score::Score;
+use config::db::trust::Trust;
+
+use crate::handlers::Handle;
 use crate::connection::ConnectionLogic;
+use crate::pipe::NetworkEventError;
+
+/*
+    Tells the other side why we refused something, usually followed by a CloseRequest.
+    The code is for the program, the message is for whoever reads the logs.
+*/
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Protocol,       // Unexpected event, or the hello exchange went wrong
+    DuplicatePost,
+    InvalidPost,    // Bad signature or history, or a message we can't read
+    Untrusted,      // We only do that for peers we trust
+    InvalidTrust,   // Trust request we could not verify
+    PeerLimit,
+    Internal        // Our own fault, try again later
+}
+
+impl ErrorCode {
+    // How much a peer we trust sinks when they turn down something we asked for.
+    // Telling us we got something wrong is doing us a favour, that costs them nothing.
+    fn penalty(&self) -> usize {
+        match self {
+            ErrorCode::Protocol | ErrorCode::DuplicatePost | ErrorCode::InvalidPost
+                | ErrorCode::InvalidTrust | ErrorCode::Internal => 0,
+            ErrorCode::PeerLimit => 4,
+            ErrorCode::Untrusted => 8
+        }
+    }
+}
+
+impl From<&DbError> for ErrorCode {
+    fn from(e: &DbError) -> Self {
+        match e {
+            DbError::Storage(_) | DbError::Encoding(_) | DbError::Locked | DbError::WrongPassphrase
+                | DbError::IdentityExists | DbError::Unprotected | DbError::InvalidBackup
+                | DbError::RecoveringFeed => ErrorCode::Internal,
+            DbError::InvalidRotation => ErrorCode::InvalidTrust,
+            DbError::DuplicatePost | DbError::ExpiredPost => ErrorCode::DuplicatePost,
+            DbError::PostNotFound | DbError::AmbiguousPost | DbError::BrokenHistory
+                | DbError::MisdirectedPost | DbError::InvalidSignature | DbError::OwnPost
+                | DbError::FuturePost | DbError::UnsupportedPost(_) | DbError::MalformedPost | DbError::RotatedKey | DbError::BrokenFeed
+                | DbError::ForkedFeed | DbError::InvalidKey | DbError::MisdirectedMessage
+                | DbError::UndecryptableMessage => ErrorCode::InvalidPost,
+            DbError::UntrustedPeer | DbError::NotBootstrap => ErrorCode::Untrusted,
+            DbError::PeerLimitReached | DbError::MinimumPeers => ErrorCode::PeerLimit,
+            DbError::AlreadyTrusted | DbError::UntrustedIntermediate | DbError::InsufficientHistory
+                | DbError::SelfTrust | DbError::WrongRecipient | DbError::IntermediateIsUs
+                | DbError::UnknownPost | DbError::UnsharedPost | DbError::UnsolicitedResponse => ErrorCode::InvalidTrust
+        }
+    }
+}
+
+// Storage hiccups usually sort themselves out
+const INTERNAL_RETRY_AFTER: u64 = 60;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Error {
-    message: String // 'we want to close this connection because reasons'
+    pub code: ErrorCode,
+    pub message: String, // 'we want to close this connection because reasons'
+    pub retry_after: Option<u64> // Seconds, if it is worth trying again at all
+}
+
+impl Error {
+    pub fn new(code: ErrorCode, message: &str) -> Self {
+        Error { code, message: message.to_string(), retry_after: None }
+    }
+
+    pub fn retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+}
+
+impl From<&DbError> for Error {
+    fn from(e: &DbError) -> Self {
+        let error = Error::new(ErrorCode::from(e), &e.to_string());
+        match error.code {
+            ErrorCode::Internal => error.retry_after(INTERNAL_RETRY_AFTER),
+            _ => error
+        }
+    }
 }
 
 impl Handle for Error {
-    async fn action(&self, connection: &mut ConnectionLogic) {
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        warn!("{:?} reported an error ({:?}): {}", connection.pipe.public, self.code, self.message);
+        if let Some(seconds) = self.retry_after {
+            info!("{:?} asked us to retry in {}s", connection.pipe.public, seconds);
+        }
+
+        if connection.requested {
+            connection.refused = Some(self.code);
+        }
+
+        // Anyone can send us errors, only answers from peers we trust to requests we sent count
+        let reporter = Node::new(*connection.pipe.public.as_bytes());
+        if !connection.requested || !connection.pipe.db.is_trusted(&reporter).unwrap_or(false) {
+            return Ok(());
+        }
 
-        
+        if let Err(e) = connection.pipe.db.penalize(&reporter, self.code.penalty()) {
+            warn!("Could not update the score of {:?}: {}", connection.pipe.public, e);
+        }
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_error_scoring() -> Result<(), Box<dyn std::error::Error>> {
+    use std::sync::Arc;
+    use config::db::NodeDB;
+    use config::db::identity::Identity;
+    use crate::handlers::{NetworkEvent, ping::Ping};
+    use crate::pipe::TestPipes;
+
+    let db1 = Arc::new(NodeDB::new(tempfile::TempDir::new()?, None)?);
+    let db2 = Arc::new(NodeDB::new(tempfile::TempDir::new()?, None)?);
+    let node2 = db2.get_identity()?.node;
+    let pipes = TestPipes::new(db1.clone(), db2).await?;
+    let mut connection = ConnectionLogic::new(pipes.dialer);
+
+    // Out of the blue, or from someone we don't trust, is only logged
+    Error::new(ErrorCode::Untrusted, "").action(&mut connection).await?;
+    connection.request(NetworkEvent::Ping(Ping{})).await?;
+    Error::new(ErrorCode::Untrusted, "").action(&mut connection).await?;
+    assert_eq!(db1.get_score(&node2, 1200)?, 1200);
+    assert_eq!(connection.refused, Some(ErrorCode::Untrusted));
+
+    // A peer we trust turning us down costs them, telling us we were wrong does not
+    db1.trust(&node2)?;
+    Error::new(ErrorCode::InvalidPost, "").action(&mut connection).await?;
+    assert_eq!(db1.get_score(&node2, 1200)?, 1200);
+    Error::new(ErrorCode::Untrusted, "").action(&mut connection).await?;
+    assert_eq!(db1.get_score(&node2, 1200)?, 1200 - ErrorCode::Untrusted.penalty());
+
+    Ok(())
+}
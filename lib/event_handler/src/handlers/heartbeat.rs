@@ -2,14 +2,15 @@ use serde::{Serialize, Deserialize};
 
 use crate::handlers::{Handle, NetworkEvent};
 use crate::connection::ConnectionLogic;
-use std::{thread, time};
+use crate::pipe::NetworkEventError;
+use tokio::time::{sleep, Duration};
 
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Heartbeat {}
 impl Handle for Heartbeat {
-    async fn action(&self, connection: &mut ConnectionLogic) {
-        thread::sleep(time::Duration::from_secs(1));
-        connection.pipe.send(NetworkEvent::Heartbeat(Heartbeat{})).await;
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        sleep(Duration::from_secs(1)).await;
+        connection.pipe.send(NetworkEvent::Heartbeat(Heartbeat{})).await
     }
 }
\ No newline at end of file
@@ -0,0 +1,136 @@
+use serde::{Serialize, Deserialize};
+use log::warn;
+
+use crate::handlers::{Handle, NetworkEvent, error::{Error, ErrorCode}, sync, peer, message, rotation};
+use crate::connection::ConnectionLogic;
+use crate::pipe::NetworkEventError;
+use crate::codec::Codec;
+use config::db::RawPost;
+
+/*
+    The first thing sent on every connection, always in json so
+    both sides can read it no matter what they prefer.
+
+    Dialer  -> Hello    { version, codecs we can speak (best first), features }
+    Accepter -> HelloAck { version, codec to use from now on, shared features }
+
+    Both sides speak the lower of the two versions, as long as it is one
+    we still support. Codecs go by name, so a hello listing one we never
+    heard of still reads fine and we just skip it. If the versions don't overlap, there is
+    no codec in common or the hello makes no sense at all, the accepter
+    replies with an Error and closes the connection instead.
+*/
+
+// 2: posts carry a version and the time their author signed, older nodes can't read them
+pub const PROTOCOL_VERSION: u32 = 2;
+// 1 only gets version 0 posts, and only in json since postcard can't leave out the newer post fields
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+pub const FEATURES: &[&str] = &[sync::FEATURE, sync::FEED_FEATURE, peer::SECONDARY_PEERS_FEATURE, message::FEATURE, rotation::FEATURE];
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Hello {
+    pub version: u32,
+    pub codecs: Vec<String>, // Codec names
+    pub features: Vec<String>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HelloAck {
+    pub version: u32,
+    pub codec: String,
+    pub features: Vec<String>
+}
+
+impl Hello {
+    pub fn new(preferred: Codec) -> Self {
+        let mut codecs = vec![preferred];
+        codecs.extend(Codec::SUPPORTED.iter().filter(|codec| **codec != preferred));
+
+        Hello {
+            version: PROTOCOL_VERSION,
+            codecs: codecs.iter().map(|codec| codec.name().to_string()).collect(),
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect()
+        }
+    }
+
+    /// What we would answer with, or why we can't talk to this peer
+    pub fn answer(&self) -> Result<HelloAck, String> {
+        if self.version < MIN_PROTOCOL_VERSION {
+            return Err(format!("Unsupported protocol version {} (we speak {} to {})", self.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION));
+        }
+        let version = self.version.min(PROTOCOL_VERSION);
+
+        let codec = self.codecs.iter().filter_map(|name| name.parse::<Codec>().ok())
+            .find(|codec| version >= 2 || *codec == Codec::Json)
+            .ok_or("No codec in common")?;
+
+        let features = self.features.iter()
+            .filter(|feature| FEATURES.contains(&feature.as_str()))
+            .cloned()
+            .collect();
+
+        Ok(HelloAck {
+            version,
+            codec: codec.name().to_string(),
+            features
+        })
+    }
+}
+
+/// Whether a peer that agreed on `version` can check the post's signature
+pub fn readable_at(version: u32, post: &RawPost) -> bool {
+    version >= 2 || post.version == 0
+}
+
+impl Handle for Hello {
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        // Only valid as the very first event, which ConnectionLogic::welcome takes care of
+        warn!("{:?} sent a hello after the connection was already set up", connection.pipe.public);
+        connection.pipe.send(NetworkEvent::Error(Error::new(ErrorCode::Protocol, "Unexpected hello"))).await
+    }
+}
+
+impl Handle for HelloAck {
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        warn!("{:?} sent a hello ack we did not ask for", connection.pipe.public);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_answer() {
+    // Whatever the dialer prefers, as long as we speak it too
+    let ack = Hello::new(Codec::Json).answer().unwrap();
+    assert_eq!((ack.version, ack.codec.as_str()), (PROTOCOL_VERSION, "Json"));
+    assert_eq!(ack.features.len(), FEATURES.len());
+
+    let mut hello = Hello::new(Codec::Postcard);
+    hello.version = MIN_PROTOCOL_VERSION - 1;
+    assert!(hello.answer().is_err());
+
+    // Newer nodes come down to us, older ones get json
+    let mut hello = Hello::new(Codec::Postcard);
+    hello.version = PROTOCOL_VERSION + 1;
+    assert_eq!(hello.answer().unwrap().version, PROTOCOL_VERSION);
+
+    hello.version = 1;
+    let ack = hello.answer().unwrap();
+    assert_eq!((ack.version, ack.codec.as_str()), (1, "Json"));
+
+    let mut hello = Hello::new(Codec::Postcard);
+    hello.codecs = vec![];
+    assert!(hello.answer().is_err());
+
+    // Codecs from newer nodes are skipped
+    let mut hello = Hello::new(Codec::Postcard);
+    hello.codecs.insert(0, "zstd-postcard".to_string());
+    assert_eq!(hello.answer().unwrap().codec, "Postcard");
+
+    hello.codecs = vec!["zstd-postcard".to_string()];
+    assert!(hello.answer().is_err());
+
+    // Only what both sides know about
+    let mut hello = Hello::new(Codec::Postcard);
+    hello.features = vec![sync::FEATURE.to_string(), "telepathy".to_string()];
+    assert_eq!(hello.answer().unwrap().features, vec![sync::FEATURE.to_string()]);
+}
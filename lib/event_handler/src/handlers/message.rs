@@ -0,0 +1,33 @@
+use serde::{Serialize, Deserialize};
+use log::{info, warn};
+
+use config::db::direct_message::{DirectMessages, DirectMessage as SealedMessage};
+
+use crate::handlers::{Handle, NetworkEvent, close_request::CloseRequest, error::Error};
+use crate::connection::ConnectionLogic;
+use crate::pipe::NetworkEventError;
+
+pub const FEATURE: &str = "direct-messages";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DirectMessage {
+    pub data: SealedMessage
+}
+
+impl Handle for DirectMessage {
+    /*
+        A peer sent us a message only we can read, it stays with us.
+     */
+
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        match connection.pipe.db.receive_message(&self.data) {
+            Ok(_message) => info!("New direct message from {:?}", connection.pipe.public),
+            Err(e) => {
+                warn!("Rejected direct message due to: {}", e);
+                connection.pipe.send(NetworkEvent::Error(Error::from(&e))).await?;
+            }
+        }
+
+        connection.pipe.send(NetworkEvent::CloseRequest(CloseRequest{})).await
+    }
+}
@@ -1,25 +1,46 @@
 use serde::{Serialize, Deserialize};
 
 use crate::connection::ConnectionLogic;
+use crate::pipe::NetworkEventError;
 pub mod ping;
 pub mod pong;
 pub mod close_request;
 pub mod close_response;
 pub mod heartbeat;
 pub mod peer;
+pub mod hello;
+pub mod error;
+pub mod sync;
+pub mod message;
+pub mod rotation;
 
 
 pub trait Handle {
     #![allow(async_fn_in_trait)]
-    async fn action(&self, connection: &mut ConnectionLogic);
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError>;
 }
 
+// Externally tagged on purpose, postcard is not self describing and cannot
+// deserialize internally or adjacently tagged enums (the json codec adds the tag back)
 #[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "type", content = "data")]
 pub enum NetworkEvent {
+    Hello(hello::Hello),
+    HelloAck(hello::HelloAck),
+    Error(error::Error),
     Ping(ping::Ping),
     Pong(pong::Pong),
     Post(peer::Post),
+    TrustRequest(peer::TrustRequest),
+    TrustResponse(peer::TrustResponse),
+    TrustBootstrap(peer::TrustBootstrap),
+    SecondaryPeerRequest(peer::SecondaryPeerRequest),
+    SecondaryPeerResponse(peer::SecondaryPeerResponse),
+    PostRequest(sync::PostRequest),
+    PostResponse(sync::PostResponse),
+    FeedRequest(sync::FeedRequest),
+    FeedResponse(sync::FeedResponse),
+    DirectMessage(message::DirectMessage),
+    KeyRotation(rotation::KeyRotation),
     Heartbeat(heartbeat::Heartbeat),
     CloseRequest(close_request::CloseRequest),
     CloseResponse(close_response::CloseResponse),
@@ -27,15 +48,51 @@ pub enum NetworkEvent {
 
 
 impl Handle for NetworkEvent {
-    async fn action(&self, connection: &mut ConnectionLogic) {
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
         match self {
+            NetworkEvent::Hello(hello) => hello.action(connection).await,
+            NetworkEvent::HelloAck(ack) => ack.action(connection).await,
+            NetworkEvent::Error(error) => error.action(connection).await,
             NetworkEvent::Ping(ping) => ping.action(connection).await,
             NetworkEvent::Pong(pong) => pong.action(connection).await,
             NetworkEvent::Post(post) => post.action(connection).await,
+            NetworkEvent::TrustRequest(request) => request.action(connection).await,
+            NetworkEvent::TrustResponse(response) => response.action(connection).await,
+            NetworkEvent::TrustBootstrap(bootstrap) => bootstrap.action(connection).await,
+            NetworkEvent::SecondaryPeerRequest(request) => request.action(connection).await,
+            NetworkEvent::SecondaryPeerResponse(response) => response.action(connection).await,
+            NetworkEvent::PostRequest(request) => request.action(connection).await,
+            NetworkEvent::PostResponse(response) => response.action(connection).await,
+            NetworkEvent::FeedRequest(request) => request.action(connection).await,
+            NetworkEvent::FeedResponse(response) => response.action(connection).await,
+            NetworkEvent::DirectMessage(message) => message.action(connection).await,
+            NetworkEvent::KeyRotation(rotation) => rotation.action(connection).await,
             NetworkEvent::Heartbeat(heart) => heart.action(connection).await,
             NetworkEvent::CloseRequest(close) => close.action(connection).await,
             NetworkEvent::CloseResponse(close) => close.action(connection).await,
         }
 
     }
-}
\ No newline at end of file
+}
+
+impl NetworkEvent {
+    /// Optional feature the peer has to agree on in the hello exchange before we send this event
+    pub fn required_feature(&self) -> Option<&'static str> {
+        match self {
+            NetworkEvent::PostRequest(_) => Some(sync::FEATURE),
+            NetworkEvent::FeedRequest(_) => Some(sync::FEED_FEATURE),
+            NetworkEvent::DirectMessage(_) => Some(message::FEATURE),
+            NetworkEvent::KeyRotation(_) => Some(rotation::FEATURE),
+            NetworkEvent::SecondaryPeerRequest(_) => Some(peer::SECONDARY_PEERS_FEATURE),
+            _ => None
+        }
+    }
+
+    /// Whether a peer that agreed on `version` in the hello exchange can make sense of this event
+    pub fn readable_at(&self, version: u32) -> bool {
+        match self {
+            NetworkEvent::Post(post) => hello::readable_at(version, &post.data.post),
+            _ => true
+        }
+    }
+}
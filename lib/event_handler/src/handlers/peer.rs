@@ -2,34 +2,60 @@ use config::db::identity::Identity;
 use iroh::PublicKey;
 use serde::{Serialize, Deserialize};
 use std::sync::Arc;
-use crate::handlers::{Handle, NetworkEvent, close_request::CloseRequest};
+use crate::handlers::{Handle, NetworkEvent, close_request::CloseRequest, error::Error};
 use crate::connection::ConnectionLogic;
-use config::db::{IncomingPost, NodeDB, OutgoingPost};
+use crate::pipe::NetworkEventError;
+use config::db::{IncomingPost, NodeDB, OutgoingPost, Node};
+use config::db::TrustRequest as Blessing;
+use config::db::Error as DbError;
 use config::db::trust::Trust;
-use std::sync::mpsc::Sender;
+use config::db::trust_request::HandleBlessing;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::error::TrySendError;
 use log::{info, warn};
 
 use config::db::handle_post::HandlePost;
+use config::db::outbox::Outbox;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Post {
-    data: OutgoingPost
+    pub data: OutgoingPost
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TrustRequest {
-
+    pub data: Blessing
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TrustResponse {
-
+    pub accepted: bool,
+    pub reason: Option<String> // Why we were rejected
 }
 
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct TrustBootstrap {
+pub struct TrustBootstrap {}
+
+pub const SECONDARY_PEERS_FEATURE: &str = "secondary-peers";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SecondaryPeerRequest {
+    pub limit: Option<usize> // The other side caps this anyway
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SecondaryPeerResponse {
+    pub nodes: Vec<Node>
+}
 
+impl TrustResponse {
+    fn from_result(result: Result<(), DbError>) -> Self {
+        match result {
+            Ok(()) => TrustResponse { accepted: true, reason: None },
+            Err(e) => TrustResponse { accepted: false, reason: Some(e.to_string()) }
+        }
+    }
 }
 
 pub async fn share_post(post: IncomingPost, db: &Arc<NodeDB>, pusher: &Sender<(PublicKey, NetworkEvent)>) {
@@ -52,15 +78,40 @@ pub async fn share_post(post: IncomingPost, db: &Arc<NodeDB>, pusher: &Sender<(P
         }
     }
 
-    let r = db.receive(&post).unwrap();
+    let r = match db.receive(&post) {
+        Ok(r) => r,
+        // Posts we already have are expected, especially when syncing
+        Err(DbError::DuplicatePost | DbError::ExpiredPost) => {
+            info!("Already have post {}", post.post.get_id().short());
+            return;
+        },
+        Err(e) => {
+            warn!("Not sharing post: {}", e);
+            return;
+        }
+    };
 
+    // This runs inside exchanges, which hold a slot the queue might be waiting on,
+    // so never wait for room: whatever doesn't fit goes out with the next outbox retry
     for outgoing in r {
         
         let to_node = &outgoing.history.last().unwrap().to;
         let to_public = PublicKey::from_bytes(&to_node.public_key).unwrap();
         let event = NetworkEvent::Post(Post{data:outgoing});
 
-        pusher.send((to_public, event)).unwrap();
+        match pusher.try_send((to_public, event)) {
+            Ok(()) => {},
+            Err(TrySendError::Full((_to, NetworkEvent::Post(post)))) => {
+                if let Err(e) = db.defer_outgoing(&post.data) {
+                    warn!("Could not defer post for {:?}: {}", to_public, e);
+                }
+            },
+            Err(TrySendError::Full(_)) => {},
+            Err(TrySendError::Closed(_)) => {
+                warn!("Stopped sharing post, the node is shutting down");
+                return;
+            }
+        }
     }
 }
 
@@ -69,13 +120,14 @@ impl Handle for Post {
         A node sent their outgoing post to us.
      */ 
 
-    async fn action(&self, connection: &mut ConnectionLogic) {
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
         let recv_post = &self.data;
         let post = IncomingPost::new(
             &recv_post.post,
             &recv_post.history,
             &recv_post.signature,
-            &connection.pipe.db.get_identity().unwrap()
+            &connection.pipe.db.get_identity().unwrap(),
+            connection.pipe.db.settings.clock_skew
         );
 
         match post {
@@ -83,11 +135,122 @@ impl Handle for Post {
                 share_post(post, &connection.pipe.db, &connection.pipe.pusher).await;
             },
             Err(e) => {
-                warn!("Rejected post due to: {:?}", e);
+                warn!("Rejected post due to: {}", e);
+                connection.pipe.send(NetworkEvent::Error(Error::from(&e))).await?;
             }
         };
 
         
-        connection.pipe.send(NetworkEvent::CloseRequest(CloseRequest{})).await;
+        connection.pipe.send(NetworkEvent::CloseRequest(CloseRequest{})).await
+    }
+}
+
+impl Handle for TrustRequest {
+    /*
+        A node wants us to trust them, because they can prove
+        one of our peers gave them a post.
+     */
+
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        let from = Node::new(*connection.pipe.public.as_bytes());
+        let response = TrustResponse::from_result(connection.pipe.db.check_blessing(self.data.clone(), &from));
+
+        match &response.reason {
+            None => info!("Now trusting {:?} through {:?}", connection.pipe.public, self.data.intermediate),
+            Some(reason) => info!("Rejected trust request from {:?} due to: {}", connection.pipe.public, reason)
+        }
+
+        connection.pipe.send(NetworkEvent::TrustResponse(response)).await
+    }
+}
+
+impl Handle for TrustBootstrap {
+    /*
+        A new node is asking to join the network through us.
+     */
+
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        let from = Node::new(*connection.pipe.public.as_bytes());
+        let response = TrustResponse::from_result(connection.pipe.db.accept_bootstrap(&from));
+        connection.pipe.send(NetworkEvent::TrustResponse(response)).await
+    }
+}
+
+impl Handle for TrustResponse {
+    /*
+        A node answered the trust request we sent them.
+     */
+
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        let from = Node::new(*connection.pipe.public.as_bytes());
+
+        match connection.pipe.db.complete_blessing(&from, self.accepted) {
+            Ok(true) => info!("{:?} accepted our trust request", connection.pipe.public),
+            Ok(false) => info!("{:?} rejected our trust request: {:?}", connection.pipe.public, self.reason),
+            Err(e) => warn!("Ignoring trust response from {:?}: {:?}", connection.pipe.public, e)
+        }
+
+        connection.pipe.send(NetworkEvent::CloseRequest(CloseRequest{})).await
+    }
+}
+
+impl Handle for SecondaryPeerRequest {
+    /*
+        A peer wants to know who else we trust, so they can find more peers.
+     */
+
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        let from = Node::new(*connection.pipe.public.as_bytes());
+        let nodes = connection.pipe.db.sample_trusted(&from, self.limit);
+
+        match nodes {
+            Ok(nodes) => {
+                connection.pipe.send(NetworkEvent::SecondaryPeerResponse(SecondaryPeerResponse{nodes})).await?;
+            },
+            Err(e) => {
+                warn!("Refused to share peers with {:?}: {}", connection.pipe.public, e);
+                connection.pipe.send(NetworkEvent::Error(Error::from(&e))).await?;
+                connection.pipe.send(NetworkEvent::CloseRequest(CloseRequest{})).await?;
+            }
+        }
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl Handle for SecondaryPeerResponse {
+    /*
+        Peers of our peer, remember them as candidates to trust later.
+     */
+
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        let from = Node::new(*connection.pipe.public.as_bytes());
+
+        match connection.pipe.db.add_candidates(&from, &self.nodes) {
+            Ok(added) => info!("{:?} shared {} secondary peers, {} of them new", connection.pipe.public, self.nodes.len(), added),
+            Err(e) => warn!("Ignoring secondary peers from {:?}: {:?}", connection.pipe.public, e)
+        }
+
+        connection.pipe.send(NetworkEvent::CloseRequest(CloseRequest{})).await
+    }
+}
+
+#[tokio::test]
+async fn test_share_post_full_queue() -> Result<(), Box<dyn std::error::Error>> {
+    use config::db::{RawPost, Hashable, MAX_CLOCK_SKEW};
+
+    let db = Arc::new(NodeDB::new(tempfile::TempDir::new()?, None)?);
+    let us = db.get_identity()?;
+    db.trust(&db.generate_identity()?.node)?;
+    db.trust(&db.generate_identity()?.node)?;
+
+    // Room for one of the two
+    let (pusher, mut queued) = tokio::sync::mpsc::channel(1);
+    let raw_post = RawPost::new(us.node.clone(), "".to_string());
+    let post = IncomingPost::new(&raw_post, &vec![], &us.sign(&raw_post.hash()), &us, MAX_CLOCK_SKEW)?;
+    share_post(post, &db, &pusher).await;
+
+    assert!(matches!(queued.try_recv()?, (_, NetworkEvent::Post(_))));
+    assert_eq!(db.due_outgoing()?.len(), 1);
+
+    Ok(())
+}
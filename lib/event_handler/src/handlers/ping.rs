@@ -3,11 +3,12 @@ use serde::{Serialize, Deserialize};
 
 use crate::handlers::{Handle, NetworkEvent, pong::Pong};
 use crate::connection::ConnectionLogic;
+use crate::pipe::NetworkEventError;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Ping {}
 impl Handle for Ping {
-    async fn action(&self, connection: &mut ConnectionLogic) {
-        connection.pipe.send(NetworkEvent::Pong(Pong{})).await;
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        connection.pipe.send(NetworkEvent::Pong(Pong{})).await
     }
 }
\ No newline at end of file
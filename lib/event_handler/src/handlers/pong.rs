@@ -2,11 +2,12 @@ use serde::{Serialize, Deserialize};
 
 use crate::handlers::{Handle, NetworkEvent, close_request::CloseRequest};
 use crate::connection::ConnectionLogic;
+use crate::pipe::NetworkEventError;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Pong {}
 impl Handle for Pong {
-    async fn action(&self, connection: &mut ConnectionLogic) {
-        connection.pipe.send(NetworkEvent::CloseRequest(CloseRequest{})).await;
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        connection.pipe.send(NetworkEvent::CloseRequest(CloseRequest{})).await
     }
 }
\ No newline at end of file
@@ -0,0 +1,56 @@
+use iroh::PublicKey;
+use serde::{Serialize, Deserialize};
+use log::{info, warn};
+
+use config::db::Node;
+use config::db::trust::Trust;
+use config::db::rotation::{KeyRotations, KeyRotation as Succession};
+
+use crate::handlers::{Handle, NetworkEvent, close_request::CloseRequest, error::Error};
+use crate::connection::ConnectionLogic;
+use crate::pipe::NetworkEventError;
+
+pub const FEATURE: &str = "key-rotation";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeyRotation {
+    pub data: Succession
+}
+
+impl Handle for KeyRotation {
+    /*
+        Someone moved to a new key, tell our peers the first time we hear about it.
+     */
+
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        let db = &connection.pipe.db;
+
+        match db.receive_rotation(&self.data) {
+            Ok(true) => {
+                info!("{:?} rotated to {:?}", self.data.old, self.data.new);
+
+                let from = Node::new(*connection.pipe.public.as_bytes());
+                let peers = db.get_trusted().unwrap_or_default();
+                for (peer, _score) in peers {
+                    if peer == from || peer == self.data.old {
+                        continue;
+                    }
+                    let Ok(destination) = PublicKey::from_bytes(&peer.public_key) else { continue };
+                    let event = NetworkEvent::KeyRotation(KeyRotation { data: self.data.clone() });
+                    // Same as posts, don't wait for room in the queue from inside an exchange
+                    if let Err(e) = connection.pipe.pusher.try_send((destination, event)) {
+                        warn!("Stopped sharing key rotation: {}", e);
+                        break;
+                    }
+                }
+            },
+            Ok(false) => info!("Already knew {:?} rotated", self.data.old),
+            Err(e) => {
+                warn!("Rejected key rotation due to: {}", e);
+                connection.pipe.send(NetworkEvent::Error(Error::from(&e))).await?;
+            }
+        }
+
+        connection.pipe.send(NetworkEvent::CloseRequest(CloseRequest{})).await
+    }
+}
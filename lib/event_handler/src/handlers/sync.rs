@@ -0,0 +1,172 @@
+use serde::{Serialize, Deserialize};
+use log::{info, warn};
+
+use config::db::{IncomingPost, OutgoingPost, Node};
+use config::db::identity::Identity;
+use config::db::post_sync::{PostSync, SyncCursor, MAX_SYNC_PAGE};
+use config::db::feed::Feed;
+
+use crate::handlers::{Handle, NetworkEvent, close_request::CloseRequest, error::Error, peer::share_post, hello::readable_at};
+use crate::connection::ConnectionLogic;
+use crate::pipe::NetworkEventError;
+
+pub const FEATURE: &str = "post-sync";
+pub const FEED_FEATURE: &str = "feed-sync";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostRequest {
+    pub after: Option<SyncCursor>, // None to start from the very beginning
+    pub limit: usize
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostResponse {
+    pub posts: Vec<OutgoingPost>,
+    pub cursor: Option<SyncCursor>,
+    pub more: bool
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FeedRequest {
+    pub author: Node,
+    pub after: u64, // Last seq we have before the gap, 0 for the whole feed
+    pub limit: usize
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FeedResponse {
+    pub author: Node,
+    pub posts: Vec<OutgoingPost>,
+    pub last: u64,
+    pub more: bool
+}
+
+impl Handle for PostRequest {
+    /*
+        A peer wants to catch up on the posts we received while they were gone.
+     */
+
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        let from = Node::new(*connection.pipe.public.as_bytes());
+        let page = connection.pipe.db.posts_after(&from, &self.after, self.limit);
+
+        match page {
+            Ok(mut page) => {
+                page.posts.retain(|post| readable_at(connection.pipe.version, &post.post));
+                let response = PostResponse { posts: page.posts, cursor: page.cursor, more: page.more };
+                connection.pipe.send(NetworkEvent::PostResponse(response)).await?;
+            },
+            Err(e) => {
+                warn!("Refused to sync posts with {:?}: {}", connection.pipe.public, e);
+                connection.pipe.send(NetworkEvent::Error(Error::from(&e))).await?;
+                connection.pipe.send(NetworkEvent::CloseRequest(CloseRequest{})).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Handle for PostResponse {
+    /*
+        A page of posts we asked for, keep asking until we are caught up.
+     */
+
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        let from = Node::new(*connection.pipe.public.as_bytes());
+        let us = connection.pipe.db.get_identity().unwrap();
+
+        info!("Syncing {} posts from {:?}", self.posts.len(), connection.pipe.public);
+        for recv_post in &self.posts {
+            let post = IncomingPost::new(&recv_post.post, &recv_post.history, &recv_post.signature, &us, connection.pipe.db.settings.clock_skew);
+
+            match post {
+                Ok(post) => share_post(post, &connection.pipe.db, &connection.pipe.pusher).await,
+                Err(e) => warn!("Rejected synced post due to: {}", e)
+            }
+        }
+
+        // Without the cursor we would ask for the same pages again, better to stop here
+        if let Some(cursor) = &self.cursor {
+            if let Err(e) = connection.pipe.db.set_sync_cursor(&from, cursor) {
+                warn!("Could not save where we are with {:?}: {}", connection.pipe.public, e);
+                connection.pipe.send(NetworkEvent::Error(Error::from(&e))).await?;
+                connection.pipe.send(NetworkEvent::CloseRequest(CloseRequest{})).await?;
+                return Ok(());
+            }
+        }
+
+        if self.more {
+            let request = PostRequest { after: self.cursor.clone(), limit: MAX_SYNC_PAGE };
+            connection.pipe.send(NetworkEvent::PostRequest(request)).await?;
+        } else {
+            connection.pipe.send(NetworkEvent::CloseRequest(CloseRequest{})).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Handle for FeedRequest {
+    /*
+        A peer is missing part of an author's feed, send them what we have after `after`.
+     */
+
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        let from = Node::new(*connection.pipe.public.as_bytes());
+        let page = connection.pipe.db.feed_after(&from, &self.author, self.after, self.limit);
+
+        match page {
+            Ok(mut page) => {
+                page.posts.retain(|post| readable_at(connection.pipe.version, &post.post));
+                let response = FeedResponse { author: self.author.clone(), posts: page.posts, last: page.last, more: page.more };
+                connection.pipe.send(NetworkEvent::FeedResponse(response)).await?;
+            },
+            Err(e) => {
+                warn!("Refused to sync a feed with {:?}: {}", connection.pipe.public, e);
+                connection.pipe.send(NetworkEvent::Error(Error::from(&e))).await?;
+                connection.pipe.send(NetworkEvent::CloseRequest(CloseRequest{})).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Handle for FeedResponse {
+    /*
+        Part of a feed we asked for, the feed table notices the gap closing (or a fork).
+        The last page of our own feed ends a recovery after an import.
+     */
+
+    async fn action(&self, connection: &mut ConnectionLogic) -> Result<(), NetworkEventError> {
+        let us = connection.pipe.db.get_identity().unwrap();
+
+        info!("Syncing {} feed posts from {:?}", self.posts.len(), connection.pipe.public);
+        for recv_post in &self.posts {
+            // They could slip in posts by anyone, only take the feed we asked for
+            if recv_post.post.author != self.author {
+                warn!("{:?} sent a post outside of the feed we asked for", connection.pipe.public);
+                continue;
+            }
+
+            let post = IncomingPost::new(&recv_post.post, &recv_post.history, &recv_post.signature, &us, connection.pipe.db.settings.clock_skew);
+            match post {
+                Ok(post) => share_post(post, &connection.pipe.db, &connection.pipe.pusher).await,
+                Err(e) => warn!("Rejected synced feed post due to: {}", e)
+            }
+        }
+
+        if self.more {
+            let request = FeedRequest { author: self.author.clone(), after: self.last, limit: MAX_SYNC_PAGE };
+            connection.pipe.send(NetworkEvent::FeedRequest(request)).await?;
+        } else {
+            // Our own feed after an import, we know where to post next now
+            if self.author == us.node && connection.pipe.db.recovering_feed().unwrap_or(false) {
+                match connection.pipe.db.feed_recovered() {
+                    Ok(()) => info!("Recovered our feed from {:?}", connection.pipe.public),
+                    Err(e) => warn!("Could not finish recovering our feed, the next sync tries again: {}", e)
+                }
+            }
+            connection.pipe.send(NetworkEvent::CloseRequest(CloseRequest{})).await?;
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,8 @@
 
 pub mod handlers;
 pub mod pipe;
+pub mod frame;
+pub mod codec;
 pub mod connection;
 
 /*
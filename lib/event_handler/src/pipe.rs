@@ -1,15 +1,20 @@
-use tokio::io::{BufReader, AsyncReadExt};
+use tokio::io::AsyncReadExt;
 use tokio::time::{timeout, Duration};
-use iroh::{endpoint::{Connection, RecvStream, SendStream, VarInt}, PublicKey};
+use iroh::{endpoint::{Connection, RecvStream, SendStream, WriteError}, PublicKey};
 use tokio::io;
 use serde::{Deserialize, Serialize};
-use log::{info, error};
+use log::{info, warn, error};
 use config::db::NodeDB;
 use std::sync::Arc;
-use std::sync::mpsc::Sender;
+use tokio::sync::mpsc::Sender;
 use crate::handlers::NetworkEvent;
+use crate::handlers::hello::PROTOCOL_VERSION;
+use crate::frame;
+use crate::codec::Codec;
 
 
+const LINGER_SECONDS: u64 = 2;
+
 pub struct Pipe<T> {
     pub send: SendStream,
     pub recv: RecvStream,
@@ -17,18 +22,35 @@ pub struct Pipe<T> {
     pub connection: Connection,
     pub db: Arc<NodeDB>,
     pub pusher: Sender<(PublicKey, NetworkEvent)>,
+    pub codec: Codec, // Json until the hello exchange agrees on something else
+    pub features: Vec<String>, // Optional features both sides agreed on during the hello exchange
+    pub version: u32, // Protocol version agreed on during the hello exchange
+    buffer: Vec<u8>, // Bytes read past the end of the last frame
     _marker: std::marker::PhantomData<T>,
 }
 
 #[derive(Debug)]
 pub enum NetworkEventError {
     Io(io::Error),
+    Write(WriteError),
     Json(serde_json::Error),
+    Postcard(postcard::Error),
+    UnknownCodec(u8),
     IncompleteData,
+    FrameTooLarge(usize),
     Timeout,
+    Handshake(String),
     SafeClose,
 }
 
+impl std::fmt::Display for NetworkEventError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for NetworkEventError {}
+
 fn debug_bytes(bs: &[u8]) -> String {
     let mut visible = String::new();
     for &b in bs {
@@ -50,73 +72,141 @@ where
             connection,
             db,
             pusher,
+            codec: Codec::Json,
+            features: vec![],
+            version: PROTOCOL_VERSION,
+            buffer: Vec::new(),
             _marker: std::marker::PhantomData,
         }
     }
 
     
     pub async fn receive(&mut self) -> Result<T, NetworkEventError> {
-        /* 
-            NEED TO FIX: the buffer stops reading at the first new line, 
-            as it assumes that it's reading json data 
-            However, if it's binary data, then there is a high chance that
-            it will randomly stop reading and return incomplete data    
-        */
-        let mut buffer = vec![0u8; 4096];
-        let mut reader = BufReader::new(&mut self.recv);
-        let mut accumulated_data = Vec::new();
-        
+        let mut chunk = vec![0u8; 4096];
         let timeout_duration = Duration::from_secs(5);
-        
+
         loop {
-            let n = timeout(timeout_duration, reader.read(&mut buffer)).await.map_err(|_| NetworkEventError::Timeout)?.map_err(NetworkEventError::Io)?;
-            
-            if n == 0 {
-                if accumulated_data.is_empty() {
-                    return Err(NetworkEventError::IncompleteData);
-                }
-                break;
-            }
-    
-            accumulated_data.extend_from_slice(&buffer[..n]);
-    
-            if let Some(pos) = accumulated_data.iter().position(|&byte| byte == b'\n') {
-                let complete_data = &accumulated_data[..pos];
-    
-                match serde_json::from_slice::<T>(complete_data) {
+            // A previous read may have already pulled in the next frame
+            if let Some(complete_data) = frame::decode(&mut self.buffer)? {
+                return match Codec::decode::<T>(&complete_data) {
                     Ok(event) => {
                         info!("[ {} -> HOST ] Received {:?}", &self.public.to_string()[..6], event);
-                        return Ok(event);
+                        Ok(event)
                     }
                     Err(e) => {
-                        error!("Failed to deserialize JSON from {:?} due to {:?}", self.public, e);
-                        error!("Raw received data: {:?}", debug_bytes(complete_data));
+                        error!("Failed to decode event from {:?} due to {:?}", self.public, e);
+                        error!("Raw received data: {:?}", debug_bytes(&complete_data));
+                        Err(e)
                     }
-                }
-    
-                accumulated_data = accumulated_data.split_off(pos + 1);
+                };
+            }
+
+            let n = timeout(timeout_duration, AsyncReadExt::read(&mut self.recv, &mut chunk)).await.map_err(|_| NetworkEventError::Timeout)?.map_err(NetworkEventError::Io)?;
+
+            if n == 0 {
+                return Err(NetworkEventError::IncompleteData);
             }
+
+            self.buffer.extend_from_slice(&chunk[..n]);
         }
-    
-        Err(NetworkEventError::IncompleteData)
     }
-    
-    pub async fn send(&mut self, event: T) {
+
+    // Something we can't encode or that doesn't fit in a frame never goes out, the caller decides what happens next
+    pub async fn send(&mut self, event: T) -> Result<(), NetworkEventError> {
         info!("[ HOST -> {} ] Sending {:?}", &self.public.to_string()[..6], event);
-        let data = serde_json::to_string(&event).unwrap();
-        let data = data.as_bytes();
-        let data = [&data, "\n".as_bytes()].concat();
-        self.send.write(&data).await.unwrap();
+        let data = match self.codec.encode(&event).and_then(|data| frame::encode(&data)) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Dropping {:?} for {:?}: {:?}", event, self.public, e);
+                return Err(e);
+            }
+        };
+        self.send.write_all(&data).await.map_err(NetworkEventError::Write)
     }
 
+    // Only ends this stream, the connection itself may be pooled and shared with other exchanges
     pub async fn close(&mut self) {
-        self.send.finish().unwrap();
-		self.connection.close(VarInt::from_u32(200), b"Received close request");
+        self.send.finish().ok();
     }
 
-    pub async  fn wait_for_close(&mut self) {
-        self.connection.closed().await;
+    // Ends the stream and waits for the other side to have everything we sent,
+    // for when the whole connection is about to go away with it
+    pub async fn linger(&mut self) {
+        self.send.finish().ok();
+        if timeout(Duration::from_secs(LINGER_SECONDS), self.send.stopped()).await.is_err() {
+            warn!("{:?} never acknowledged the end of the stream", self.public);
+        }
+    }
+
+
+}
+
+// Two ends of one stream over localhost, the dialer already sent a ping so the accepting side could see it
+#[cfg(test)]
+pub(crate) struct TestPipes {
+    pub dialer: Pipe<NetworkEvent>,
+    pub accepter: Pipe<NetworkEvent>,
+    _queued: tokio::sync::mpsc::Receiver<(PublicKey, NetworkEvent)>, // Whatever the handlers push goes nowhere
+    _endpoints: (iroh::Endpoint, iroh::Endpoint)
+}
+
+#[cfg(test)]
+impl TestPipes {
+    pub async fn new(dialer: Arc<NodeDB>, accepter: Arc<NodeDB>) -> Result<Self, Box<dyn std::error::Error>> {
+        use config::db::identity::Identity;
+        const ALPN: &[u8] = b"cricket/test";
+
+        let mut endpoints = vec![];
+        for db in [&dialer, &accepter] {
+            let endpoint = iroh::Endpoint::builder()
+                .alpns(vec![ALPN.to_vec()])
+                .secret_key(iroh::SecretKey::from_bytes(&db.get_identity()?.private_key))
+                .relay_mode(iroh::RelayMode::Disabled)
+                .bind_addr_v4(std::net::SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, 0))
+                .bind()
+                .await?;
+            endpoints.push(endpoint);
+        }
+        let (accepting, dialing) = (endpoints.pop().unwrap(), endpoints.pop().unwrap());
+        let (pusher, queued) = tokio::sync::mpsc::channel(16);
+
+        let address = iroh::NodeAddr::new(accepting.node_id()).with_direct_addresses([accepting.bound_sockets().0]);
+        let (dialed, accepted) = tokio::join!(dialing.connect(address, ALPN), async {
+            accepting.accept().await.ok_or("nobody dialed")?.await.map_err(|e| e.to_string())
+        });
+
+        let connection = dialed?;
+        let (send, recv) = connection.open_bi().await?;
+        let mut dialer = Pipe::new(send, recv, accepting.node_id(), connection, dialer, pusher.clone());
+        dialer.send(NetworkEvent::Ping(crate::handlers::ping::Ping{})).await?;
+
+        let connection = accepted?;
+        let (send, recv) = connection.accept_bi().await?;
+        let mut accepter = Pipe::new(send, recv, dialing.node_id(), connection, accepter, pusher);
+        accepter.receive().await?;
+
+        Ok(TestPipes { dialer, accepter, _queued: queued, _endpoints: (dialing, accepting) })
     }
+}
+
+#[tokio::test]
+async fn test_oversize_event() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::handlers::error::{Error, ErrorCode};
+
+    let db1 = Arc::new(NodeDB::new(tempfile::TempDir::new()?, None)?);
+    let db2 = Arc::new(NodeDB::new(tempfile::TempDir::new()?, None)?);
+    let mut pipes = TestPipes::new(db1, db2).await?;
 
+    // Way past the frame limit, it never leaves and the stream is still good for the next one
+    let huge = "x".repeat(frame::MAX_FRAME_SIZE + 1);
+    let result = pipes.dialer.send(NetworkEvent::Error(Error::new(ErrorCode::Internal, &huge))).await;
+    assert!(matches!(result, Err(NetworkEventError::FrameTooLarge(_))));
+
+    pipes.dialer.send(NetworkEvent::Error(Error::new(ErrorCode::Internal, "small"))).await?;
+    match pipes.accepter.receive().await? {
+        NetworkEvent::Error(error) => assert_eq!(error.message, "small"),
+        other => panic!("Expected the small error, got {:?}", other)
+    }
 
+    Ok(())
 }
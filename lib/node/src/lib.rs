@@ -1,28 +1,78 @@
-use config::db::{IncomingPost, NodeDB, RawPost, Hashable};
+use config::db::{IncomingPost, NodeDB, Hashable, TrustRequest, PostId};
 use config::db::identity::Identity;
+use config::db::trust_request::HandleBlessing;
+use config::db::score::Score;
+use config::db::trust::Trust;
+use config::db::post_sync::{PostSync, MAX_SYNC_PAGE};
+use config::db::handle_post::HandlePost;
+use config::db::outbox::{Outbox, Retry};
+use config::db::retention::Retention;
+use config::db::feed::Feed;
+use config::db::direct_message::DirectMessages;
+use config::db::audience::Audience;
+use config::db::rotation::{KeyRotations, KeyRotation};
 
 use iroh::{Endpoint, PublicKey};
+use iroh::endpoint::{Connection, SendStream, RecvStream, VarInt};
 use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 use log::{info, warn};
-use std::thread;
+use anyhow::anyhow;
 
-use std::sync::mpsc::{Sender, Receiver};
-use std::sync::mpsc;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, watch};
+use tokio::task::{JoinHandle, JoinSet};
 
-use event_handler::{connection::ConnectionLogic, handlers::NetworkEvent, pipe::Pipe};
-use event_handler::handlers::peer::share_post;
+use event_handler::{connection::ConnectionLogic, handlers::NetworkEvent, pipe::Pipe, codec::Codec};
+use event_handler::handlers::peer::{self, share_post};
+use event_handler::handlers::{sync, message, rotation, close_request::CloseRequest, error::ErrorCode};
+use config::db::direct_message::DirectMessage as SealedMessage;
 
-const CHAT_ALPN: &[u8] = b"pkarr-discovery-demo-chat";
+const CRICKET_ALPN: &[u8] = b"cricket/1";
+
+const OUTGOING_QUEUE_SIZE: usize = 1024;
+// Streams we handle at the same time. Separate, so a burst of incoming exchanges
+// can't starve the pushes they queue (or the other way around).
+const MAX_INBOUND_EXCHANGES: usize = 64;
+const MAX_OUTBOUND_EXCHANGES: usize = 64;
+const MAX_CONNECTIONS: usize = 256; // Incoming connections we serve at once, the rest wait to be accepted
+
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const POOL_SWEEP_INTERVAL: Duration = Duration::from_secs(15);
+
+const OUTBOX_INTERVAL: Duration = Duration::from_secs(10); // How often we look for posts that are due a retry
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(60 * 10);
+const CANDIDATE_INTERVAL: Duration = Duration::from_secs(60 * 2); // Time a candidate's feed gets to reach us before we give up on them
+
+pub const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(10);
+
+// A connection we dialed and already said hello on, every exchange gets its own stream
+struct PooledConnection {
+    connection: Connection,
+    codec: Codec,
+    features: Vec<String>,
+    version: u32,
+    last_used: Instant
+}
 
 pub struct Node {
     pub endpoint: Arc<Endpoint>,
     pub public_key: PublicKey,
     pub db: Arc<NodeDB>,
-    pub pipe_tx: Sender<(PublicKey, NetworkEvent)> // So the pipe can create new connections to other peers
+    pub codec: Codec, // What we would prefer to speak, the peer we connect to gets the final say
+    pub pipe_tx: Sender<(PublicKey, NetworkEvent)>, // So the pipe can create new connections to other peers
+    pool: tokio::sync::Mutex<HashMap<PublicKey, PooledConnection>>,
+    inbound: Arc<Semaphore>,
+    outbound: Arc<Semaphore>,
+    connections: Arc<Semaphore>,
+    shutdown: watch::Sender<bool>,
+    dispatcher: tokio::sync::Mutex<Option<JoinHandle<()>>>,
+    tasks: tokio::sync::Mutex<JoinSet<()>> // Everything else that runs until shutdown
 }
 
 impl Node {
-    pub async fn new(db:NodeDB) -> Arc<Self> {
+    pub async fn new(db:NodeDB, codec:Codec, passphrase:Option<&str>) -> anyhow::Result<Arc<Self>> {
         
         /*
             TODO, I am feeling sick, so i might leave this project for a sec
@@ -45,17 +95,21 @@ impl Node {
                 - PromotePost
                 - DemotePost
 
-            You will need to consider the case where you cannot connect to a given peer (demote?)
+            [DONE] You will need to consider the case where you cannot connect to a given peer (demote?)
             You could also do something where the node does not construct a pipe if the connecting peer scores too low. 
 
             Anyways, hope you feel better.
         */
 
-        let raw_secret = db.get_identity().unwrap();
+        // A locked identity can't sign or connect, so this comes first
+        if let Some(passphrase) = passphrase {
+            db.unlock(passphrase)?;
+        }
+
+        let raw_secret = db.get_identity()?;
         let secret_key = iroh::SecretKey::from_bytes(&raw_secret.private_key.clone());
-        let public_key = secret_key.public();
 
-        info!("We are {:?}", public_key);
+        info!("We are {:?}", secret_key.public());
 
         let discovery = iroh::discovery::pkarr::dht::DhtDiscovery::builder().dht(true)
             .n0_dns_pkarr_relay()
@@ -64,83 +118,678 @@ impl Node {
             .unwrap();
 
         let endpoint = Endpoint::builder()
-            .alpns(vec![CHAT_ALPN.to_vec()])
+            .alpns(vec![CRICKET_ALPN.to_vec()])
             .secret_key(secret_key)
             .discovery(Box::new(discovery))
             .bind()
             .await
             .unwrap();
-        
-        let (pipe_tx, pipe_rx): (Sender<(PublicKey, NetworkEvent)>, Receiver<(PublicKey, NetworkEvent)>) = mpsc::channel();
+
+        Self::start(db, codec, endpoint).await
+    }
+
+    /// Everything that happens once we have an endpoint, which has to be bound with our identity key
+    pub async fn start(db:NodeDB, codec:Codec, endpoint:Endpoint) -> anyhow::Result<Arc<Self>> {
+        let public_key = endpoint.node_id();
+
+        let (pipe_tx, mut pipe_rx) = mpsc::channel::<(PublicKey, NetworkEvent)>(OUTGOING_QUEUE_SIZE);
+        let (shutdown, _) = watch::channel(false);
 
         let node = Node {
             endpoint: Arc::new(endpoint),
             public_key: public_key,
             db: Arc::new(db),
-            pipe_tx: pipe_tx
+            codec,
+            pipe_tx,
+            pool: tokio::sync::Mutex::new(HashMap::new()),
+            inbound: Arc::new(Semaphore::new(MAX_INBOUND_EXCHANGES)),
+            connections: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+            outbound: Arc::new(Semaphore::new(MAX_OUTBOUND_EXCHANGES)),
+            shutdown,
+            dispatcher: tokio::sync::Mutex::new(None),
+            tasks: tokio::sync::Mutex::new(JoinSet::new())
         };
 
         let node = Arc::new(node);
-        let node_first_copy = node.clone();
-
-        // This is the worst code of my life
-        // So basically, we need to spawn a pipe for every new connection to a node,
-        // but a pipe might need to spawn a connection from within.
-        // So we create a channel that allows each pipe to spawn new connections
-        // TODO, make it so the pipe can create *and* then get the output from other pipes?
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        thread::spawn(move || {
+
+        // Pipes (and the user) queue events here, and each one gets its own task once
+        // there is a free outbound slot. Until then the queue fills up, and whoever is
+        // queueing has to wait (or, inside an exchange, hand posts to the outbox instead).
+        // On shutdown the queue stops taking new events, but whatever is already in it still goes out.
+        let dispatcher = node.clone();
+        let mut stopping = node.shutdown.subscribe();
+        let handle = tokio::spawn(async move {
+            let mut pushes = JoinSet::new();
             loop {
-                let (destination, event) = pipe_rx.recv().unwrap();
-                let node_second_copy = node_first_copy.clone();
-                
-                rt.spawn(async move {
-                    node_second_copy.push(destination, event).await;
+                let next = tokio::select! {
+                    next = pipe_rx.recv() => next,
+                    _ = stopped(&mut stopping), if !pipe_rx.is_closed() => {
+                        pipe_rx.close();
+                        continue;
+                    }
+                };
+                let Some((destination, event)) = next else { break };
+
+                let Ok(permit) = dispatcher.outbound.clone().acquire_owned().await else { break };
+                let node = dispatcher.clone();
+                pushes.spawn(async move {
+                    node.push(destination, event).await;
+                    drop(permit);
                 });
+
+                // Forget about the ones that are done, so the set doesn't keep growing
+                while pushes.try_join_next().is_some() {}
+            }
+            while pushes.join_next().await.is_some() {}
+        });
+        *node.dispatcher.lock().await = Some(handle);
+
+        let mut tasks = node.tasks.lock().await;
+
+        let node_sweeper = node.clone();
+        let mut stopping = node.shutdown.subscribe();
+        tasks.spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(POOL_SWEEP_INTERVAL) => node_sweeper.sweep_pool().await,
+                    _ = stopped(&mut stopping) => break
+                }
             }
         });
 
-        node
+        let node_outbox = node.clone();
+        let mut stopping = node.shutdown.subscribe();
+        tasks.spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(OUTBOX_INTERVAL) => {
+                        if let Err(e) = node_outbox.retry_outbox().await {
+                            warn!("Could not go through the outbox: {}", e);
+                        }
+                    },
+                    _ = stopped(&mut stopping) => break
+                }
+            }
+        });
+
+        let node_compaction = node.clone();
+        let mut stopping = node.shutdown.subscribe();
+        tasks.spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(COMPACTION_INTERVAL) => {
+                        let db = node_compaction.db.clone();
+                        match tokio::task::spawn_blocking(move || db.compact()).await {
+                            Ok(Ok(compaction)) => info!("Compacted {} posts and {} seen markers", compaction.posts, compaction.seen),
+                            Ok(Err(e)) => warn!("Could not compact the database: {}", e),
+                            Err(e) => warn!("Compaction crashed: {:?}", e)
+                        }
+                    },
+                    _ = stopped(&mut stopping) => break
+                }
+            }
+        });
+
+        let node_candidates = node.clone();
+        let mut stopping = node.shutdown.subscribe();
+        tasks.spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(CANDIDATE_INTERVAL) => {
+                        if let Err(e) = node_candidates.befriend_candidates().await {
+                            warn!("Could not reach out to candidate peers: {:?}", e);
+                        }
+                        if let Err(e) = node_candidates.recover_feed().await {
+                            warn!("Could not ask for our own feed: {:?}", e);
+                        }
+                    },
+                    _ = stopped(&mut stopping) => break
+                }
+            }
+        });
+        drop(tasks);
+
+        // Let the bootstrap nodes know we exist, so they start sharing posts with us
+        if let Some(bootstrap_nodes) = &node.db.bootstrap_nodes {
+            for bootstrap in bootstrap_nodes {
+                node.db.register_pending(bootstrap).unwrap();
+                let destination = PublicKey::from_bytes(&bootstrap.public_key).unwrap();
+                node.pipe_tx.send((destination, NetworkEvent::TrustBootstrap(peer::TrustBootstrap{}))).await.unwrap();
+            }
+        }
+
+        // Catch up on whatever we missed while we were offline
+        if let Err(e) = node.sync().await {
+            warn!("Could not sync with our peers: {:?}", e);
+        }
+
+        if let Err(e) = node.fill_gaps().await {
+            warn!("Could not ask for missing feed posts: {:?}", e);
+        }
+
+        if let Err(e) = node.recover_feed().await {
+            warn!("Could not ask for our own feed: {:?}", e);
+        }
+
+        if let Err(e) = node.discover_peers().await {
+            warn!("Could not ask for secondary peers: {:?}", e);
+        }
+
+        Ok(node)
         
     }
 
-    pub fn push_to_thread(&self, mut connection: ConnectionLogic) {
-        thread::spawn(move || {
-            tokio::runtime::Runtime::new().unwrap().block_on(async {
-                connection.handle().await;
-            });
+    /// Handles a stream in the background, holding its exchange slot until it is done
+    pub fn spawn_exchange(&self, mut connection: ConnectionLogic, permit: OwnedSemaphorePermit) {
+        tokio::spawn(async move {
+            let _permit = permit;
+            let _ = connection.handle().await; // Already logged
         });
     }
 
-    pub async fn send_post(&self, content:&String) {
-        let us = self.db.get_identity().unwrap();
-        let raw = RawPost::new(us.node.clone(),content.clone());
+    // Refused while we are still recovering our feed after an import
+    pub async fn send_post(&self, content:&str) -> Result<(), Box<dyn std::error::Error>> {
+        let us = self.db.get_identity()?;
+        let raw = self.db.new_post(content.to_string())?;
         let signature = us.sign(&raw.hash());
-        let post = IncomingPost::new(&raw, &vec![], &signature, &us).unwrap();
+        let post = IncomingPost::new(&raw, &vec![], &signature, &us, self.db.settings.clock_skew)?;
 
         share_post(post, &self.db, &self.pipe_tx).await;
+        Ok(())
+    }
 
+    // Only readable by the nodes we trust right now
+    pub async fn send_private_post(&self, content:&str) -> Result<(), Box<dyn std::error::Error>> {
+        let us = self.db.get_identity()?;
+        let raw = self.db.new_private_post(content.to_string())?;
+        let signature = us.sign(&raw.hash());
+        let post = IncomingPost::new(&raw, &vec![], &signature, &us, self.db.settings.clock_skew)?;
+
+        share_post(post, &self.db, &self.pipe_tx).await;
+        Ok(())
+    }
+
+    // Straight to them, and retried with the outbox until they have it
+    pub async fn send_message(&self, recipient:&config::db::Node, content:&str) -> Result<(), Box<dyn std::error::Error>> {
+        let sealed = self.db.write_message(recipient, content)?;
+        let destination = PublicKey::from_bytes(&recipient.public_key)?;
+        self.pipe_tx.send((destination, NetworkEvent::DirectMessage(message::DirectMessage{data:sealed}))).await?;
+        Ok(())
+    }
+
+    // Our connections keep using the old key until we restart. Peers we can't reach now
+    // hear about it from the outbox, or the next time we connect.
+    pub async fn rotate_key(&self, passphrase:Option<&str>) -> Result<KeyRotation, Box<dyn std::error::Error>> {
+        let succession = self.db.rotate_identity(passphrase)?;
+        let peers = self.peers()?;
+        for remote in peers {
+            let destination = PublicKey::from_bytes(&remote.public_key)?;
+            for announcement in self.db.announcements_for(&remote)? {
+                let event = NetworkEvent::KeyRotation(rotation::KeyRotation{data:announcement});
+                self.pipe_tx.send((destination, event)).await?;
+            }
+        }
+        Ok(succession)
     }
 
+    pub async fn promote(&self, post:&PostId) -> Result<(), Box<dyn std::error::Error>> {
+        // Promoting might make the author worth trusting directly
+        if let Some(request) = self.db.promote(post)? {
+            info!("Asking {:?} to trust us", request.recipient);
+            self.send_trust_request(request).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn demote(&self, post:&PostId) -> Result<(), Box<dyn std::error::Error>> {
+        self.db.demote(post)?;
+
+        // Demoting might have cost the author our trust
+        let author = self.db.resolve(post)?.post.author;
+        if !self.db.is_trusted(&author)? {
+            self.evict(PublicKey::from_bytes(&author.public_key)?).await;
+        }
+        Ok(())
+    }
+
+    // Everyone we trust plus the bootstrap nodes, without ourselves
+    fn peers(&self) -> Result<Vec<config::db::Node>, Box<dyn std::error::Error>> {
+        let us = self.db.get_identity()?;
+
+        let mut peers:Vec<config::db::Node> = self.db.get_trusted()?.into_iter().map(|(node, _score)| node).collect();
+        for bootstrap in self.db.bootstrap_nodes.iter().flatten() {
+            if !peers.contains(bootstrap) {
+                peers.push(bootstrap.clone());
+            }
+        }
+        peers.retain(|peer| peer != &us.node);
+
+        Ok(peers)
+    }
+
+    pub async fn sync(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for remote in self.peers()? {
+            let after = self.db.get_sync_cursor(&remote)?;
+            let destination = PublicKey::from_bytes(&remote.public_key)?;
+            let request = sync::PostRequest { after, limit: MAX_SYNC_PAGE };
+            self.pipe_tx.send((destination, NetworkEvent::PostRequest(request))).await?;
+        }
+
+        Ok(())
+    }
+
+    // Ask around for the posts of the authors we trust that never reached us
+    pub async fn fill_gaps(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let peers = self.peers()?;
+        for (author, _score) in self.db.get_trusted()? {
+            // Everything after the first gap, whoever has it
+            let Some((first, _last)) = self.db.feed_gaps(&author)?.first().copied() else { continue };
+            for remote in &peers {
+                let destination = PublicKey::from_bytes(&remote.public_key)?;
+                let request = sync::FeedRequest { author: author.clone(), after: first - 1, limit: MAX_SYNC_PAGE };
+                self.pipe_tx.send((destination, NetworkEvent::FeedRequest(request))).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // After an import we don't know where our feed is at, ask everyone for it until one of them answered
+    pub async fn recover_feed(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.db.recovering_feed()? {
+            return Ok(());
+        }
+
+        let us = self.db.get_identity()?;
+        let peers = self.peers()?;
+        for remote in peers {
+            let destination = PublicKey::from_bytes(&remote.public_key)?;
+            let request = sync::FeedRequest { author: us.node.clone(), after: 0, limit: MAX_SYNC_PAGE };
+            self.pipe_tx.send((destination, NetworkEvent::FeedRequest(request))).await?;
+        }
+
+        Ok(())
+    }
+
+    // Ask the nodes we trust (and the bootstrap nodes) who they trust
+    pub async fn discover_peers(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for remote in self.peers()? {
+            self.db.request_candidates(&remote)?;
+            let destination = PublicKey::from_bytes(&remote.public_key)?;
+            let request = peer::SecondaryPeerRequest { limit: None };
+            self.pipe_tx.send((destination, NetworkEvent::SecondaryPeerRequest(request))).await?;
+        }
+
+        Ok(())
+    }
+
+    // Candidates we can prove we know get a trust request. For the others we ask the peer
+    // that told us about them for their feed, and give up if that didn't get us anywhere.
+    pub async fn befriend_candidates(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for (candidate, entry) in self.db.get_candidates()? {
+            if self.db.is_trusted(&candidate)? {
+                self.db.remove_candidate(&candidate)?;
+                continue;
+            }
+
+            if let Some(request) = self.db.candidate_blessing(&candidate)? {
+                info!("Asking candidate {:?} to trust us", candidate);
+                self.db.remove_candidate(&candidate)?;
+                self.send_trust_request(request).await?;
+            } else if entry.asked {
+                info!("Giving up on candidate {:?}", candidate);
+                self.db.remove_candidate(&candidate)?;
+            } else {
+                self.db.candidate_asked(&candidate)?;
+                let destination = PublicKey::from_bytes(&entry.via.public_key)?;
+                let request = sync::FeedRequest { author: candidate, after: 0, limit: MAX_SYNC_PAGE };
+                self.pipe_tx.send((destination, NetworkEvent::FeedRequest(request))).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn send_trust_request(&self, request:TrustRequest) -> Result<(), Box<dyn std::error::Error>> {
+        self.db.register_pending(&request.recipient)?;
+        let destination = PublicKey::from_bytes(&request.recipient.public_key)?;
+        self.pipe_tx.send((destination, NetworkEvent::TrustRequest(peer::TrustRequest{data:request}))).await?;
+        Ok(())
+    }
+
+    // The caller holds an outbound slot for as long as this runs
     pub async fn push(&self, destination:PublicKey, event:NetworkEvent) {
-        let pipe = self.connect_to_node(destination).await;
-        let mut connection = ConnectionLogic::new(pipe);
-        connection.pipe.send(event).await;
-        self.push_to_thread(connection);
+        // Posts are worth delivering later, everything else is cheap to ask for again
+        let post = match &event {
+            NetworkEvent::Post(post) => Some(post.data.clone()),
+            _ => None
+        };
+        let message = match &event {
+            NetworkEvent::DirectMessage(message) => Some(message.data.clone()),
+            _ => None
+        };
+        let succession = match &event {
+            NetworkEvent::KeyRotation(succession) => Some(succession.data.clone()),
+            _ => None
+        };
+
+        let outcome = self.deliver(destination, event).await;
+        if let Err(e) = &outcome {
+            warn!("Could not deliver to {:?}: {:?}", destination, e);
+            if let Some(post) = post {
+                if let Err(e) = self.db.queue_outgoing(&post) {
+                    warn!("Could not queue post for {:?}: {}", destination, e);
+                }
+            }
+        }
+        if let Some(message) = message {
+            self.settle_message(&message, &outcome);
+        }
+        if let Some(succession) = succession {
+            self.settle_announcement(destination, &succession, &outcome);
+        }
+    }
+
+    // Ok once the other side saw the exchange through, not just when we managed to write to them,
+    // with the error they answered if they turned it down
+    async fn deliver(&self, destination:PublicKey, event:NetworkEvent) -> anyhow::Result<Option<ErrorCode>> {
+        let mut connection = self.connect_to_node(destination).await?;
+
+        if let Some(feature) = event.required_feature() {
+            if !connection.pipe.features.iter().any(|agreed| agreed == feature) {
+                let _ = connection.request(NetworkEvent::CloseRequest(CloseRequest{})).await;
+                let _ = connection.handle().await;
+                return Err(anyhow!("{:?} does not support {}", destination, feature));
+            }
+        }
+        // Nothing to retry, they will never be able to read it
+        if !event.readable_at(connection.pipe.version) {
+            info!("Not sending {:?} to {:?}, it is too new for protocol version {}", event, destination, connection.pipe.version);
+            let _ = connection.request(NetworkEvent::CloseRequest(CloseRequest{})).await;
+            let _ = connection.handle().await;
+            return Ok(None);
+        }
+        if let Err(e) = connection.request(event).await {
+            connection.pipe.close().await;
+            return Err(anyhow!("Could not send: {:?}", e));
+        }
+        connection.handle().await.map_err(|e| anyhow!("Exchange failed: {:?}", e))?;
+        Ok(connection.refused)
+    }
+
+    // A message only counts as sent once the recipient took it
+    fn settle_message(&self, message:&SealedMessage, outcome:&anyhow::Result<Option<ErrorCode>>) {
+        if let Ok(None) = outcome {
+            if let Err(e) = self.db.message_delivered(message) {
+                warn!("Could not mark the message to {:?} as delivered: {}", message.recipient, e);
+            }
+            return;
+        }
+
+        match self.db.message_failed(message) {
+            Ok(Retry::At(at)) => info!("Message to {:?} not delivered ({:?}), next try at {}", message.recipient, outcome, at),
+            Ok(Retry::GaveUp) => warn!("Gave up delivering a message to {:?}", message.recipient),
+            Err(e) => warn!("Could not queue the message to {:?}: {}", message.recipient, e)
+        }
+    }
+
+    // Our own rotations wait until the peer took them, the ones we pass on are best effort
+    fn settle_announcement(&self, destination:PublicKey, succession:&KeyRotation, outcome:&anyhow::Result<Option<ErrorCode>>) {
+        let peer = config::db::Node::new(*destination.as_bytes());
+        let settled = match outcome {
+            Ok(None) => self.db.rotation_announced(&peer, succession).map(|_| None),
+            _ => self.db.announcement_failed(&peer, succession)
+        };
+        match settled {
+            Ok(Some(at)) => info!("Key rotation not announced to {:?} ({:?}), next try at {}", destination, outcome, at),
+            Ok(None) => {},
+            Err(e) => warn!("Could not queue the key rotation for {:?}: {}", destination, e)
+        }
+    }
+
+    // Whatever rotations of ours they don't have yet, without waiting for room in the queue
+    fn announce_rotations(&self, node:PublicKey) {
+        let peer = config::db::Node::new(*node.as_bytes());
+        let announcements = match self.db.announcements_for(&peer) {
+            Ok(announcements) => announcements,
+            Err(e) => {
+                warn!("Could not look up key rotations for {:?}: {}", node, e);
+                return;
+            }
+        };
+        for announcement in announcements {
+            let event = NetworkEvent::KeyRotation(rotation::KeyRotation{data:announcement});
+            if let Err(e) = self.pipe_tx.try_send((node, event)) {
+                warn!("Could not announce our key rotation to {:?}: {}", node, e); // The outbox gets to it
+                break;
+            }
+        }
+    }
 
+    // Tries every post, message and key rotation that is due again, one peer at a time.
+    // A peer we could not reach is not dialed again this round, and only pays for it once.
+    async fn retry_outbox(&self) -> Result<(), config::db::Error> {
+        let mut unreachable:HashSet<PublicKey> = HashSet::new();
+
+        for entry in self.db.due_outgoing()? {
+            let recipient = entry.recipient().clone();
+            let post = entry.post.post.get_id();
+
+            let destination = match PublicKey::from_bytes(&recipient.public_key) {
+                Ok(destination) => destination,
+                Err(_) => {
+                    self.db.delivered(&recipient, &post)?; // Never going to reach them
+                    continue;
+                }
+            };
+            if unreachable.contains(&destination) {
+                self.db.delivery_failed(&recipient, &post)?;
+                continue;
+            }
+
+            let _permit = self.outbound.acquire().await;
+            info!("Delivering {} to {:?} after {} attempts", post.short(), destination, entry.attempts);
+            match self.deliver(destination, NetworkEvent::Post(peer::Post{data: entry.post})).await {
+                Ok(_answer) => self.db.delivered(&recipient, &post)?,
+                Err(e) => {
+                    unreachable.insert(destination);
+                    self.db.unreachable(&recipient)?;
+                    match self.db.delivery_failed(&recipient, &post)? {
+                        Retry::At(at) => info!("Still could not deliver to {:?} ({:?}), next try at {}", destination, e, at),
+                        Retry::GaveUp => warn!("Gave up delivering {} to {:?}", post.short(), destination)
+                    }
+                }
+            }
+        }
+
+        for message in self.db.due_messages()? {
+            let Ok(destination) = PublicKey::from_bytes(&message.recipient.public_key) else {
+                self.db.message_failed(&message)?;
+                continue;
+            };
+            if unreachable.contains(&destination) {
+                self.db.message_failed(&message)?;
+                continue;
+            }
+
+            let _permit = self.outbound.acquire().await;
+            let outcome = self.deliver(destination, NetworkEvent::DirectMessage(message::DirectMessage{data: message.clone()})).await;
+            if outcome.is_err() {
+                unreachable.insert(destination);
+            }
+            self.settle_message(&message, &outcome);
+        }
+
+        for (peer, succession) in self.db.due_announcements()? {
+            let Ok(destination) = PublicKey::from_bytes(&peer.public_key) else {
+                self.db.rotation_announced(&peer, &succession)?; // Never going to reach them
+                continue;
+            };
+            if unreachable.contains(&destination) {
+                self.db.announcement_failed(&peer, &succession)?;
+                continue;
+            }
+
+            let _permit = self.outbound.acquire().await;
+            let outcome = self.deliver(destination, NetworkEvent::KeyRotation(rotation::KeyRotation{data: succession.clone()})).await;
+            if outcome.is_err() {
+                unreachable.insert(destination);
+            }
+            self.settle_announcement(destination, &succession, &outcome);
+        }
+
+        Ok(())
     }
 
-    pub async fn connect_to_node(&self, node:PublicKey) -> Pipe<NetworkEvent> {
+    /// Opens a new stream to the node, reusing a pooled connection if we have a live one
+    pub async fn connect_to_node(&self, node:PublicKey) -> anyhow::Result<ConnectionLogic> {
+        let pooled = {
+            let mut pool = self.pool.lock().await;
+            match pool.get_mut(&node) {
+                Some(entry) if entry.connection.close_reason().is_none() => {
+                    entry.last_used = Instant::now();
+                    Some((entry.connection.clone(), entry.codec, entry.features.clone(), entry.version))
+                },
+                _ => None
+            }
+        };
+
+        if let Some((connection, codec, features, version)) = pooled {
+            match connection.open_bi().await {
+                Ok((send, recv)) => {
+                    let mut logic = self.exchange(send, recv, node, connection);
+                    logic.resume(codec, features, version);
+                    return Ok(logic);
+                },
+                Err(e) => info!("Pooled connection to {:?} went stale: {:?}", node, e)
+            }
+        }
+        self.evict(node).await;
+
         info!("Connecting to {:?}", node); 
-        let connection = self.endpoint.connect(node, CHAT_ALPN).await.unwrap();     
+        let connection = self.endpoint.connect(node, CRICKET_ALPN).await?;
         info!("Connection made with {:?}", node);             
-        let (send, recv) = connection.open_bi().await.unwrap();
-        let db_ref = self.db.clone();
-        Pipe::new(send, recv, node, connection, db_ref, self.pipe_tx.clone())
+        let (send, recv) = connection.open_bi().await?;
+
+        let mut logic = self.exchange(send, recv, node, connection.clone());
+        if let Err(e) = logic.greet(self.codec).await {
+            connection.close(VarInt::from_u32(0), b"Handshake failed");
+            return Err(anyhow!("Could not agree on a protocol: {:?}", e));
+        }
+
+        self.pool.lock().await.insert(node, PooledConnection {
+            connection,
+            codec: logic.pipe.codec,
+            features: logic.pipe.features.clone(),
+            version: logic.pipe.version,
+            last_used: Instant::now()
+        });
+        self.announce_rotations(node);
+
+        Ok(logic)
     }
-   
-    pub async fn accept_connections(&self) {
-        while let Some(incoming) = self.endpoint.accept().await {
+
+    fn exchange(&self, send:SendStream, recv:RecvStream, node:PublicKey, connection:Connection) -> ConnectionLogic {
+        let pipe:Pipe<NetworkEvent> = Pipe::new(send, recv, node, connection, self.db.clone(), self.pipe_tx.clone());
+        ConnectionLogic::new(pipe).with_shutdown(self.shutdown.subscribe())
+    }
+
+    /// Drops and closes our pooled connection to the node, if there is one
+    pub async fn evict(&self, node:PublicKey) {
+        if let Some(entry) = self.pool.lock().await.remove(&node) {
+            info!("Closing pooled connection to {:?}", node);
+            entry.connection.close(VarInt::from_u32(0), b"Evicted");
+        }
+    }
+
+    fn is_peer(&self, node:&PublicKey) -> bool {
+        let node = config::db::Node::new(*node.as_bytes());
+        let is_bootstrap = self.db.bootstrap_nodes.iter().flatten().any(|bootstrap| bootstrap == &node);
+        is_bootstrap || self.db.is_trusted(&node).unwrap_or(false)
+    }
+
+    async fn sweep_pool(&self) {
+        let mut pool = self.pool.lock().await;
+
+        // Nodes we don't trust (anymore) only get a short grace period, enough for a trust request
+        let stale:Vec<PublicKey> = pool.iter()
+            .filter(|(node, entry)| {
+                let idle = entry.last_used.elapsed();
+                entry.connection.close_reason().is_some()
+                    || idle > POOL_IDLE_TIMEOUT
+                    || (idle > POOL_SWEEP_INTERVAL && !self.is_peer(node))
+            })
+            .map(|(node, _entry)| *node)
+            .collect();
+
+        for node in stale {
+            if let Some(entry) = pool.remove(&node) {
+                info!("Closing pooled connection to {:?}", node);
+                entry.connection.close(VarInt::from_u32(0), b"Idle");
+            }
+        }
+    }
+
+    /// Stops accepting, lets queued events and running exchanges finish (until the deadline),
+    /// then closes every connection and flushes the database
+    pub async fn shutdown(&self, deadline:Duration) -> anyhow::Result<()> {
+        info!("Shutting down");
+        let deadline = tokio::time::Instant::now() + deadline;
+        self.shutdown.send_replace(true);
+
+        // Anything already queued still gets pushed
+        if let Some(mut dispatcher) = self.dispatcher.lock().await.take() {
+            if tokio::time::timeout_at(deadline, &mut dispatcher).await.is_err() {
+                warn!("Gave up on draining the outgoing queue");
+                dispatcher.abort(); // Along with the pushes it is waiting for
+            }
+        }
+
+        // The background loops stop at their next turn, the outbox might be in the middle of a retry
+        let mut tasks = self.tasks.lock().await;
+        if tokio::time::timeout_at(deadline, async { while tasks.join_next().await.is_some() {} }).await.is_err() {
+            warn!("Gave up on {} background tasks", tasks.len());
+            tasks.shutdown().await;
+        }
+
+        // Every exchange holds a slot, once we have all of them nothing is running anymore.
+        // We keep them until the endpoint is closed, so nothing new starts in between.
+        let mut held = vec![];
+        for (exchanges, all) in [(&self.inbound, MAX_INBOUND_EXCHANGES), (&self.outbound, MAX_OUTBOUND_EXCHANGES)] {
+            match tokio::time::timeout_at(deadline, exchanges.acquire_many(all as u32)).await {
+                Ok(Ok(permit)) => held.push(permit),
+                Ok(Err(e)) => warn!("Could not wait for running exchanges: {}", e),
+                Err(_) => warn!("Gave up on {} running exchanges", all - exchanges.available_permits())
+            }
+        }
+
+        for (node, entry) in self.pool.lock().await.drain() {
+            info!("Closing pooled connection to {:?}", node);
+            entry.connection.close(VarInt::from_u32(0), b"Shutting down");
+        }
+        self.endpoint.close().await;
+        drop(held);
+
+        self.db.db.flush_async().await?;
+        info!("Shut down");
+        Ok(())
+    }
+
+    pub async fn accept_connections(self: Arc<Self>) {
+        let mut stopping = self.shutdown.subscribe();
+        loop {
+            // Wait for a free slot first, so a flood of connections queues up instead of piling on tasks
+            let slot = tokio::select! {
+                slot = self.connections.clone().acquire_owned() => slot.ok(),
+                _ = stopped(&mut stopping) => None
+            };
+            let Some(slot) = slot else { break };
+
+            let incoming = tokio::select! {
+                incoming = self.endpoint.accept() => incoming,
+                _ = stopped(&mut stopping) => None
+            };
+            let Some(incoming) = incoming else { break };
 
             let connecting = match incoming.accept() {
                 Ok(connecting) => connecting,
@@ -149,21 +798,278 @@ impl Node {
                     continue;
                 }
             };
-            
-            let connection = connecting.await.unwrap();
-            let node = connection.remote_node_id().unwrap();
-            info!("Connection made with {:?}", node);
 
-            let (send, recv) = connection.accept_bi().await.unwrap();
+            let node = self.clone();
+            tokio::spawn(async move {
+                let _slot = slot;
+                match connecting.await {
+                    Ok(connection) => node.serve_connection(connection).await,
+                    Err(err) => warn!("Incoming connection failed: {err:#}")
+                }
+            });
+        }
+    }
+
+    // The dialer opens a new stream for every exchange, only the first one starts with a hello
+    async fn serve_connection(&self, connection:Connection) {
+        let node = match connection.remote_node_id() {
+            Ok(node) => node,
+            Err(err) => {
+                warn!("Could not identify incoming connection: {err:#}");
+                return;
+            }
+        };
+        info!("Connection made with {:?}", node);
 
-            let db_ref = self.db.clone();
+        let mut agreed:Option<(Codec, Vec<String>, u32)> = None;
+        let mut stopping = self.shutdown.subscribe();
+
+        loop {
+            let stream = tokio::select! {
+                stream = connection.accept_bi() => stream.ok(),
+                _ = stopped(&mut stopping) => None
+            };
+            let Some((send, recv)) = stream else { break };
+
+            // We don't read the next stream until this one has a slot, so the peer waits instead of piling up tasks
+            let permit = tokio::select! {
+                permit = self.inbound.clone().acquire_owned() => permit.ok(),
+                _ = stopped(&mut stopping) => None
+            };
+            let Some(permit) = permit else { break };
 
-            let pipe:Pipe<NetworkEvent> = Pipe::new(send, recv, node, connection, db_ref, self.pipe_tx.clone());
-            let connection = ConnectionLogic::new(pipe);
-            self.push_to_thread(connection);
+            let mut logic = self.exchange(send, recv, node, connection.clone());
+
+            match &agreed {
+                Some((codec, features, version)) => logic.resume(*codec, features.clone(), *version),
+                None => {
+                    if let Err(e) = logic.welcome().await {
+                        warn!("Handshake with {:?} failed: {:?}", node, e);
+                        return;
+                    }
+                    agreed = Some((logic.pipe.codec, logic.pipe.features.clone(), logic.pipe.version));
+                    self.announce_rotations(node);
+                }
+            }
+
+            self.spawn_exchange(logic, permit);
         }
+
+        info!("Connection with {:?} closed", node);
+    }
+
+
+
+}
+
+// Resolves once shutdown starts (or the node is gone)
+async fn stopped(stopping: &mut watch::Receiver<bool>) {
+    let _ = stopping.wait_for(|stop| *stop).await;
+}
+
+// Only reachable on localhost, and only by nodes that are told the address
+#[cfg(test)]
+async fn local_endpoint(db: &NodeDB) -> anyhow::Result<Endpoint> {
+    Endpoint::builder()
+        .alpns(vec![CRICKET_ALPN.to_vec()])
+        .secret_key(iroh::SecretKey::from_bytes(&db.get_identity()?.private_key))
+        .relay_mode(iroh::RelayMode::Disabled)
+        .bind_addr_v4(std::net::SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, 0))
+        .bind()
+        .await
+}
+
+#[tokio::test]
+async fn test_shutdown() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+
+    let db = NodeDB::new(dir.path(), None)?;
+    let endpoint = local_endpoint(&db).await?;
+    let node = Node::start(db, Codec::Postcard, endpoint).await?;
+    let accepting = tokio::spawn(node.clone().accept_connections());
+
+    node.send_post("hello").await?;
+    node.shutdown(SHUTDOWN_DEADLINE).await?;
+    accepting.await?;
+
+    // Nothing is left holding on to the database, so it opens again, post and all
+    drop(Arc::into_inner(node).ok_or("something still holds on to the node")?);
+    let db = NodeDB::new(dir.path(), None)?;
+    let us = db.get_identity()?;
+    assert_eq!(db.feed_head(&us.node)?.map(|head| head.seq), Some(1));
+
+    let endpoint = local_endpoint(&db).await?;
+    let node = Node::start(db, Codec::Postcard, endpoint).await?;
+    node.send_post("hello again").await?;
+    assert_eq!(node.db.feed_head(&us.node)?.map(|head| head.seq), Some(2));
+    node.shutdown(SHUTDOWN_DEADLINE).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+async fn eventually(check: impl Fn() -> bool) -> bool {
+    for _ in 0..100 {
+        if check() {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    false
+}
+
+#[tokio::test]
+async fn test_outbox_retry() -> Result<(), Box<dyn std::error::Error>> {
+    use config::db::Settings;
+    use config::db::outbox::Outbox;
+
+    let (dir1, dir2) = (tempfile::TempDir::new()?, tempfile::TempDir::new()?);
+    let db1 = NodeDB::new(dir1.path(), None)?.with_settings(Settings { outbox_backoff: 0, ..Settings::default() });
+    let db2 = NodeDB::new(dir2.path(), None)?;
+    let (node1, node2) = (db1.get_identity()?.node, db2.get_identity()?.node);
+    db1.trust(&node2)?;
+    db2.trust(&node1)?;
+
+    // Nobody knows where node2 is yet, so the post waits in the outbox
+    let endpoint = local_endpoint(&db1).await?;
+    let sender = Node::start(db1, Codec::Postcard, endpoint).await?;
+    sender.send_post("hello").await?;
+    sender.send_post("hello again").await?;
+    sender.send_message(&node2, "hello").await?;
+    assert!(eventually(|| sender.db.due_outgoing().is_ok_and(|due| due.len() == 2)).await);
+    assert!(!sender.db.inbox(None, 1)?[0].delivered);
+
+    // Everything waiting for them costs one attempt to reach them, not one each
+    let score = sender.db.get_score(&node2, 1200)?;
+    sender.retry_outbox().await?;
+    assert_eq!(sender.db.get_score(&node2, 1200)?, score - sender.db.settings.unreachable_penalty);
+    assert!(sender.db.due_outgoing()?.iter().all(|entry| entry.attempts == 2));
+
+    let endpoint = local_endpoint(&db2).await?;
+    let receiver = Node::start(db2, Codec::Postcard, endpoint).await?;
+    let accepting = tokio::spawn(receiver.clone().accept_connections());
+    sender.endpoint.add_node_addr(iroh::NodeAddr::new(receiver.public_key).with_direct_addresses([receiver.endpoint.bound_sockets().0]))?;
+
+    // Only gone from the outbox once node2 actually has it
+    sender.retry_outbox().await?;
+    assert_eq!(sender.db.due_outgoing()?.len(), 0);
+    assert_eq!(receiver.db.feed_head(&node1)?.map(|head| head.seq), Some(2));
+    assert!(sender.db.inbox(None, 1)?[0].delivered);
+    assert_eq!(receiver.db.inbox(None, 1)?[0].content, "hello");
+
+    sender.shutdown(SHUTDOWN_DEADLINE).await?;
+    receiver.shutdown(SHUTDOWN_DEADLINE).await?;
+    accepting.await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_feed_recovery() -> Result<(), Box<dyn std::error::Error>> {
+    let db1 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let us = db1.get_identity()?;
+    let db2 = NodeDB::new(tempfile::TempDir::new()?, None)?;
+    let node2 = db2.get_identity()?;
+
+    // node2 has our first two posts
+    for content in ["first", "second"] {
+        let raw = db1.new_post(content.to_string())?;
+        let signature = us.sign(&raw.hash());
+        db1.receive(&IncomingPost::new(&raw, &vec![], &signature, &us, db1.settings.clock_skew)?)?;
+        db2.receive(&IncomingPost::new(&raw, &vec![], &signature, &node2, db2.settings.clock_skew)?)?;
     }
 
+    // A fresh install doesn't know about them, so it can't post until node2 sent them back
+    let db3 = NodeDB::new(tempfile::TempDir::new()?, Some(vec![node2.node.clone()]))?;
+    db3.import_mnemonic(&db1.export_mnemonic()?, true, None)?;
+    assert!(db3.recovering_feed()?);
 
+    let endpoint = local_endpoint(&db2).await?;
+    let bootstrap = Node::start(db2, Codec::Postcard, endpoint).await?;
+    let accepting = tokio::spawn(bootstrap.clone().accept_connections());
 
-}
\ No newline at end of file
+    let endpoint = local_endpoint(&db3).await?;
+    endpoint.add_node_addr(iroh::NodeAddr::new(bootstrap.public_key).with_direct_addresses([bootstrap.endpoint.bound_sockets().0]))?;
+    let imported = Node::start(db3, Codec::Postcard, endpoint).await?;
+
+    assert!(eventually(|| imported.db.recovering_feed().is_ok_and(|recovering| !recovering)).await);
+    assert_eq!(imported.db.feed_head(&us.node)?.map(|head| head.seq), Some(2));
+    imported.send_post("third").await?;
+    assert_eq!(imported.db.feed_head(&us.node)?.map(|head| head.seq), Some(3));
+
+    imported.shutdown(SHUTDOWN_DEADLINE).await?;
+    bootstrap.shutdown(SHUTDOWN_DEADLINE).await?;
+    accepting.await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rotation_announced() -> Result<(), Box<dyn std::error::Error>> {
+    let (db1, db2) = (NodeDB::new(tempfile::TempDir::new()?, None)?, NodeDB::new(tempfile::TempDir::new()?, None)?);
+    let (old, node2) = (db1.get_identity()?.node, db2.get_identity()?.node);
+    db1.trust(&node2)?;
+    db2.trust(&old)?;
+
+    // node2 can't be reached yet, so the rotation waits for it
+    let endpoint = local_endpoint(&db1).await?;
+    let sender = Node::start(db1, Codec::Postcard, endpoint).await?;
+    let succession = sender.rotate_key(None).await?;
+    assert_eq!(sender.db.announcements_for(&node2)?, vec![succession.clone()]);
+
+    let endpoint = local_endpoint(&db2).await?;
+    let receiver = Node::start(db2, Codec::Postcard, endpoint).await?;
+    let accepting = tokio::spawn(receiver.clone().accept_connections());
+    sender.endpoint.add_node_addr(iroh::NodeAddr::new(receiver.public_key).with_direct_addresses([receiver.endpoint.bound_sockets().0]))?;
+
+    // Whatever we connect for next, node2 hears about it on the way
+    sender.sync().await?;
+    assert!(eventually(|| receiver.db.rotated_to(&old).is_ok_and(|known| known.as_ref() == Some(&succession))).await);
+    assert!(eventually(|| sender.db.announcements_for(&node2).is_ok_and(|left| left.is_empty())).await);
+    assert!(receiver.db.is_trusted(&succession.new)?);
+
+    sender.shutdown(SHUTDOWN_DEADLINE).await?;
+    receiver.shutdown(SHUTDOWN_DEADLINE).await?;
+    accepting.await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pooling() -> Result<(), Box<dyn std::error::Error>> {
+    let (db1, db2) = (NodeDB::new(tempfile::TempDir::new()?, None)?, NodeDB::new(tempfile::TempDir::new()?, None)?);
+    db1.trust(&db2.get_identity()?.node)?;
+    db2.trust(&db1.get_identity()?.node)?;
+
+    let endpoint = local_endpoint(&db1).await?;
+    let sender = Node::start(db1, Codec::Postcard, endpoint).await?;
+    let endpoint = local_endpoint(&db2).await?;
+    let receiver = Node::start(db2, Codec::Postcard, endpoint).await?;
+    let accepting = tokio::spawn(receiver.clone().accept_connections());
+    sender.endpoint.add_node_addr(iroh::NodeAddr::new(receiver.public_key).with_direct_addresses([receiver.endpoint.bound_sockets().0]))?;
+
+    // Every exchange gets its own stream, on the one connection we keep around
+    let mut connections = vec![];
+    for _ in 0..3 {
+        let mut exchange = sender.connect_to_node(receiver.public_key).await?;
+        exchange.request(NetworkEvent::CloseRequest(CloseRequest{})).await?;
+        exchange.handle().await?;
+
+        let pool = sender.pool.lock().await;
+        assert_eq!(pool.len(), 1);
+        connections.push(pool.get(&receiver.public_key).ok_or("not pooled")?.connection.stable_id());
+    }
+    assert!(connections.windows(2).all(|pair| pair[0] == pair[1]));
+
+    // Until we let go of it
+    let connection = sender.pool.lock().await.get(&receiver.public_key).ok_or("not pooled")?.connection.clone();
+    sender.evict(receiver.public_key).await;
+    assert!(sender.pool.lock().await.is_empty());
+    assert!(connection.close_reason().is_some());
+
+    sender.shutdown(SHUTDOWN_DEADLINE).await?;
+    receiver.shutdown(SHUTDOWN_DEADLINE).await?;
+    accepting.await?;
+
+    Ok(())
+}
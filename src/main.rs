@@ -1,23 +1,75 @@
 use std::str::FromStr;
 use clap::Parser;
-use config::db::{NodeDB, PostId};
+use config::db::{NodeDB, PostId, Settings};
 use config::db::Node as Peer;
 use iroh::PublicKey;
-use node::Node;
+use node::{Node, SHUTDOWN_DEADLINE};
 use env_logger::Builder;
 use log::{self, info};
 use std::thread;
+use std::sync::Arc;
 use std::time::Duration;
 use std::io;
 use std::io::Write;
 use event_handler::handlers::{NetworkEvent, ping, peer};
-use config::db::search::Search;
+use event_handler::codec::Codec;
+use config::db::search::{Search, RankCursor};
+use config::db::rank;
+use config::db::handle_post::HandlePost;
+use config::db::post_sync::SyncCursor;
+use config::db::direct_message::DirectMessages;
+use config::db::audience::Audience;
+use config::db::identity::Identity;
+use config::db::trust::Trust;
+use config::db::feed::Feed;
 
 
 #[derive(Parser)]
 struct Args {
     src: String,
-    bootstrap_nodes: Option<Vec<String>>
+    bootstrap_nodes: Option<Vec<String>>,
+
+    /// Preferred wire format (json or postcard), agreed with each peer when connecting
+    #[arg(long, default_value = "json")]
+    codec: Codec,
+
+    /// Seconds we keep retrying a post for a peer we can't reach
+    #[arg(long)]
+    outbox_ttl: Option<u64>,
+
+    /// Days we keep other people's posts for (our own and promoted posts are kept forever),
+    /// posts from before authors signed when they wrote them are refused then
+    #[arg(long)]
+    keep_days: Option<u64>,
+
+    /// Most posts we keep around
+    #[arg(long)]
+    keep_posts: Option<usize>,
+
+    /// Most bytes of posts we keep around
+    #[arg(long)]
+    keep_bytes: Option<u64>,
+
+    /// Seconds a post may claim to be from the future (other clocks are never quite right)
+    #[arg(long)]
+    clock_skew: Option<u64>,
+
+    /// How /feed and /search are ordered: chronological, hot, score or trusted
+    /// (new posts always show up as they arrive, trusted still leaves the others out)
+    #[arg(long, default_value = "chronological")]
+    ranker: String,
+
+    /// Identity file made with /export to use instead of our own
+    #[arg(long)]
+    import: Option<String>,
+
+    /// Read the 24 words from /mnemonic and use that identity instead of our own
+    #[arg(long)]
+    import_mnemonic: bool,
+
+    /// Let --import or --import-mnemonic replace the identity this database already has
+    #[arg(long)]
+    force_import: bool
 }
 
 #[tokio::main]
@@ -28,6 +80,10 @@ async fn main() -> anyhow::Result<()> {
 
     let args = Args::parse();
 
+    let ranker:Arc<dyn rank::Ranker> = rank::by_name(&args.ranker)
+        .ok_or(anyhow::anyhow!("Unknown ranker {}, pick one of {:?}", args.ranker, rank::RANKERS))?
+        .into();
+
     let cleaned_nodes = match args.bootstrap_nodes.clone() {
         Some(bootstrap_nodes) => {
             Some(bootstrap_nodes.iter().map(|public| {
@@ -38,10 +94,59 @@ async fn main() -> anyhow::Result<()> {
         None => {None}
     };
     
-    let config_loader = NodeDB::new(args.src.to_string(), cleaned_nodes).expect("Could not create database");
+    let mut settings = Settings::default();
+    if let Some(outbox_ttl) = args.outbox_ttl {
+        settings.outbox_ttl = outbox_ttl;
+    }
+    if let Some(keep_days) = args.keep_days {
+        settings.retention_age = Some(keep_days * 60 * 60 * 24);
+    }
+    settings.retention_count = args.keep_posts;
+    settings.retention_bytes = args.keep_bytes;
+    if let Some(clock_skew) = args.clock_skew {
+        settings.clock_skew = clock_skew;
+    }
+
+    let config_loader = NodeDB::new(args.src.to_string(), cleaned_nodes).expect("Could not create database")
+        .with_settings(settings);
+
+    // Imports get locked right away, with CRICKET_PASSPHRASE or a new passphrase
+    let imported = if args.import.is_some() || args.import_mnemonic {
+        let lock_with = match std::env::var("CRICKET_PASSPHRASE") {
+            Ok(passphrase) => Some(passphrase),
+            Err(_) => read_new_passphrase().map_err(|e| anyhow::anyhow!("{}", e))?
+        };
+        let us = if let Some(path) = &args.import {
+            let backup = std::fs::read(path)?;
+            let passphrase = rpassword::prompt_password("Passphrase of the identity file: ")?;
+            config_loader.import_identity(&backup, &passphrase, args.force_import, lock_with.as_deref())?
+        } else {
+            let words = rpassword::prompt_password("Words: ")?;
+            config_loader.import_mnemonic(&words, args.force_import, lock_with.as_deref())?
+        };
+        println!("Imported identity {}", hex::encode(us.node.public_key));
+        if config_loader.recovering_feed()? {
+            println!("Fetching our earlier posts from our peers, posting waits until one of them answered");
+        }
+        Some(lock_with)
+    } else {
+        None
+    };
+
+    // Locked identities need their passphrase, CRICKET_PASSPHRASE also locks one that is still in the clear
+    let passphrase = match (imported, std::env::var("CRICKET_PASSPHRASE")) {
+        (Some(lock_with), _) => lock_with,
+        (None, Ok(passphrase)) => Some(passphrase),
+        (None, Err(_)) if config_loader.is_locked()? => Some(rpassword::prompt_password("Passphrase: ")?),
+        (None, Err(_)) => None
+    };
 
     // TODO rename Node to Listener?
-    let node = Node::new(config_loader).await;
+    let node = Node::new(config_loader, args.codec, passphrase.as_deref()).await?;
+    if passphrase.is_none() {
+        println!("Your identity key is stored unencrypted, use /passphrase to set one");
+    }
+    warn_rewrite(&node.db);
 
     /*
     if let Some(bootstraps ) = args.bootstrap_nodes {
@@ -60,22 +165,30 @@ async fn main() -> anyhow::Result<()> {
     });
 
     let node_clone = node.clone();
+    let feed_ranker = ranker.clone();
     let us_public_key_bytes = node.public_key.as_bytes().clone();
     tokio::spawn(async move {
-        let mut after:Option<PostId> = None;
+        // Only reads what arrived since the last poll, even if it was written long ago
+        let mut after:Option<SyncCursor> = None;
         loop {
-            let posts = node_clone.db.search_posts(&after, 10).unwrap();
-            for (post, score) in posts {
+            let posts = node_clone.db.posts_received(&after, 10).unwrap();
+            after = posts.last().map(SyncCursor::of).or(after);
+
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |now| now.as_secs());
+            for post in posts {
+                // Whatever the ranker leaves out of the feed
+                if let Ok(None) = feed_ranker.rank(&node_clone.db, &post, now) {
+                    continue;
+                }
                 let author = &hex::encode(&post.post.author.public_key)[..6];
                 if post.post.author.public_key == us_public_key_bytes {
                     continue;
                 }
 
-                let content = &post.post.content;
-                println!("{}: {}", author, content.strip_suffix("\n").unwrap());
+                // Private posts that were not meant for us
+                let Ok(Some(content)) = node_clone.db.read_post(&post.post) else { continue };
+                println!("[{}] {}: {}", post.post.get_id().short(), author, content.trim_end());
                 io::stdout().flush().unwrap();
-
-                after = Some(post.post.get_id())
             }
             
 
@@ -84,19 +197,221 @@ async fn main() -> anyhow::Result<()> {
     });
 
 
+    println!("Type to post, or use /feed, /promote <id>, /demote <id>, /search <words or \"a phrase\">, /private <message>, /dm <peer> <message>, /inbox [peer], /passphrase, /export <file>, /mnemonic, /rotate, exit");
+
     let mut input_string = String::new();
+    let mut feed_cursor:Option<RankCursor> = None;
 
     loop {
         input_string.clear();
         io::stdin().read_line(&mut input_string).unwrap();
-        
-        if input_string == "exit" {
-            return Ok(())
-        }
 
-        node.send_post(&input_string).await;
+        let mut command = input_string.trim().splitn(2, ' ');
+        match (command.next(), command.next()) {
+            (Some("exit"), None) => {
+                node.shutdown(SHUTDOWN_DEADLINE).await?;
+                return Ok(());
+            },
+            (Some(""), None) => {},
+            // The best posts according to the ranker, the next ones every time
+            (Some("/feed"), None) => {
+                match node.db.search_posts(ranker.as_ref(), &feed_cursor, 10) {
+                    Ok(page) if page.posts.is_empty() => {
+                        println!("That was everything, /feed starts over");
+                        feed_cursor = None;
+                    },
+                    Ok(page) => {
+                        for (post, _rank) in page.posts {
+                            let Ok(Some(content)) = node.db.read_post(&post.post) else { continue };
+                            let author = &hex::encode(post.post.author.public_key)[..6];
+                            println!("  [{}] {}: {}", post.post.get_id().short(), author, content.trim_end());
+                        }
+                        feed_cursor = page.cursor;
+                    },
+                    Err(e) => println!("Could not rank the feed: {}", e)
+                }
+            },
+            (Some("/promote"), Some(short)) => {
+                let promoted = match find_post(&node, short) {
+                    Ok(post) => node.promote(&post).await,
+                    Err(e) => Err(e)
+                };
+                match promoted {
+                    Ok(()) => println!("Promoted {}", short),
+                    Err(e) => println!("Could not promote {}: {}", short, e)
+                }
+            },
+            (Some("/search"), Some(query)) => {
+                match node.db.search_text(query, ranker.as_ref(), &None, 20) {
+                    Ok(page) => {
+                        for (post, _rank) in page.posts {
+                            let Ok(Some(content)) = node.db.read_post(&post.post) else { continue };
+                            let author = &hex::encode(post.post.author.public_key)[..6];
+                            println!("  [{}] {}: {}", post.post.get_id().short(), author, content.trim_end());
+                        }
+                    },
+                    Err(e) => println!("Could not search: {}", e)
+                }
+            },
+            (Some("/private"), Some(content)) => {
+                if let Err(e) = node.send_private_post(content).await {
+                    println!("Could not post: {}", e);
+                }
+            },
+            (Some("/dm"), Some(rest)) => {
+                let mut rest = rest.splitn(2, ' ');
+                let sent = match (rest.next(), rest.next()) {
+                    (Some(short), Some(content)) => match find_peer(&node, short) {
+                        Ok(peer) => node.send_message(&peer, content).await,
+                        Err(e) => Err(e)
+                    },
+                    _ => Err("usage: /dm <peer> <message>".into())
+                };
+                if let Err(e) = sent {
+                    println!("Could not send message: {}", e);
+                }
+            },
+            (Some("/inbox"), short) => {
+                let peer = match short.map(|short| find_peer(&node, short)).transpose() {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        println!("Could not find peer: {}", e);
+                        continue;
+                    }
+                };
+                match node.db.inbox(peer.as_ref(), 20) {
+                    Ok(messages) => {
+                        for message in messages.iter().rev() {
+                            let arrow = if message.outgoing { "->" } else { "<-" };
+                            let pending = if message.delivered { "" } else { " (not delivered yet)" };
+                            println!("  {} {}: {}{}", arrow, &hex::encode(message.peer.public_key)[..6], message.content.trim_end(), pending);
+                        }
+                    },
+                    Err(e) => println!("Could not read messages: {}", e)
+                }
+            },
+            (Some("/passphrase"), None) => {
+                let changed = match read_new_passphrase() {
+                    Ok(passphrase) => node.db.change_passphrase(passphrase.as_deref()).map_err(|e| e.into()),
+                    Err(e) => Err(e)
+                };
+                match changed {
+                    Ok(()) => {
+                        println!("Passphrase changed");
+                        warn_rewrite(&node.db);
+                    },
+                    Err(e) => println!("Could not change passphrase: {}", e)
+                }
+            },
+            (Some("/export"), Some(path)) => {
+                let exported = match read_new_passphrase() {
+                    Ok(Some(passphrase)) => node.db.export_identity(&passphrase)
+                        .map_err(|e| e.into())
+                        .and_then(|backup| std::fs::write(path.trim(), backup).map_err(|e| e.into())),
+                    Ok(None) => Err("the identity file needs a passphrase".into()),
+                    Err(e) => Err(e)
+                };
+                match exported {
+                    Ok(()) => println!("Exported identity to {}", path.trim()),
+                    Err(e) => println!("Could not export identity: {}", e)
+                }
+            },
+            (Some("/rotate"), None) => {
+                let rotated = match read_new_passphrase() {
+                    Ok(passphrase) => node.rotate_key(passphrase.as_deref()).await,
+                    Err(e) => Err(e)
+                };
+                match rotated {
+                    Ok(rotation) => {
+                        println!("Rotated to {}, restart to start using it", hex::encode(rotation.new.public_key));
+                        node.shutdown(SHUTDOWN_DEADLINE).await?;
+                        return Ok(());
+                    },
+                    Err(e) => println!("Could not rotate key: {}", e)
+                }
+            },
+            (Some("/mnemonic"), None) => {
+                match node.db.export_mnemonic() {
+                    Ok(words) => println!("Anyone with these words can be you, keep them safe:\n{}", words),
+                    Err(e) => println!("Could not export identity: {}", e)
+                }
+            },
+            (Some("/demote"), Some(short)) => {
+                let demoted = match find_post(&node, short) {
+                    Ok(post) => node.demote(&post).await,
+                    Err(e) => Err(e)
+                };
+                match demoted {
+                    Ok(()) => println!("Demoted {}", short),
+                    Err(e) => println!("Could not demote {}: {}", short, e)
+                }
+            },
+            // Never post a mistyped command for everyone to see
+            (Some(command), _) if command.starts_with('/') => match usage(command) {
+                Some(usage) => println!("usage: {}", usage),
+                None => println!("Unknown command {}", command)
+            },
+            _ => {
+                if let Err(e) = node.send_post(&input_string).await {
+                    println!("Could not post: {}", e);
+                }
+            }
+        }
 
     }
 
     
-}
\ No newline at end of file
+}
+
+// The key we just locked is still in sled's files until the database is rewritten, which happens on the next start
+fn warn_rewrite(db: &NodeDB) {
+    if db.needs_rewrite().unwrap_or(true) {
+        println!("WARNING: the unencrypted identity key is still on disk until cricket restarts, restart it as soon as you can");
+    }
+}
+
+// Asks twice, an empty passphrase stores the key unencrypted
+fn read_new_passphrase() -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let passphrase = rpassword::prompt_password("New passphrase (empty for none): ")?;
+    if rpassword::prompt_password("Again: ")? != passphrase {
+        return Err("passphrases do not match".into());
+    }
+    Ok(if passphrase.is_empty() { None } else { Some(passphrase) })
+}
+
+// Looks up a trusted peer from the start of their public key, like the feed shows it
+fn find_peer(node: &Node, short: &str) -> Result<Peer, Box<dyn std::error::Error>> {
+    let short = short.trim().to_lowercase();
+    let mut matches = node.db.get_trusted()?.into_iter()
+        .map(|(peer, _score)| peer)
+        .filter(|peer| hex::encode(peer.public_key).starts_with(&short));
+
+    match (matches.next(), matches.next()) {
+        (Some(peer), None) => Ok(peer),
+        (Some(_), Some(_)) => Err(format!("more than one peer starts with {}", short).into()),
+        _ => Err(format!("no trusted peer starts with {}", short).into())
+    }
+}
+
+// Looks up a post from the short id shown in the feed
+fn find_post(node: &Node, short: &str) -> Result<PostId, Box<dyn std::error::Error>> {
+    Ok(node.db.resolve_prefix(short.trim())?.post.get_id())
+}
+
+// For commands typed without the argument they need, or with one they don't take
+fn usage(command: &str) -> Option<&'static str> {
+    match command {
+        "/promote" => Some("/promote <id>"),
+        "/demote" => Some("/demote <id>"),
+        "/search" => Some("/search <words or \"a phrase\">"),
+        "/private" => Some("/private <message>"),
+        "/dm" => Some("/dm <peer> <message>"),
+        "/inbox" => Some("/inbox [peer]"),
+        "/export" => Some("/export <file>"),
+        "/passphrase" => Some("/passphrase"),
+        "/mnemonic" => Some("/mnemonic"),
+        "/rotate" => Some("/rotate"),
+        "/feed" => Some("/feed"),
+        _ => None
+    }
+}